@@ -0,0 +1,308 @@
+use crate::error::ErrorCategory;
+use failure::Fail;
+use serde_derive::{Serialize, Deserialize};
+use uuid::Uuid;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+pub type Result<T> = std::result::Result<T, BackendError>;
+
+/// A virtualization environment that labs can be deployed to.
+/// The deploy/drop logic is written only against this trait so that
+/// hypervisors other than Hyper-V can be plugged in later.
+/// It's shared between the threads that deploy VMs in parallel, hence `Sync`.
+pub trait Backend: Sync {
+    fn get_vms(&self) -> Result<Vec<Vm>>;
+
+    /// Imports the VM found in the folder at `path` in place, giving it a new ID.
+    /// Every network adapter of the VM is reported in the returned `ImportedVm`.
+    /// Those whose switches don't exist on the host are left disconnected and reported as missing.
+    fn import_vm_inplace_new_id(&self, path: &Path, rename_action: Option<RenameAction>) -> Result<ImportedVm>;
+
+    /// Returns false if the VM was not found
+    fn start_vm(&self, vm_id: &VmId) -> Result<bool>;
+
+    /// Returns false if the VM was not found
+    fn stop_vm(&self, vm_id: &VmId) -> Result<bool>;
+
+    /// Returns false if the VM was not found
+    fn delete_vm(&self, vm_id: &VmId) -> Result<bool>;
+
+    /// `notes` are kept with the switch and come back in `Switch::notes`
+    fn create_switch(&self, name: &str, switch_type: &SwitchType<&str>, notes: &str) -> Result<Uuid>;
+
+    /// Returns false if the switch was not found
+    fn delete_switch(&self, switch_id: &str) -> Result<bool>;
+
+    fn connect_adapter(&self, vm_id: &VmId, adapter_id: &str, switch_id: &str) -> Result<()>;
+
+    /// Exports the VM into a new folder named after it under `dest_path`
+    fn export_vm(&self, vm_id: &VmId, dest_path: &Path) -> Result<()>;
+
+    fn get_vm_adapters(&self, vm_id: &VmId) -> Result<Vec<Adapter>>;
+
+    /// The network adapters of the VM exported to the folder at `path`, as importing it would report them, without importing it
+    fn get_exported_vm_adapters(&self, path: &Path) -> Result<Vec<SwitchStatus>>;
+
+    fn get_switches(&self) -> Result<Vec<Switch>>;
+
+    /// The VMs with an adapter connected to the switch
+    fn get_switch_vm_ids(&self, switch_id: &str) -> Result<Vec<VmId>>;
+
+    /// Returns `None` if the VM hasn't reported one yet, e.g. because it's still booting
+    fn get_vm_link_local_address(&self, vm_id: &VmId) -> Result<Option<String>>;
+
+    /// Adds the hosts to the list of hosts the provisioner is allowed to talk to.
+    /// Fails if the remoting service needed to talk to them isn't running.
+    fn trust_hosts(&self, hosts: &[String]) -> Result<()>;
+
+    /// Runs the provisioning script against the targets and returns its output
+    fn run_provisioner(&self, script_path: &Path, targets: &[ProvisionTarget]) -> Result<String>;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportedVm {
+    #[serde(rename = "VmId")]
+    pub id: VmId,
+    #[serde(rename = "VmName")]
+    pub name: String,
+    #[serde(rename = "AdapterStatus")]
+    pub adapter_status: HashMap<String, SwitchStatus>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchStatus {
+    /// Name of the switch the adapter was connected to. Empty if it wasn't connected.
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "IsMissing")]
+    pub is_missing: bool,
+    #[serde(rename = "AdapterName")]
+    pub adapter_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Vm {
+    #[serde(rename = "Id")]
+    pub id: VmId,
+    #[serde(rename = "Name")]
+    pub name: String,
+    /// Power state, e.g. "Running" or "Off"
+    #[serde(rename = "State")]
+    pub state: String,
+    #[serde(rename = "UptimeSeconds")]
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Adapter {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "SwitchName")]
+    pub switch_name: Option<String>,
+    #[serde(rename = "SwitchId")]
+    pub switch_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Switch {
+    #[serde(rename = "Id")]
+    pub id: Uuid,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "SwitchType")]
+    pub switch_type: SwitchKind,
+    /// Name of the host network adapter an external switch is bound to
+    #[serde(rename = "NetAdapterName")]
+    pub net_adapter_name: Option<String>,
+    #[serde(rename = "Notes", default)]
+    pub notes: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwitchKind {
+    #[default]
+    Private,
+    Internal,
+    External,
+}
+
+/// A VM as handed to a provisioning script
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisionTarget {
+    #[serde(rename = "Id")]
+    pub id: VmId,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Address")]
+    pub address: String,
+}
+
+// TODO: should this be a newtype?
+pub type VmId = Uuid;
+
+pub enum SwitchType<S: AsRef<str>> {
+    Private,
+    /// With the address to give the host's adapter on the switch, in CIDR notation (e.g. "192.168.50.1/24")
+    Internal(Option<S>),
+    /// Bound to the named host network adapter, or to the one the host's default route goes through if `None`
+    External(Option<S>),
+}
+
+pub enum RenameAction {
+    NewName(String), // TODO; can we find a way to use &str here instead of String
+    AddPrefix(String),
+}
+
+/// The `Backend` method that failed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    GetVms,
+    ImportVm,
+    StartVm,
+    StopVm,
+    DeleteVm,
+    CreateSwitch,
+    DeleteSwitch,
+    ConnectAdapter,
+    ExportVm,
+    GetVmAdapters,
+    GetExportedVmAdapters,
+    GetSwitches,
+    GetSwitchVms,
+    GetVmLinkLocalAddress,
+    TrustHosts,
+    RunProvisioner,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Operation::GetVms => "get VMs",
+            Operation::ImportVm => "import VM",
+            Operation::StartVm => "start VM",
+            Operation::StopVm => "stop VM",
+            Operation::DeleteVm => "delete VM",
+            Operation::CreateSwitch => "create switch",
+            Operation::DeleteSwitch => "delete switch",
+            Operation::ConnectAdapter => "connect adapter",
+            Operation::ExportVm => "export VM",
+            Operation::GetVmAdapters => "get VM adapters",
+            Operation::GetExportedVmAdapters => "get adapters of exported VM",
+            Operation::GetSwitches => "get switches",
+            Operation::GetSwitchVms => "get VMs connected to switch",
+            Operation::GetVmLinkLocalAddress => "get VM link-local address",
+            Operation::TrustHosts => "trust hosts",
+            Operation::RunProvisioner => "run provisioner",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// What a failed PowerShell command returned
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandOutput {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn handle_blank(s: String) -> String { if !s.is_empty() { s } else { "<empty>".to_owned() } }
+        write!(f, "Exit code: {}.\nStdout: {} \nStderr: {}",
+            self.exit_code,
+            handle_blank(to_string_truncated(&self.stdout, 1000)),
+            handle_blank(to_string_truncated(&self.stderr, 1000)))
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    VmNotFound { operation: Operation, vm_id: VmId },
+    SwitchNotFound { operation: Operation, switch_id: String },
+    AdapterNotFound { operation: Operation, vm_id: VmId, adapter_id: String },
+    InvalidArgument { operation: Operation, msg: String },
+    /// Import could not even work out whether the VM is compatible with the host
+    NoCompatibilityReport { path: PathBuf, output: CommandOutput },
+    /// Import found problems with the VM other than missing switches, which are the only ones it can fix
+    UnresolvedIncompatibilities { path: PathBuf, output: CommandOutput },
+    ImportFailed { path: PathBuf, output: CommandOutput },
+    /// The environment the commands run in failed, e.g. PowerShell couldn't be started or died
+    HostFailed { operation: Operation, msg: String },
+    /// `target` is the ID of the VM or switch the command was run against, if any
+    CommandFailed { operation: Operation, target: Option<String>, output: CommandOutput },
+    BadOutput { operation: Operation, msg: String },
+}
+
+impl Fail for BackendError {}
+
+impl BackendError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            BackendError::VmNotFound { .. }
+            | BackendError::SwitchNotFound { .. }
+            | BackendError::AdapterNotFound { .. } => ErrorCategory::NotFound,
+            BackendError::InvalidArgument { .. } => ErrorCategory::InvalidInput,
+            BackendError::NoCompatibilityReport { .. }
+            | BackendError::UnresolvedIncompatibilities { .. } => ErrorCategory::Incompatible,
+            BackendError::ImportFailed { .. }
+            | BackendError::HostFailed { .. }
+            | BackendError::CommandFailed { .. }
+            | BackendError::BadOutput { .. } => ErrorCategory::Backend,
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            BackendError::NoCompatibilityReport { .. }
+            | BackendError::UnresolvedIncompatibilities { .. }
+            | BackendError::ImportFailed { .. } => Operation::ImportVm,
+            BackendError::VmNotFound { operation, .. }
+            | BackendError::SwitchNotFound { operation, .. }
+            | BackendError::AdapterNotFound { operation, .. }
+            | BackendError::InvalidArgument { operation, .. }
+            | BackendError::HostFailed { operation, .. }
+            | BackendError::CommandFailed { operation, .. }
+            | BackendError::BadOutput { operation, .. } => *operation,
+        }
+    }
+
+    /// What the PowerShell command returned, if the error came from one
+    pub fn output(&self) -> Option<&CommandOutput> {
+        match self {
+            BackendError::NoCompatibilityReport { output, .. }
+            | BackendError::UnresolvedIncompatibilities { output, .. }
+            | BackendError::ImportFailed { output, .. }
+            | BackendError::CommandFailed { output, .. } => Some(output),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::VmNotFound { operation, vm_id } => write!(f, "Failed to {}: VM {} not found", operation, vm_id),
+            BackendError::SwitchNotFound { operation, switch_id } => write!(f, "Failed to {}: switch {} not found", operation, switch_id),
+            BackendError::AdapterNotFound { operation, vm_id, adapter_id } => write!(f, "Failed to {}: VM {} has no adapter with Id {}", operation, vm_id, adapter_id),
+            BackendError::InvalidArgument { operation, msg } => write!(f, "Failed to {}: {}", operation, msg),
+            BackendError::NoCompatibilityReport { path, output } => write!(f, "Failed to generate compatibility report for VM in '{}'.\n{}", path.display(), output),
+            BackendError::UnresolvedIncompatibilities { path, output } => write!(f, "Failed to resolve all incompatibilities of VM in '{}'.\n{}", path.display(), output),
+            BackendError::ImportFailed { path, output } => write!(f, "Failed to import VM in '{}'.\n{}", path.display(), output),
+            BackendError::HostFailed { operation, msg } => write!(f, "Failed to {}: {}", operation, msg),
+            BackendError::CommandFailed { operation, target: Some(target), output } => write!(f, "Failed to {} {}. Powershell returned failure.\n{}", operation, target, output),
+            BackendError::CommandFailed { operation, target: None, output } => write!(f, "Failed to {}. Powershell returned failure.\n{}", operation, output),
+            BackendError::BadOutput { operation, msg } => write!(f, "Failed to {}: failed to parse powershell output: {}", operation, msg),
+        }
+    }
+}
+
+fn to_string_truncated(s: &str, take: usize) -> String {
+    s.chars().take(take).collect()
+}
@@ -0,0 +1,760 @@
+use crate::checksums::{self, Checksums, LabIndex, Mismatch, INDEX_FILE_NAME};
+use crate::copy::{self, CopyProgress, STAGING_SUFFIX};
+use crate::error::LamaError;
+use crate::journal::Journal;
+use crate::sha256::Sha256;
+use crate::state::{LabState, LAMA_DIR_NAME};
+use failure::Error;
+use log::{info, warn};
+use serde_derive::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use tar::{Builder, EntryType, GnuExtSparseHeader, Header};
+
+pub const BUNDLE_EXTENSION: &str = "lama";
+/// The first entry of a bundle
+const MANIFEST_ENTRY_NAME: &str = "lama-bundle.json";
+/// Bump this whenever the format changes in a way older versions of lama can't read
+const BUNDLE_VERSION: u32 = 1;
+/// Files are scanned for runs of zeros in chunks of this size, which a tar archive needs to be a multiple of 512
+const CHUNK_SIZE: usize = 64 * 1024;
+const BUFFER_SIZE: usize = 16 * CHUNK_SIZE;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_LEVEL: i32 = 3;
+/// Extensions of virtual hard disks, which are packed after the rest of the lab
+const DISK_EXTENSIONS: [&str; 4] = ["vhd", "vhdx", "avhd", "avhdx"];
+/// In the `.lama` folder of a lab for as long as it's being extracted from a bundle
+const PARTIAL_FILE_NAME: &str = "extracting";
+
+/// What a bundle holds. It's the first entry of the bundle, so that it's known before any of the lab's files is read.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub version: u32,
+    pub lama_version: String,
+    /// Name of the lab's folder, which the lab is extracted into
+    pub name: String,
+    pub index: LabIndex,
+}
+
+/// The parts of a file that hold anything but zeros, as offset and length. The rest is stored as holes.
+type DataRegions = Vec<(u64, u64)>;
+
+/// Whether `path` is a bundle written by `pack`
+pub fn is_bundle(path: &Path) -> bool {
+    path.is_file() && has_bundle_extension(path)
+}
+
+pub fn has_bundle_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case(BUNDLE_EXTENSION))
+}
+
+/// Packs the exported lab at `lab_path` into a bundle at `bundle_path`: a tar archive that starts with a manifest
+/// listing every file of the lab with its size and hash, optionally compressed with zstd. The runs of zeros disk
+/// images are mostly made of are stored as holes in GNU sparse entries, so they take no room even uncompressed.
+/// The disk images come last, one VM folder after the other, so a deploy can get going before they're extracted.
+pub fn pack_lab(lab_path: &Path, bundle_path: &Path, compress: bool) -> Result<(), Error> {
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+
+    // A deployed lab's VMs run from its files, which no longer match what was exported
+    let deployed = LabState::load(lab_path)?.is_some_and(|s| !s.vms.is_empty());
+    if deployed || Checksums::set_aside_exists(lab_path) {
+        return Err(LamaError::InvalidInput(format!("Lab '{}' was deployed in place, so its VMs' files have changed since it was exported. Pack an export of it instead", lab_path.display())).into());
+    }
+
+    if bundle_path.exists() {
+        return Err(LamaError::InvalidInput(format!("'{}' already exists", bundle_path.display())).into());
+    }
+
+    let name = fs::canonicalize(lab_path)?.file_name().map_or_else(|| "lab".to_owned(), |n| n.to_string_lossy().into_owned());
+    let paths: Vec<String> = checksums::list_files(lab_path, "")?.into_iter().filter(|p| p != INDEX_FILE_NAME).collect();
+    let sizes = paths.iter().map(|p| Ok(fs::metadata(checksums::from_key(lab_path, p))?.len())).collect::<Result<Vec<u64>, Error>>()?;
+    let total: u64 = sizes.iter().sum();
+
+    // The manifest has to come first, so every file is hashed before any of it is written
+    info!("Scanning {} files ({})", paths.len(), copy::format_bytes(total));
+    let mut index = LabIndex::new();
+    let mut regions = BTreeMap::new();
+    let mut progress = CopyProgress::new(total);
+    for (path, size) in paths.iter().zip(&sizes) {
+        progress.start_file(path, *size);
+        let (sha256, file_regions) = scan_file(&checksums::from_key(lab_path, path), &mut progress)?;
+        index.insert(path, *size, sha256);
+        regions.insert(path.clone(), file_regions);
+    }
+    progress.finish();
+
+    let stored: u64 = regions.values().flat_map(|r| r.iter().map(|(_, length)| length)).sum();
+    info!("Writing {} ({} of data, {} of it zeros left out)", bundle_path.display(), copy::format_bytes(stored), copy::format_bytes(total - stored));
+    let manifest = BundleManifest { version: BUNDLE_VERSION, lama_version: env!("CARGO_PKG_VERSION").to_owned(), name, index };
+    let mut part_name = bundle_path.file_name().unwrap_or_default().to_owned();
+    part_name.push(STAGING_SUFFIX);
+    let part_path = bundle_path.with_file_name(part_name);
+    let writer = BufWriter::new(File::create(&part_path)?);
+    let mut progress = CopyProgress::new(stored);
+    let result = if compress {
+        zstd::Encoder::new(writer, ZSTD_LEVEL).map_err(Error::from)
+            .and_then(|encoder| write_bundle(encoder, lab_path, &manifest, &regions, &mut progress))
+            .and_then(|encoder| Ok(encoder.finish()?))
+            .and_then(|mut writer| Ok(writer.flush()?))
+    } else {
+        write_bundle(writer, lab_path, &manifest, &regions, &mut progress).and_then(|mut writer| Ok(writer.flush()?))
+    };
+    progress.finish();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+
+    fs::rename(&part_path, bundle_path)?;
+    info!("Lab packed successfully to {} ({})", bundle_path.display(), copy::format_bytes(fs::metadata(bundle_path)?.len()));
+    Ok(())
+}
+
+/// Hashes the file at `path` and finds the chunks of it that aren't all zeros
+fn scan_file(path: &Path, progress: &mut CopyProgress) -> Result<(String, DataRegions), Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut regions: DataRegions = Vec::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = read_full(&mut file, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        for chunk in buffer[..read].chunks(CHUNK_SIZE) {
+            if chunk.iter().any(|b| *b != 0) {
+                match regions.last_mut() {
+                    Some((start, length)) if *start + *length == offset => *length += chunk.len() as u64,
+                    _ => regions.push((offset, chunk.len() as u64)),
+                }
+            }
+            offset += chunk.len() as u64;
+        }
+        progress.add(read as u64);
+    }
+    Ok((hasher.finish(), regions))
+}
+
+fn write_bundle<W: Write>(writer: W, lab_path: &Path, manifest: &BundleManifest, regions: &BTreeMap<String, DataRegions>, progress: &mut CopyProgress) -> Result<W, Error> {
+    let mut builder = Builder::new(writer);
+    let manifest_bytes = serde_json::to_vec_pretty(manifest)?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest_bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    builder.append_data(&mut header, MANIFEST_ENTRY_NAME, manifest_bytes.as_slice())?;
+
+    // Sorting is stable, so the disk images are still in the order of their folders
+    let mut files: Vec<_> = manifest.index.files().collect();
+    files.sort_by_key(|(path, _, _)| is_disk_image(path));
+    for (path, size, _) in files {
+        let file_regions = &regions[path];
+        let file = File::open(checksums::from_key(lab_path, path))?;
+        let mut header = Header::new_gnu();
+        header.set_metadata(&file.metadata()?);
+        progress.start_file(path, size);
+        let stored: u64 = file_regions.iter().map(|(_, length)| length).sum();
+        if stored == size {
+            header.set_size(size);
+            builder.append_data(&mut header, path, ProgressReader { inner: file.take(size), progress: &mut *progress })?;
+            continue;
+        }
+
+        // The first few regions go in the header itself, the rest in extension headers that come before the data
+        let mut sparse = file_regions.clone();
+        let ends_with_data = sparse.last().is_some_and(|(start, length)| start + length == size);
+        if !ends_with_data {
+            sparse.push((size, 0)); // Ends the file with a hole
+        }
+        header.set_entry_type(EntryType::GNUSparse);
+        header.set_size(stored);
+        let gnu_header = header.as_gnu_mut().ok_or_else(|| LamaError::Internal("Not a GNU tar header".to_owned()))?;
+        gnu_header.set_real_size(size);
+        let in_header = gnu_header.sparse.len();
+        for (header_entry, (start, length)) in gnu_header.sparse.iter_mut().zip(&sparse) {
+            header_entry.set_offset(*start);
+            header_entry.set_length(*length);
+        }
+        gnu_header.set_is_extended(sparse.len() > in_header);
+
+        let mut extensions = Vec::new();
+        let mut rest = sparse[in_header.min(sparse.len())..].chunks(GnuExtSparseHeader::new().sparse.len()).peekable();
+        while let Some(entries) = rest.next() {
+            let mut extension = GnuExtSparseHeader::new();
+            for (header_entry, (start, length)) in extension.sparse.iter_mut().zip(entries) {
+                header_entry.set_offset(*start);
+                header_entry.set_length(*length);
+            }
+            extension.set_is_extended(rest.peek().is_some());
+            extensions.extend_from_slice(extension.as_bytes());
+        }
+
+        let data = RegionReader { file, regions: file_regions.clone(), current: 0, left_in_region: None };
+        builder.append_data(&mut header, path, Cursor::new(extensions).chain(ProgressReader { inner: data, progress: &mut *progress }))?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+/// Reads the first entry of the bundle, without reading any further
+pub fn read_manifest(bundle_path: &Path) -> Result<BundleManifest, Error> {
+    let mut archive = tar::Archive::new(open_bundle(bundle_path)?);
+    let mut entries = archive.entries()?;
+    next_manifest(bundle_path, entries.next())
+}
+
+/// Whether the lab at `lab_path` is still being extracted from a bundle, or was until that failed or was interrupted
+pub fn is_partly_extracted(lab_path: &Path) -> bool {
+    lab_path.join(LAMA_DIR_NAME).join(PARTIAL_FILE_NAME).is_file()
+}
+
+fn is_disk_image(path: &str) -> bool {
+    Path::new(path).extension().and_then(|e| e.to_str()).is_some_and(|e| DISK_EXTENSIONS.iter().any(|d| e.eq_ignore_ascii_case(d)))
+}
+
+/// A bundle being extracted by `run` on one thread, while the others wait for the files they need to be there
+pub struct Extraction {
+    bundle_path: PathBuf,
+    lab_path: PathBuf,
+    index: LabIndex,
+    status: Mutex<ExtractionStatus>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct ExtractionStatus {
+    /// The files extracted so far, as keys of the index
+    extracted: HashSet<String>,
+    finished: bool,
+    failed: bool,
+    /// What made the extraction fail, until it's taken
+    error: Option<Error>,
+    stopped: bool,
+}
+
+impl Extraction {
+    /// Reads the manifest of the bundle at `bundle_path` and makes the folder of `dest_dir` named after the lab to
+    /// extract it into. The lab is extracted in place, so that VMs can be deployed from it before it's all there.
+    pub fn start(bundle_path: &Path, dest_dir: &Path) -> Result<Self, Error> {
+        let manifest = read_manifest(bundle_path)?;
+        if !checksums::is_safe_key(&manifest.name) || manifest.name.contains('/') {
+            return Err(LamaError::InvalidLab(format!("Bad lab name '{}' in bundle '{}'", manifest.name, bundle_path.display())).into());
+        }
+
+        let bad_paths: Vec<&str> = manifest.index.files().map(|(path, _, _)| path).filter(|p| !checksums::is_safe_key(p)).collect();
+        if !bad_paths.is_empty() {
+            return Err(LamaError::InvalidLab(format!("The manifest of bundle '{}' has paths outside the lab: {}", bundle_path.display(), bad_paths.join(", "))).into());
+        }
+
+        // A bundle can only be read from the start, so an extraction that was interrupted starts over,
+        // unless VMs were deployed from what it left
+        let lab_path = dest_dir.join(&manifest.name);
+        if lab_path.exists() {
+            if !is_partly_extracted(&lab_path) || Journal::exists(&lab_path) || LabState::load(&lab_path)?.is_some() {
+                return Err(LamaError::CopyExists(lab_path).into());
+            }
+            fs::remove_dir_all(&lab_path)?;
+        }
+        fs::create_dir_all(lab_path.join(LAMA_DIR_NAME))?;
+        File::create(lab_path.join(LAMA_DIR_NAME).join(PARTIAL_FILE_NAME))?;
+
+        Ok(Self {
+            bundle_path: bundle_path.to_owned(),
+            lab_path,
+            index: manifest.index,
+            status: Mutex::new(ExtractionStatus::default()),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn lab_path(&self) -> &Path {
+        &self.lab_path
+    }
+
+    /// Extracts the files that follow the manifest, leaving runs of zeros as holes, which makes room for them on
+    /// file systems that do sparse files. Files are checked against their hashes in the manifest as they're
+    /// extracted, and with `verify` the first one that doesn't match fails the extraction. Whatever it fails with
+    /// is kept for `take_error`.
+    pub fn run(&self, verify: bool) {
+        let total: u64 = self.index.files().map(|(_, size, _)| size).sum();
+        info!("Extracting {} files ({}) to {}", self.index.files().count(), copy::format_bytes(total), self.lab_path.display());
+        let result = self.extract_files(verify);
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => status.finished = true,
+            Err(e) => {
+                status.failed = true;
+                status.error = Some(e);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    fn extract_files(&self, verify: bool) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(open_bundle(&self.bundle_path)?);
+        let mut entries = archive.entries()?;
+        next_manifest(&self.bundle_path, entries.next())?;
+
+        let expected: HashSet<&str> = self.index.files().map(|(path, _, _)| path).collect();
+        let mut extracted = HashSet::new();
+        let mut mismatches = Vec::new();
+        for entry in entries {
+            let mut entry = entry?;
+            let path = checksums::to_key(&entry.path()?);
+            let is_file = match entry.header().entry_type() {
+                EntryType::Regular | EntryType::GNUSparse => true,
+                EntryType::Directory => false,
+                _ => return Err(LamaError::InvalidLab(format!("'{}' in bundle '{}' is not a file", path, self.bundle_path.display())))?,
+            };
+            if !is_file {
+                continue;
+            }
+            if !expected.contains(path.as_str()) || !extracted.insert(path.clone()) {
+                return Err(LamaError::InvalidLab(format!("'{}' in bundle '{}' is not in its manifest, or is in the bundle twice", path, self.bundle_path.display())).into());
+            }
+
+            let dest_path = checksums::from_key(&self.lab_path, &path);
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let (size, sha256) = write_sparse(&mut entry, &dest_path, &|| self.status.lock().unwrap().stopped)?;
+            if let Some(mismatch) = self.index.check_file(&path, size, &sha256) {
+                if verify {
+                    return Err(LamaError::ChecksumMismatch { lab_path: self.bundle_path.clone(), mismatches: vec![mismatch.to_string()] }.into());
+                }
+                mismatches.push(mismatch.to_string());
+            }
+            self.add_extracted(path);
+        }
+
+        let missing: Vec<String> = expected.iter().filter(|p| !extracted.contains(**p)).map(|p| Mismatch::Missing((*p).to_owned()).to_string()).collect();
+        if verify && !missing.is_empty() {
+            return Err(LamaError::ChecksumMismatch { lab_path: self.bundle_path.clone(), mismatches: missing }.into());
+        }
+        mismatches.extend(missing);
+        if !mismatches.is_empty() {
+            warn!("The files of bundle '{}' don't match its manifest, extracted anyway as asked:\n  {}", self.bundle_path.display(), mismatches.join("\n  "));
+        }
+
+        // The index isn't packed since the manifest holds it, but it's written back so the lab is the export it was packed from
+        self.index.save(&self.lab_path)?;
+        fs::remove_file(self.lab_path.join(LAMA_DIR_NAME).join(PARTIAL_FILE_NAME))?;
+        info!("Extracted the lab to {}", self.lab_path.display());
+        Ok(())
+    }
+
+    fn add_extracted(&self, path: String) {
+        let mut status = self.status.lock().unwrap();
+        let folder = path.split_once('/').map(|(folder, _)| format!("{}/", folder));
+        status.extracted.insert(path);
+        if let Some(folder) = folder {
+            if self.has_extracted(&status, |p| p.starts_with(&folder)) {
+                info!("==> Extracted {}", folder.trim_end_matches('/'));
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Waits for everything but the disk images, which is all it takes to look into the lab
+    pub fn wait_for_layout(&self) -> Result<(), Error> {
+        self.wait_until(|status| self.has_extracted(status, |p| !is_disk_image(p)))
+    }
+
+    /// Waits for the files in `folder`, which is relative to the lab root
+    pub fn wait_for_folder(&self, folder: &Path) -> Result<(), Error> {
+        let prefix = format!("{}/", checksums::to_key(folder));
+        self.wait_until(|status| self.has_extracted(status, |p| p.starts_with(&prefix)))
+    }
+
+    pub fn wait_for_all(&self) -> Result<(), Error> {
+        self.wait_until(|_| false)
+    }
+
+    /// Waits until `done` or the extraction is over. Fails if the extraction did, with an error that only says so.
+    fn wait_until<F: Fn(&ExtractionStatus) -> bool>(&self, done: F) -> Result<(), Error> {
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if status.finished || done(&status) {
+                return Ok(());
+            }
+            if status.failed {
+                return Err(LamaError::Internal(format!("Extracting bundle '{}' failed", self.bundle_path.display())).into());
+            }
+            status = self.changed.wait(status).unwrap();
+        }
+    }
+
+    /// Whether every file of the index that `wanted` picks has been extracted
+    fn has_extracted<F: Fn(&str) -> bool>(&self, status: &ExtractionStatus, wanted: F) -> bool {
+        self.index.files().all(|(path, _, _)| !wanted(path) || status.extracted.contains(path))
+    }
+
+    /// What made the extraction fail, if it did. Waiting for files only says that it failed.
+    pub fn take_error(&self) -> Option<Error> {
+        self.status.lock().unwrap().error.take()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.status.lock().unwrap().finished
+    }
+
+    /// Makes `run` give up, for when what was extracted is no longer needed
+    pub fn stop(&self) {
+        self.status.lock().unwrap().stopped = true;
+    }
+}
+
+/// Writes what `source` reads to a new file at `dest`, seeking over runs of zeros instead of writing them, until
+/// `stopped` says otherwise. Returns the size and hash of the file.
+fn write_sparse<R: Read>(source: &mut R, dest: &Path, stopped: &dyn Fn() -> bool) -> Result<(u64, String), Error> {
+    let mut file = File::create(dest)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut size = 0;
+    let mut written_to = 0;
+    loop {
+        if stopped() {
+            return Err(LamaError::Aborted.into());
+        }
+        let read = read_full(source, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        for chunk in buffer[..read].chunks(CHUNK_SIZE) {
+            if chunk.iter().any(|b| *b != 0) {
+                if written_to < size {
+                    file.seek(SeekFrom::Start(size))?;
+                }
+                file.write_all(chunk)?;
+                written_to = size + chunk.len() as u64;
+            }
+            size += chunk.len() as u64;
+        }
+    }
+    file.set_len(size)?;
+    file.sync_all()?;
+    Ok((size, hasher.finish()))
+}
+
+fn next_manifest<R: Read>(bundle_path: &Path, entry: Option<io::Result<tar::Entry<R>>>) -> Result<BundleManifest, Error> {
+    let not_a_bundle = || LamaError::InvalidLab(format!("'{}' is not a lab bundle", bundle_path.display()));
+    let mut entry = entry.ok_or_else(not_a_bundle)?.map_err(|_| not_a_bundle())?;
+    if entry.path()?.as_ref() != Path::new(MANIFEST_ENTRY_NAME) {
+        return Err(not_a_bundle().into());
+    }
+
+    let mut bytes = Vec::new();
+    entry.read_to_end(&mut bytes)?;
+    let manifest: BundleManifest = serde_json::from_slice(&bytes)
+        .map_err(|e| LamaError::BadFile { path: bundle_path.to_owned(), msg: e.to_string() })?;
+    if manifest.version > BUNDLE_VERSION {
+        return Err(LamaError::NewerVersion { path: bundle_path.to_owned(), lama_version: manifest.lama_version }.into());
+    }
+    Ok(manifest)
+}
+
+/// Opens the bundle for reading, decompressing it if it was compressed
+fn open_bundle(bundle_path: &Path) -> Result<Box<dyn Read>, Error> {
+    let mut reader = BufReader::with_capacity(BUFFER_SIZE, File::open(bundle_path)?);
+    if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Reads until `buffer` is full or there's nothing left, so that chunks line up with the offsets they're at
+fn read_full<R: Read>(source: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads the data regions of a file one after the other
+struct RegionReader {
+    file: File,
+    regions: DataRegions,
+    current: usize,
+    left_in_region: Option<u64>,
+}
+
+impl Read for RegionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current < self.regions.len() {
+            let (start, length) = self.regions[self.current];
+            let left = match self.left_in_region {
+                Some(left) => left,
+                None => {
+                    self.file.seek(SeekFrom::Start(start))?;
+                    length
+                }
+            };
+            if left == 0 {
+                self.current += 1;
+                self.left_in_region = None;
+                continue;
+            }
+
+            let max = (buf.len() as u64).min(left) as usize;
+            let read = self.file.read(&mut buf[..max])?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The file got shorter while it was packed"));
+            }
+            self.left_in_region = Some(left - read as u64);
+            return Ok(read);
+        }
+        Ok(0)
+    }
+}
+
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a mut CopyProgress,
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.add(read as u64);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{contents, write_files};
+
+    fn zeros(size: usize) -> Vec<u8> {
+        vec![0; size]
+    }
+
+    /// A disk image that starts, goes on and ends with runs of zeros, none of them lined up with the chunks
+    fn disk_with_holes() -> Vec<u8> {
+        [zeros(3 * CHUNK_SIZE + 100), contents(CHUNK_SIZE, 1), zeros(5 * CHUNK_SIZE), contents(10, 2), zeros(2 * CHUNK_SIZE + 7)].concat()
+    }
+
+    /// A disk image with more runs of zeros than fit in the header of its entry, so that it needs extension headers
+    fn disk_with_many_holes() -> Vec<u8> {
+        (0..60).flat_map(|i| if i % 2 == 0 { contents(CHUNK_SIZE, i) } else { zeros(CHUNK_SIZE) }).collect()
+    }
+
+    /// Stored as it is, and unlike anything else in the lab
+    fn dense_disk() -> Vec<u8> {
+        (0..3 * CHUNK_SIZE + 1).map(|i| (i % 7) as u8 + 1).collect()
+    }
+
+    /// The files of a lab, keyed by their path in it
+    fn lab_files() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("lab.toml", b"[[switches]]\nname = \"corp\"\n".to_vec()),
+            ("empty.txt", Vec::new()),
+            ("vm01/Virtual Machines/vm.vmcx", contents(1000, 3)),
+            ("vm01/disk.vhdx", disk_with_holes()),
+            ("vm01/zeros.vhdx", zeros(4 * CHUNK_SIZE + 1)),
+            ("vm02/disk.vhdx", disk_with_many_holes()),
+            ("vm02/dense.vhdx", dense_disk()),
+        ]
+    }
+
+    fn make_lab(dir: &Path) -> PathBuf {
+        let lab_path = dir.join("mylab");
+        write_files(&lab_path, &lab_files());
+        lab_path
+    }
+
+    /// Extracts the bundle the way a deploy does, but all in one go, and removes what it extracted if that fails
+    fn extract(bundle_path: &Path, dest_dir: &Path, verify: bool) -> Result<PathBuf, Error> {
+        let extraction = Extraction::start(bundle_path, dest_dir)?;
+        extraction.run(verify);
+        match extraction.take_error() {
+            Some(e) => {
+                fs::remove_dir_all(extraction.lab_path()).unwrap();
+                Err(e)
+            }
+            None => Ok(extraction.lab_path().to_owned()),
+        }
+    }
+
+    /// The paths of the files in the bundle, in the order they're in
+    fn entry_paths(bundle_path: &Path) -> Vec<String> {
+        let mut archive = tar::Archive::new(open_bundle(bundle_path).unwrap());
+        archive.entries().unwrap().map(|e| checksums::to_key(&e.unwrap().path().unwrap())).collect()
+    }
+
+    /// Packs a lab, extracts it again and checks it came out the same. Returns the bundle and the folder it's in.
+    fn round_trip(compress: bool) -> (PathBuf, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, compress).unwrap();
+        assert!(is_bundle(&bundle_path));
+        assert_eq!(read_manifest(&bundle_path).unwrap().name, "mylab");
+
+        let dest_dir = dir.path().join("dest");
+        fs::create_dir_all(&dest_dir).unwrap();
+        let extracted_path = extract(&bundle_path, &dest_dir, true).unwrap();
+        assert_eq!(extracted_path, dest_dir.join("mylab"));
+        for (path, contents) in lab_files() {
+            assert_eq!(fs::read(checksums::from_key(&extracted_path, path)).unwrap(), contents, "{}", path);
+        }
+        assert!(!is_partly_extracted(&extracted_path));
+
+        // The index is written back, so the extracted lab is the export it was packed from
+        let index = LabIndex::load(&extracted_path).unwrap().unwrap();
+        assert_eq!(index.files().count(), lab_files().len());
+        assert_eq!(checksums::list_files(&extracted_path, "").unwrap().len(), lab_files().len() + 1);
+        (bundle_path, dir)
+    }
+
+    #[test]
+    fn extract_gives_back_what_was_packed() {
+        let (bundle_path, _dir) = round_trip(false);
+        // The runs of zeros take no room in the bundle
+        let total: usize = lab_files().iter().map(|(_, contents)| contents.len()).sum();
+        let zero_chunks: usize = lab_files().iter()
+            .map(|(_, contents)| contents.chunks(CHUNK_SIZE).filter(|c| c.iter().all(|b| *b == 0)).map(|c| c.len()).sum::<usize>())
+            .sum();
+        let bundle_size = fs::metadata(&bundle_path).unwrap().len() as usize;
+        assert!(bundle_size < total - zero_chunks + 64 * 1024, "{} of {} bytes", bundle_size, total);
+        assert!(!fs::read(&bundle_path).unwrap().starts_with(&ZSTD_MAGIC));
+    }
+
+    #[test]
+    fn extract_gives_back_what_was_packed_with_zstd() {
+        let (bundle_path, _dir) = round_trip(true);
+        assert!(fs::read(&bundle_path).unwrap().starts_with(&ZSTD_MAGIC));
+    }
+
+    #[test]
+    fn extract_checks_the_files_against_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, false).unwrap();
+
+        // Flip a byte of the dense disk, which is stored as it is
+        let mut bundle = fs::read(&bundle_path).unwrap();
+        let dense = dense_disk();
+        let at = bundle.windows(64).position(|w| w == &dense[..64]).unwrap();
+        bundle[at] ^= 0xff;
+        fs::write(&bundle_path, bundle).unwrap();
+
+        let dest_dir = dir.path().join("dest");
+        let e = extract(&bundle_path, &dest_dir, true).unwrap_err();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::ChecksumMismatch { mismatches, .. }) if *mismatches == ["'vm02/dense.vhdx' is corrupted"]));
+        assert!(fs::read_dir(&dest_dir).unwrap().next().is_none());
+
+        // Unless it's told not to
+        let extracted_path = extract(&bundle_path, &dest_dir, false).unwrap();
+        assert_eq!(fs::read(extracted_path.join("vm02").join("dense.vhdx")).unwrap()[0], dense[0] ^ 0xff);
+    }
+
+    #[test]
+    fn disk_images_are_packed_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, true).unwrap();
+        assert_eq!(entry_paths(&bundle_path), vec![
+            MANIFEST_ENTRY_NAME, "empty.txt", "lab.toml", "vm01/Virtual Machines/vm.vmcx",
+            "vm01/disk.vhdx", "vm01/zeros.vhdx", "vm02/dense.vhdx", "vm02/disk.vhdx",
+        ]);
+    }
+
+    #[test]
+    fn waiting_for_a_folder_returns_once_its_files_are_there() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, false).unwrap();
+        let extraction = Extraction::start(&bundle_path, &dir.path().join("dest")).unwrap();
+
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(|_| extraction.run(true));
+            extraction.wait_for_layout().unwrap();
+            assert_eq!(fs::read(extraction.lab_path().join("lab.toml")).unwrap(), lab_files()[0].1);
+            extraction.wait_for_folder(Path::new("vm01")).unwrap();
+            for (path, contents) in lab_files().into_iter().filter(|(p, _)| p.starts_with("vm01/")) {
+                assert_eq!(fs::read(checksums::from_key(extraction.lab_path(), path)).unwrap(), contents, "{}", path);
+            }
+            extraction.wait_for_all().unwrap();
+        }).unwrap();
+        assert!(extraction.is_finished());
+        assert!(extraction.take_error().is_none());
+        assert!(!is_partly_extracted(extraction.lab_path()));
+    }
+
+    #[test]
+    fn waiting_fails_once_the_extraction_does() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, false).unwrap();
+        let mut bundle = fs::read(&bundle_path).unwrap();
+        let dense = dense_disk();
+        let at = bundle.windows(64).position(|w| w == &dense[..64]).unwrap();
+        bundle[at] ^= 0xff;
+        fs::write(&bundle_path, bundle).unwrap();
+        let extraction = Extraction::start(&bundle_path, &dir.path().join("dest")).unwrap();
+
+        crossbeam_utils::thread::scope(|scope| {
+            scope.spawn(|_| extraction.run(true));
+            extraction.wait_for_folder(Path::new("vm01")).unwrap();
+            extraction.wait_for_folder(Path::new("vm02")).unwrap_err();
+            extraction.wait_for_all().unwrap_err();
+        }).unwrap();
+        let e = extraction.take_error().unwrap();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::ChecksumMismatch { .. })));
+        assert!(is_partly_extracted(extraction.lab_path()));
+    }
+
+    #[test]
+    fn an_interrupted_extraction_is_started_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, false).unwrap();
+        let dest_dir = dir.path().join("dest");
+        write_files(&dest_dir, &[("mylab/vm01/disk.vhdx", "half a disk"), ("mylab/.lama/extracting", "")]);
+
+        let extracted_path = extract(&bundle_path, &dest_dir, true).unwrap();
+        assert_eq!(fs::read(extracted_path.join("vm01").join("disk.vhdx")).unwrap(), disk_with_holes());
+
+        // Unless VMs were deployed from what it left
+        write_files(&dest_dir, &[("mylab/.lama/extracting", "")]);
+        Journal::new(&extracted_path).record(crate::journal::JournalEntry::CreatedSwitch { id: uuid::Uuid::nil(), name: "corp".to_owned() }).unwrap();
+        let e = extract(&bundle_path, &dest_dir, true).unwrap_err();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::CopyExists(_))));
+    }
+
+    #[test]
+    fn extract_does_not_overwrite_an_earlier_extraction() {
+        let dir = tempfile::tempdir().unwrap();
+        let lab_path = make_lab(dir.path());
+        let bundle_path = dir.path().join("mylab.lama");
+        pack_lab(&lab_path, &bundle_path, true).unwrap();
+        let dest_dir = dir.path().join("dest");
+        fs::create_dir_all(dest_dir.join("mylab")).unwrap();
+
+        let e = extract(&bundle_path, &dest_dir, true).unwrap_err();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::CopyExists(_))));
+    }
+}
@@ -0,0 +1,406 @@
+use crate::error::LamaError;
+use crate::progress::Progress;
+use crate::sha256;
+use crate::state::LAMA_DIR_NAME;
+use serde_derive::{Serialize, Deserialize};
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CHECKSUMS_FILE_NAME: &str = "checksums.json";
+pub const INDEX_FILE_NAME: &str = "index.json";
+
+/// Sizes and SHA-256 hashes of every file in the VM folders of an exported lab, kept in `checksums.json`
+/// at the lab root, so that a lab that got damaged on its way to the host is caught before it's deployed.
+/// They only hold until the lab is deployed in place, which changes the files of its VMs, so `deploy`
+/// moves them to `.lama/checksums.json` once it has checked them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checksums {
+    /// The VM folders, relative to the lab root
+    folders: Vec<String>,
+    /// Keyed by path relative to the lab root, with `/` separators whatever the platform
+    files: BTreeMap<String, FileChecksum>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FileChecksum {
+    size: u64,
+    sha256: String,
+}
+
+/// Sizes and SHA-256 hashes of every file of an exported lab but the index itself, kept in `index.json` at the lab root.
+/// A web server can't be asked what's in a folder, so this is what a lab is downloaded by.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LabIndex {
+    /// Keyed like `Checksums::files`
+    files: BTreeMap<String, FileChecksum>,
+}
+
+/// A way the files of a lab differ from its checksums. Paths are as in `Checksums::files`.
+pub enum Mismatch {
+    Missing(String),
+    /// A file in a VM folder that has no checksum
+    Extra(String),
+    WrongSize { path: String, expected: u64, actual: u64 },
+    WrongHash(String),
+}
+
+impl Checksums {
+    /// Hashes the files in the VM folders of the lab at `lab_path`
+    pub fn compute(lab_path: &Path, folders: &[PathBuf], progress: &Progress) -> Result<Self, Error> {
+        let mut checksums = Self { folders: Vec::new(), files: BTreeMap::new() };
+        for folder in folders {
+            let folder = to_key(folder);
+            progress.step(&format!("==> Computing checksums of {}", folder), || -> Result<(), Error> {
+                for path in list_files(lab_path, &folder)? {
+                    let file_path = from_key(lab_path, &path);
+                    let size = fs::metadata(&file_path)?.len();
+                    let sha256 = sha256::hash_file(&file_path)?;
+                    checksums.files.insert(path, FileChecksum { size, sha256 });
+                }
+                Ok(())
+            }, |_| "Done".to_owned())?;
+            checksums.folders.push(folder);
+        }
+        Ok(checksums)
+    }
+
+    /// Returns `None` if the lab has no checksums
+    pub fn load<P: AsRef<Path>>(lab_path: P) -> Result<Option<Self>, Error> {
+        let checksums_path = lab_path.as_ref().join(CHECKSUMS_FILE_NAME);
+        if !checksums_path.is_file() {
+            return Ok(None);
+        }
+
+        let checksums_file = fs::File::open(&checksums_path)?;
+        let checksums: Checksums = serde_json::from_reader(checksums_file)
+            .map_err(|e| LamaError::BadFile { path: checksums_path.clone(), msg: e.to_string() })?;
+        let bad_folders: Vec<&str> = checksums.folders.iter().map(|f| f.as_str()).filter(|f| !is_safe_key(f)).collect();
+        if !bad_folders.is_empty() {
+            return Err(LamaError::InvalidLab(format!("'{}' has folders outside the lab: {}", checksums_path.display(), bad_folders.join(", "))).into());
+        }
+        Ok(Some(checksums))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, lab_path: P) -> Result<(), Error> {
+        let checksums_file = fs::File::create(lab_path.as_ref().join(CHECKSUMS_FILE_NAME))?;
+        serde_json::to_writer_pretty(checksums_file, self)?;
+        Ok(())
+    }
+
+    /// Whether the lab was deployed in place since its checksums were written, which means they no longer hold
+    pub fn set_aside_exists<P: AsRef<Path>>(lab_path: P) -> bool {
+        lab_path.as_ref().join(LAMA_DIR_NAME).join(CHECKSUMS_FILE_NAME).is_file()
+    }
+
+    /// Moves the checksums out of the way before the lab is deployed in place
+    pub fn set_aside<P: AsRef<Path>>(lab_path: P) -> Result<(), Error> {
+        let lama_dir_path = lab_path.as_ref().join(LAMA_DIR_NAME);
+        fs::create_dir_all(&lama_dir_path)?;
+        fs::rename(lab_path.as_ref().join(CHECKSUMS_FILE_NAME), lama_dir_path.join(CHECKSUMS_FILE_NAME))?;
+        Ok(())
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The files that have a checksum, as keys of `files`
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|p| p.as_str())
+    }
+
+    /// Checks a file that was hashed elsewhere, such as while it was copied. Files outside the VM folders aren't checked.
+    pub fn check_file(&self, path: &str, size: u64, sha256: &str) -> Option<Mismatch> {
+        match self.files.get(path) {
+            Some(expected) => expected.check(path, size, sha256),
+            None if self.folders.iter().any(|f| path.starts_with(&format!("{}/", f))) => Some(Mismatch::Extra(path.to_owned())),
+            None => None,
+        }
+    }
+
+    /// Checks the files of the lab against the checksums. Files whose size is off aren't hashed.
+    pub fn verify(&self, lab_path: &Path, progress: &Progress) -> Result<Vec<Mismatch>, Error> {
+        let mut mismatches = Vec::new();
+        for folder in &self.folders {
+            let folder_mismatches = progress.step(&format!("==> Verifying {}", folder), || -> Result<Vec<Mismatch>, Error> {
+                let mut folder_mismatches = Vec::new();
+                let prefix = format!("{}/", folder);
+                let actual_files: BTreeSet<String> = list_files(lab_path, folder)?.into_iter().collect();
+                for (path, expected) in self.files.range(prefix.clone()..).take_while(|(p, _)| p.starts_with(&prefix)) {
+                    if !actual_files.contains(path) {
+                        folder_mismatches.push(Mismatch::Missing(path.clone()));
+                        continue;
+                    }
+
+                    let file_path = from_key(lab_path, path);
+                    let size = fs::metadata(&file_path)?.len();
+                    if size != expected.size {
+                        folder_mismatches.push(Mismatch::WrongSize { path: path.clone(), expected: expected.size, actual: size });
+                    } else if sha256::hash_file(&file_path)? != expected.sha256 {
+                        folder_mismatches.push(Mismatch::WrongHash(path.clone()));
+                    }
+                }
+
+                for path in actual_files {
+                    if !self.files.contains_key(&path) {
+                        folder_mismatches.push(Mismatch::Extra(path));
+                    }
+                }
+                Ok(folder_mismatches)
+            }, |m| match m.len() {
+                0 => "OK".to_owned(),
+                1 => "1 problem".to_owned(),
+                n => format!("{} problems", n),
+            })?;
+            mismatches.extend(folder_mismatches);
+        }
+        Ok(mismatches)
+    }
+}
+
+impl LabIndex {
+    pub fn new() -> Self {
+        Self { files: BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, path: &str, size: u64, sha256: String) {
+        self.files.insert(path.to_owned(), FileChecksum { size, sha256 });
+    }
+
+    /// Indexes the lab at `lab_path`, taking the hashes of its VMs' files from `checksums` rather than hashing them again
+    pub fn compute(lab_path: &Path, checksums: &Checksums) -> Result<Self, Error> {
+        let mut files = BTreeMap::new();
+        for path in list_files(lab_path, "")? {
+            if path == INDEX_FILE_NAME {
+                continue;
+            }
+
+            let checksum = match checksums.files.get(&path) {
+                Some(checksum) => checksum.clone(),
+                None => {
+                    let file_path = from_key(lab_path, &path);
+                    FileChecksum { size: fs::metadata(&file_path)?.len(), sha256: sha256::hash_file(&file_path)? }
+                }
+            };
+            files.insert(path, checksum);
+        }
+        Ok(Self { files })
+    }
+
+    /// Reads an index from wherever `path` says it came from
+    pub fn from_slice(bytes: &[u8], path: &Path) -> Result<Self, Error> {
+        let index = serde_json::from_slice(bytes).map_err(|e| LamaError::BadFile { path: path.to_owned(), msg: e.to_string() })?;
+        Ok(index)
+    }
+
+    /// Returns `None` if the lab has no index
+    pub fn load<P: AsRef<Path>>(lab_path: P) -> Result<Option<Self>, Error> {
+        let index_path = lab_path.as_ref().join(INDEX_FILE_NAME);
+        if !index_path.is_file() {
+            return Ok(None);
+        }
+        Self::from_slice(&fs::read(&index_path)?, &index_path).map(Some)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, lab_path: P) -> Result<(), Error> {
+        let index_file = fs::File::create(lab_path.as_ref().join(INDEX_FILE_NAME))?;
+        serde_json::to_writer_pretty(index_file, self)?;
+        Ok(())
+    }
+
+    /// The path, size and hash of each file
+    pub fn files(&self) -> impl Iterator<Item = (&str, u64, &str)> {
+        self.files.iter().map(|(path, f)| (path.as_str(), f.size, f.sha256.as_str()))
+    }
+
+    /// The files that are new or different since `old`
+    pub fn changed_since<'a>(&'a self, old: &'a LabIndex) -> impl Iterator<Item = &'a str> {
+        self.files.iter().filter(move |(path, f)| old.files.get(*path) != Some(f)).map(|(path, _)| path.as_str())
+    }
+
+    /// Checks a file that was hashed as it was downloaded
+    pub fn check_file(&self, path: &str, size: u64, sha256: &str) -> Option<Mismatch> {
+        match self.files.get(path) {
+            Some(expected) => expected.check(path, size, sha256),
+            None => Some(Mismatch::Extra(path.to_owned())),
+        }
+    }
+}
+
+impl FileChecksum {
+    fn check(&self, path: &str, size: u64, sha256: &str) -> Option<Mismatch> {
+        if size != self.size {
+            Some(Mismatch::WrongSize { path: path.to_owned(), expected: self.size, actual: size })
+        } else if sha256 != self.sha256 {
+            Some(Mismatch::WrongHash(path.to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Missing(path) => write!(f, "'{}' is missing", path),
+            Mismatch::Extra(path) => write!(f, "'{}' is not in the checksums", path),
+            Mismatch::WrongSize { path, expected, actual } => write!(f, "'{}' is {} bytes instead of {}", path, actual, expected),
+            Mismatch::WrongHash(path) => write!(f, "'{}' is corrupted", path),
+        }
+    }
+}
+
+/// The files under `folder` of the lab, or all of them if it's empty, as keys of `Checksums::files`.
+/// lama's own `.lama` folders are left out.
+pub fn list_files(lab_path: &Path, folder: &str) -> Result<Vec<String>, Error> {
+    let mut files = Vec::new();
+    let mut dirs = vec![folder.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let dir_path = from_key(lab_path, &dir);
+        if !dir_path.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir_path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string()
+                .map_err(|name| LamaError::InvalidLab(format!("Bad file name '{}' in '{}'", name.to_string_lossy(), dir_path.display())))?;
+            let path = if dir.is_empty() { name.clone() } else { format!("{}/{}", dir, name) };
+            if entry.file_type()?.is_dir() {
+                if name != LAMA_DIR_NAME {
+                    dirs.push(path);
+                }
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Whether a key from a file lama didn't write itself stays inside the lab
+pub fn is_safe_key(key: &str) -> bool {
+    key.split('/').all(|c| !c.is_empty() && c != "." && c != ".." && !c.contains(&['\\', ':'][..]))
+}
+
+/// The key of `files` for `path`, which is relative to the lab root
+pub fn to_key(path: &Path) -> String {
+    path.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+pub fn from_key(lab_path: &Path, key: &str) -> PathBuf {
+    key.split('/').fold(lab_path.to_owned(), |path, component| path.join(component))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lab_with_files;
+
+    /// A lab with two VM folders, each with a file in a subfolder, and a file at the lab root
+    fn make_lab() -> tempfile::TempDir {
+        lab_with_files(&[
+            ("dc01/Virtual Machines/vm.vmcx", b"dc01 config".to_vec()),
+            ("dc01/disk.vhdx", vec![7u8; 4096]),
+            ("web01/Virtual Machines/vm.vmcx", b"web01 config".to_vec()),
+            ("web01/disk.vhdx", vec![7u8; 4096]),
+            ("lab.toml", Vec::new()),
+        ])
+    }
+
+    fn compute(lab_path: &Path) -> Checksums {
+        Checksums::compute(lab_path, &[PathBuf::from("dc01"), PathBuf::from("web01")], &Progress::new(false)).unwrap()
+    }
+
+    fn verify(checksums: &Checksums, lab_path: &Path) -> Vec<String> {
+        checksums.verify(lab_path, &Progress::new(false)).unwrap().iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn verify_of_an_untouched_lab_finds_nothing() {
+        let lab = make_lab();
+        let checksums = compute(lab.path());
+        assert_eq!(checksums.file_count(), 4);
+        assert_eq!(checksums.paths().collect::<Vec<_>>(), vec![
+            "dc01/Virtual Machines/vm.vmcx", "dc01/disk.vhdx", "web01/Virtual Machines/vm.vmcx", "web01/disk.vhdx",
+        ]);
+        assert!(verify(&checksums, lab.path()).is_empty());
+    }
+
+    #[test]
+    fn verify_finds_every_kind_of_mismatch() {
+        let lab = make_lab();
+        let checksums = compute(lab.path());
+        fs::remove_file(lab.path().join("dc01").join("Virtual Machines").join("vm.vmcx")).unwrap();
+        fs::write(lab.path().join("dc01").join("disk.vhdx"), vec![7u8; 100]).unwrap();
+        fs::write(lab.path().join("web01").join("disk.vhdx"), vec![8u8; 4096]).unwrap();
+        fs::write(lab.path().join("web01").join("extra.txt"), "").unwrap();
+        // Neither files outside the VM folders nor lama's own files are checked
+        fs::write(lab.path().join("notes.txt"), "").unwrap();
+        fs::create_dir_all(lab.path().join("web01").join(LAMA_DIR_NAME)).unwrap();
+        fs::write(lab.path().join("web01").join(LAMA_DIR_NAME).join("state.json"), "").unwrap();
+
+        assert_eq!(verify(&checksums, lab.path()), vec![
+            "'dc01/Virtual Machines/vm.vmcx' is missing",
+            "'dc01/disk.vhdx' is 100 bytes instead of 4096",
+            "'web01/disk.vhdx' is corrupted",
+            "'web01/extra.txt' is not in the checksums",
+        ]);
+    }
+
+    #[test]
+    fn checksums_survive_a_save_and_load() {
+        let lab = make_lab();
+        assert!(Checksums::load(lab.path()).unwrap().is_none());
+        compute(lab.path()).save(lab.path()).unwrap();
+        let checksums = Checksums::load(lab.path()).unwrap().unwrap();
+        assert_eq!(checksums.file_count(), 4);
+        assert!(verify(&checksums, lab.path()).is_empty());
+    }
+
+    #[test]
+    fn checksums_with_folders_outside_the_lab_are_refused() {
+        let lab = make_lab();
+        fs::write(lab.path().join(CHECKSUMS_FILE_NAME), r#"{ "folders": ["dc01", "../secrets"], "files": {} }"#).unwrap();
+        let e = Checksums::load(lab.path()).unwrap_err();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::InvalidLab(msg)) if msg.ends_with("has folders outside the lab: ../secrets")), "{}", e);
+    }
+
+    #[test]
+    fn check_file_only_checks_files_in_the_vm_folders() {
+        let lab = make_lab();
+        let checksums = compute(lab.path());
+        let expected = checksums.files["dc01/disk.vhdx"].clone();
+        assert!(checksums.check_file("dc01/disk.vhdx", expected.size, &expected.sha256).is_none());
+        assert!(matches!(checksums.check_file("dc01/disk.vhdx", expected.size, "0"), Some(Mismatch::WrongHash(_))));
+        assert!(matches!(checksums.check_file("dc01/disk.vhdx", 1, &expected.sha256), Some(Mismatch::WrongSize { expected: 4096, actual: 1, .. })));
+        assert!(matches!(checksums.check_file("dc01/new.vhdx", 0, ""), Some(Mismatch::Extra(_))));
+        assert!(checksums.check_file("lab.toml", 0, "").is_none());
+        // A folder whose name starts with that of a VM folder isn't part of it
+        assert!(checksums.check_file("dc01-old/disk.vhdx", 0, "").is_none());
+    }
+
+    #[test]
+    fn is_safe_key_refuses_keys_that_leave_the_lab() {
+        for key in &["lab.toml", "dc01/Virtual Machines/vm.vmcx", "a..b/.c", "..."] {
+            assert!(is_safe_key(key), "{}", key);
+        }
+        for key in &["", "/", "/etc/passwd", "../lab.toml", "dc01/../../x", "./x", "dc01//x", "dc01/", "C:/x", "c:x", "dc01\\..\\x", "\\\\server\\share"] {
+            assert!(!is_safe_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_through_paths() {
+        let lab = Path::new("lab");
+        for key in &["lab.toml", "dc01/Virtual Machines/vm.vmcx", "a/b/c/d"] {
+            let path = from_key(lab, key);
+            assert_eq!(to_key(path.strip_prefix(lab).unwrap()), *key);
+        }
+        assert_eq!(to_key(&Path::new("dc01").join("Virtual Machines").join("vm.vmcx")), "dc01/Virtual Machines/vm.vmcx");
+        assert_eq!(from_key(lab, "dc01/disk.vhdx"), lab.join("dc01").join("disk.vhdx"));
+    }
+}
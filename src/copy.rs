@@ -0,0 +1,452 @@
+use crate::checksums::{self, Checksums, Mismatch};
+use crate::error::LamaError;
+use crate::logging;
+use crate::sha256::Sha256;
+use crate::state::LAMA_DIR_NAME;
+use failure::Error;
+use log::{info, warn, LevelFilter};
+use pbr::{ProgressBar, Units};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Appended to the name of the lab's folder for the folder it's copied into until the copy is complete
+pub const STAGING_SUFFIX: &str = ".lama-partial";
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+pub struct CopiedLab {
+    pub path: PathBuf,
+    /// Whether every file of the lab's VMs was checked against its checksums on the way
+    pub verified: bool,
+}
+
+struct SourceFile {
+    /// Relative to the lab root
+    path: PathBuf,
+    size: u64,
+}
+
+/// Copies the lab at `source_path` into `dest_dir`, in a folder of the same name. Files are copied one by one
+/// into a staging folder next to it, which is only renamed into place once all of them made it. A copy that
+/// was interrupted, e.g. by a dropped connection, is picked up where it stopped the next time.
+/// With `verify`, and if the lab has checksums, the files are checked against them as they're copied.
+pub fn copy_lab(source_path: &Path, dest_dir: &Path, verify: bool) -> Result<CopiedLab, Error> {
+    let folder_name = source_path.file_name().map(|n| n.to_owned()).unwrap_or_else(|| OsString::from("lab"));
+    let final_path = dest_dir.join(&folder_name);
+    if final_path.exists() {
+        return Err(LamaError::CopyExists(final_path).into());
+    }
+
+    let mut staging_name = folder_name;
+    staging_name.push(STAGING_SUFFIX);
+    let staging_path = dest_dir.join(staging_name);
+
+    let (dirs, files) = list_source(source_path)?;
+    let checksums = if verify { Checksums::load(source_path)? } else { None };
+    if let Some(checksums) = &checksums {
+        let keys: Vec<String> = files.iter().map(|f| checksums::to_key(&f.path)).collect();
+        let missing: Vec<String> = checksums.paths()
+            .filter(|p| !keys.iter().any(|k| k == p))
+            .map(|p| Mismatch::Missing(p.to_owned()).to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(LamaError::ChecksumMismatch { lab_path: source_path.to_owned(), mismatches: missing }.into());
+        }
+    }
+
+    let total: u64 = files.iter().map(|f| f.size).sum();
+    let staged: u64 = files.iter().map(|f| resumable_size(&staging_path.join(&f.path), f.size)).sum();
+    if staged > 0 {
+        info!("Resuming the copy in {} ({} of {} copied)", staging_path.display(), format_bytes(staged), format_bytes(total));
+    } else {
+        info!("Copying {} files ({}) to {}", files.len(), format_bytes(total), final_path.display());
+    }
+
+    let mut progress = CopyProgress::new(total - staged);
+    let result = copy_files(source_path, &staging_path, &dirs, &files, checksums.as_ref(), &mut progress);
+    progress.finish();
+    match result {
+        Ok(mismatches) if mismatches.is_empty() => {}
+        Ok(mismatches) => {
+            warn!("The partial copy is kept in '{}'. Deploying again copies the corrupted files again", staging_path.display());
+            return Err(LamaError::ChecksumMismatch { lab_path: source_path.to_owned(), mismatches }.into());
+        }
+        Err(e) => {
+            warn!("The partial copy is kept in '{}'. Deploying again picks it up where it stopped", staging_path.display());
+            return Err(e);
+        }
+    }
+
+    fs::rename(&staging_path, &final_path)?;
+    info!("Copied the lab to {}", final_path.display());
+    Ok(CopiedLab { path: final_path, verified: checksums.is_some() })
+}
+
+/// Copies `files` into the staging folder and returns how any that are still wrong after a second try differ from the checksums
+fn copy_files(source_path: &Path, staging_path: &Path, dirs: &[PathBuf], files: &[SourceFile], checksums: Option<&Checksums>, progress: &mut CopyProgress) -> Result<Vec<String>, Error> {
+    fs::create_dir_all(staging_path)?;
+    for dir in dirs {
+        fs::create_dir_all(staging_path.join(dir))?;
+    }
+
+    let mut mismatches = Vec::new();
+    for file in files {
+        let key = checksums::to_key(&file.path);
+        let source_file_path = source_path.join(&file.path);
+        let dest_file_path = staging_path.join(&file.path);
+        progress.start_file(&key, file.size);
+        let (size, sha256) = copy_file(&source_file_path, &dest_file_path, progress)?;
+        let mismatch = match check_copy(&key, file.size, size, &sha256, checksums) {
+            Some(mismatch) => {
+                // A resumed file may have picked up garbage from the interrupted copy, so it's copied again from scratch
+                warn!("{} after copying it. Copying it again", mismatch);
+                fs::remove_file(&dest_file_path)?;
+                progress.add_to_total(file.size);
+                progress.start_file(&key, file.size);
+                let (size, sha256) = copy_file(&source_file_path, &dest_file_path, progress)?;
+                check_copy(&key, file.size, size, &sha256, checksums)
+            }
+            None => None,
+        };
+        if let Some(mismatch) = mismatch {
+            mismatches.push(mismatch.to_string());
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Files of the lab's VMs are checked against the checksums. The others only against the size they had before the copy.
+fn check_copy(key: &str, expected_size: u64, size: u64, sha256: &str, checksums: Option<&Checksums>) -> Option<Mismatch> {
+    match checksums.and_then(|c| c.check_file(key, size, sha256)) {
+        Some(mismatch) => Some(mismatch),
+        None if size != expected_size => Some(Mismatch::WrongSize { path: key.to_owned(), expected: expected_size, actual: size }),
+        None => None,
+    }
+}
+
+/// Copies `source` to `dest`, carrying on from the end of `dest` if it's already there. Returns the size and hash of the copy.
+fn copy_file(source: &Path, dest: &Path, progress: &mut CopyProgress) -> Result<(u64, String), Error> {
+    let mut source_file = fs::File::open(source)?;
+    let mut hasher = Sha256::new();
+    let (mut dest_file, offset) = open_to_resume(dest, source_file.metadata()?.len(), &mut hasher)?;
+    progress.skip(offset);
+    source_file.seek(SeekFrom::Start(offset))?;
+    let size = offset + append(&mut source_file, &mut dest_file, &mut hasher, progress)?;
+    Ok((size, hasher.finish()))
+}
+
+/// Opens `dest` to go on writing at its end, and returns it along with where that is. What's already there is
+/// hashed into `hasher` rather than trusted, so that the hash covers the whole file. A file longer than `max_size`
+/// can't be the start of the one being written, so it's started over.
+pub fn open_to_resume(dest: &Path, max_size: u64, hasher: &mut Sha256) -> Result<(fs::File, u64), Error> {
+    let mut dest_file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dest)?;
+    let mut offset = 0;
+    if dest_file.metadata()?.len() > max_size {
+        dest_file.set_len(0)?;
+    } else {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let read = dest_file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            offset += read as u64;
+        }
+    }
+    dest_file.seek(SeekFrom::Start(offset))?;
+    Ok((dest_file, offset))
+}
+
+/// Appends everything left in `source` to `dest_file`, and returns how much that was
+pub fn append<R: Read>(source: &mut R, dest_file: &mut fs::File, hasher: &mut Sha256, progress: &mut CopyProgress) -> Result<u64, Error> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)?,
+        };
+        dest_file.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+        progress.add(read as u64);
+    }
+    dest_file.sync_all()?;
+    Ok(size)
+}
+
+pub fn staged_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// How much of a file of `size` bytes is already at `path` for `open_to_resume` to carry on from.
+/// Nothing if what's there is longer, since it's started over.
+pub fn resumable_size(path: &Path, size: u64) -> u64 {
+    match staged_size(path) {
+        staged if staged > size => 0,
+        staged => staged,
+    }
+}
+
+/// The folders and files of the lab, relative to its root, leaving out lama's own `.lama` folders
+fn list_source(source_path: &Path) -> Result<(Vec<PathBuf>, Vec<SourceFile>), Error> {
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(source_path.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                if entry.file_name() != LAMA_DIR_NAME {
+                    dirs.push(path.clone());
+                    pending.push(path);
+                }
+            } else {
+                files.push(SourceFile { path, size: metadata.len() });
+            }
+        }
+    }
+    dirs.sort();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((dirs, files))
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// A progress bar over the bytes left to copy, with the speed and time left, and the file being copied in front.
+/// It's only shown on a plain console since nothing else can be printed while it's there.
+pub struct CopyProgress {
+    bar: Option<ProgressBar<Stdout>>,
+    file_name: String,
+    file_size: u64,
+    file_copied: u64,
+}
+
+impl CopyProgress {
+    pub fn new(total: u64) -> Self {
+        let bar = if logging::console_level() == LevelFilter::Info {
+            let mut bar = ProgressBar::new(total);
+            bar.set_units(Units::Bytes);
+            bar.set_max_refresh_rate(Some(Duration::from_millis(200)));
+            bar.format("[=>-]");
+            Some(bar)
+        } else {
+            None
+        };
+        Self { bar, file_name: String::new(), file_size: 0, file_copied: 0 }
+    }
+
+    pub fn start_file(&mut self, key: &str, size: u64) {
+        self.file_name = key.rsplit('/').next().unwrap_or(key).to_owned();
+        self.file_size = size;
+        self.file_copied = 0;
+        self.update_message();
+    }
+
+    /// For bytes that have to be copied again, or whose count wasn't known up front
+    pub fn add_to_total(&mut self, bytes: u64) {
+        if let Some(bar) = &mut self.bar {
+            bar.total += bytes;
+        }
+    }
+
+    /// For when the size of the file wasn't known when it was started
+    pub fn set_file_size(&mut self, size: u64) {
+        self.file_size = size;
+        self.update_message();
+    }
+
+    /// The file is started over
+    pub fn restart_file(&mut self) {
+        self.file_copied = 0;
+        self.update_message();
+    }
+
+    /// Counts the part of the file that was already copied, which isn't part of the total
+    pub fn skip(&mut self, bytes: u64) {
+        self.file_copied += bytes;
+        self.update_message();
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.file_copied += bytes;
+        self.update_message();
+        if let Some(bar) = &mut self.bar {
+            bar.add(bytes);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(bar) = &mut self.bar {
+            bar.finish_println("");
+        }
+    }
+
+    fn update_message(&mut self) {
+        if let Some(bar) = &mut self.bar {
+            let percent = (self.file_copied.min(self.file_size) * 100).checked_div(self.file_size).unwrap_or(100);
+            bar.message(&format!("{} {:>3}% ", self.file_name, percent));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCategory;
+    use crate::progress::Progress;
+    use crate::tests::{contents, hash, write_files};
+
+    /// A lab with one VM whose disk spans a few buffers, and checksums for it
+    fn make_lab(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let lab_path = dir.join("lab");
+        let disk = contents(2 * BUFFER_SIZE + 123, 0);
+        write_files(&lab_path, &[
+            ("vm01/Virtual Machines/vm.vmcx", b"config".to_vec()),
+            ("vm01/disk.vhdx", disk.clone()),
+            ("lab.toml", Vec::new()),
+        ]);
+        Checksums::compute(&lab_path, &[PathBuf::from("vm01")], &Progress::new(false)).unwrap().save(&lab_path).unwrap();
+        (lab_path, disk)
+    }
+
+    /// Leaves a partial copy of the disk behind, as an interrupted copy would
+    fn stage_disk(dest_dir: &Path, partial: &[u8]) -> PathBuf {
+        let staging_path = dest_dir.join(format!("lab{}", STAGING_SUFFIX));
+        write_files(&staging_path, &[("vm01/disk.vhdx", partial)]);
+        staging_path.join("vm01").join("disk.vhdx")
+    }
+
+    #[test]
+    fn copy_file_resumes_at_the_end_of_the_partial_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = contents(BUFFER_SIZE + 1000, 0);
+        fs::write(dir.path().join("source"), &source).unwrap();
+        // What's already there is kept rather than copied again, even when it's wrong
+        let mut partial = source[..1000].to_vec();
+        partial[0] ^= 0xff;
+        fs::write(dir.path().join("dest"), &partial).unwrap();
+
+        let mut progress = CopyProgress::new(source.len() as u64 - 1000);
+        let (size, sha256) = copy_file(&dir.path().join("source"), &dir.path().join("dest"), &mut progress).unwrap();
+
+        let mut expected = partial;
+        expected.extend_from_slice(&source[1000..]);
+        assert_eq!(fs::read(dir.path().join("dest")).unwrap(), expected);
+        assert_eq!(size, source.len() as u64);
+        assert_eq!(sha256, hash(&expected));
+    }
+
+    #[test]
+    fn open_to_resume_starts_an_oversized_partial_copy_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        fs::write(&dest, contents(100, 0)).unwrap();
+        assert_eq!(resumable_size(&dest, 99), 0);
+        assert_eq!(resumable_size(&dest, 100), 100);
+        assert_eq!(resumable_size(&dir.path().join("missing"), 100), 0);
+
+        let mut hasher = Sha256::new();
+        let (_, offset) = open_to_resume(&dest, 99, &mut hasher).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(staged_size(&dest), 0);
+        assert_eq!(hasher.finish(), hash(b""));
+
+        fs::write(&dest, contents(100, 0)).unwrap();
+        let mut hasher = Sha256::new();
+        let (_, offset) = open_to_resume(&dest, 100, &mut hasher).unwrap();
+        assert_eq!(offset, 100);
+        assert_eq!(hasher.finish(), hash(&contents(100, 0)));
+    }
+
+    #[test]
+    fn copy_lab_resumes_an_interrupted_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lab_path, disk) = make_lab(dir.path());
+        let dest_dir = dir.path().join("dest");
+        stage_disk(&dest_dir, &disk[..BUFFER_SIZE + 7]);
+
+        let copied = copy_lab(&lab_path, &dest_dir, true).unwrap();
+        assert_eq!(copied.path, dest_dir.join("lab"));
+        assert!(copied.verified);
+        assert_eq!(fs::read(copied.path.join("vm01").join("disk.vhdx")).unwrap(), disk);
+        assert_eq!(fs::read(copied.path.join("vm01").join("Virtual Machines").join("vm.vmcx")).unwrap(), b"config");
+        assert!(!dest_dir.join(format!("lab{}", STAGING_SUFFIX)).exists());
+    }
+
+    #[test]
+    fn copy_lab_starts_oversized_partial_copies_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lab_path, disk) = make_lab(dir.path());
+        let dest_dir = dir.path().join("dest");
+        let mut partial = disk.clone();
+        partial.extend_from_slice(b"left over from an older version of the lab");
+        stage_disk(&dest_dir, &partial);
+
+        let copied = copy_lab(&lab_path, &dest_dir, true).unwrap();
+        assert_eq!(fs::read(copied.path.join("vm01").join("disk.vhdx")).unwrap(), disk);
+    }
+
+    #[test]
+    fn copy_lab_copies_a_corrupted_partial_copy_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lab_path, disk) = make_lab(dir.path());
+        let dest_dir = dir.path().join("dest");
+        let mut partial = disk[..BUFFER_SIZE].to_vec();
+        partial[10] ^= 0xff;
+        stage_disk(&dest_dir, &partial);
+
+        let copied = copy_lab(&lab_path, &dest_dir, true).unwrap();
+        assert_eq!(fs::read(copied.path.join("vm01").join("disk.vhdx")).unwrap(), disk);
+    }
+
+    #[test]
+    fn copy_lab_without_verify_keeps_a_corrupted_partial_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lab_path, disk) = make_lab(dir.path());
+        let dest_dir = dir.path().join("dest");
+        let mut partial = disk[..BUFFER_SIZE].to_vec();
+        partial[10] ^= 0xff;
+        stage_disk(&dest_dir, &partial);
+
+        // Only the size is checked, and that's right
+        let copied = copy_lab(&lab_path, &dest_dir, false).unwrap();
+        assert!(!copied.verified);
+        assert_eq!(fs::read(copied.path.join("vm01").join("disk.vhdx")).unwrap()[10], partial[10]);
+    }
+
+    #[test]
+    fn copy_lab_does_not_overwrite_an_earlier_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (lab_path, _) = make_lab(dir.path());
+        let dest_dir = dir.path().join("dest");
+        fs::create_dir_all(dest_dir.join("lab")).unwrap();
+        fs::write(dest_dir.join("lab").join("lab.toml"), "mine").unwrap();
+
+        let e = copy_lab(&lab_path, &dest_dir, true).err().unwrap();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::CopyExists(path)) if *path == dest_dir.join("lab")));
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::Conflict);
+        assert_eq!(fs::read(dest_dir.join("lab").join("lab.toml")).unwrap(), b"mine");
+        assert!(!dest_dir.join(format!("lab{}", STAGING_SUFFIX)).exists());
+    }
+}
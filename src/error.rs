@@ -0,0 +1,273 @@
+use crate::backend::{BackendError, CommandOutput, Operation, SwitchKind, VmId};
+use failure::{Error, Fail};
+use serde_derive::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// What kind of thing went wrong, so that callers can react to errors without parsing their messages.
+/// Each category has an exit code of its own, see the table in the README.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// Something that was asked for doesn't exist
+    NotFound,
+    /// What was asked for, or the lab it was asked of, doesn't make sense
+    InvalidInput,
+    /// The lab is in a state that doesn't allow what was asked for
+    Conflict,
+    /// A VM can't be imported on this host
+    Incompatible,
+    /// The hypervisor, or the PowerShell used to drive it, failed
+    Backend,
+    Timeout,
+    Internal,
+    RolledBack,
+    Aborted,
+    /// The lab's files don't match the checksums it was exported with
+    Corrupted,
+    /// A lab couldn't be downloaded
+    Network,
+    /// Undoing a deploy failed part way, so some of what it created is still there
+    CleanupIncomplete,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Internal => 1,
+            ErrorCategory::NotFound => 2,
+            ErrorCategory::InvalidInput => 3,
+            ErrorCategory::Conflict => 4,
+            ErrorCategory::Incompatible => 5,
+            ErrorCategory::Backend => 6,
+            ErrorCategory::Timeout => 7,
+            ErrorCategory::RolledBack => 8,
+            ErrorCategory::Aborted => 9,
+            ErrorCategory::Corrupted => 10,
+            ErrorCategory::Network => 11,
+            ErrorCategory::CleanupIncomplete => 12,
+        }
+    }
+
+    /// The category of any error, falling back to `Internal` for the ones that aren't ours
+    pub fn of(error: &Error) -> Self {
+        if let Some(e) = error.downcast_ref::<LamaError>() {
+            e.category()
+        } else if let Some(e) = error.downcast_ref::<BackendError>() {
+            e.category()
+        } else {
+            ErrorCategory::Internal
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LamaError {
+    PathNotFound(PathBuf),
+    UrlNotFound(String),
+    /// Nothing has been deployed from the lab
+    NotDeployed(PathBuf),
+    VmNotDeployed { vm_id: VmId, vm_path: Option<PathBuf> },
+    NothingToResume(PathBuf),
+    /// A previous deploy of the lab was interrupted and has to be resumed or dropped first
+    UnfinishedDeploy(PathBuf),
+    /// The lab on disk isn't something that can be deployed
+    InvalidLab(String),
+    /// A file of lama's own couldn't be read
+    BadFile { path: PathBuf, msg: String },
+    /// The state file was written by a newer version of lama
+    NewerVersion { path: PathBuf, lama_version: String },
+    /// A bad commandline argument or answer to a prompt
+    InvalidInput(String),
+    /// A question had to be asked but there was no one to answer it. `flags` answer it up front.
+    NeedsAnswer { question: String, flags: &'static str },
+    AlreadyDeployed(PathBuf),
+    /// A lab was to be copied to where there's already something
+    CopyExists(PathBuf),
+    /// A switch the lab needs is already on the host and was created by lama for another lab
+    SwitchNameTaken { name: String },
+    /// A switch the lab needs is already on the host, wasn't created by lama and isn't an external switch the lab can share
+    SwitchExists { name: String, switch_type: SwitchKind },
+    /// Switches of the lab that weren't deleted because VMs outside the lab are still connected to them
+    SwitchesInUse { names: Vec<String> },
+    LinkLocalAddressTimeout { vm_id: VmId },
+    /// `mismatches` describe how the lab's files differ from its checksums
+    ChecksumMismatch { lab_path: PathBuf, mismatches: Vec<String> },
+    /// The archive or bundle at `url` has no `.sha256` file next to it to be checked against
+    MissingHash { url: String },
+    /// `url` couldn't be fetched, e.g. because the server is unreachable or answered with an error
+    DownloadFailed { url: String, msg: String },
+    Internal(String),
+    /// The deploy failed with `cause` and everything it had done was undone
+    RolledBack { cause: Error },
+    /// Rolling back a deploy, which failed with `cause` if it was this run's, left behind what `failures` describe
+    CleanupIncomplete { cause: Option<Error>, failures: Vec<String> },
+    Aborted,
+}
+
+impl Fail for LamaError {}
+
+impl LamaError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            LamaError::PathNotFound(_)
+            | LamaError::UrlNotFound(_)
+            | LamaError::NotDeployed(_)
+            | LamaError::VmNotDeployed { .. }
+            | LamaError::NothingToResume(_)
+            | LamaError::MissingHash { .. } => ErrorCategory::NotFound,
+            LamaError::InvalidLab(_)
+            | LamaError::BadFile { .. }
+            | LamaError::InvalidInput(_)
+            | LamaError::NeedsAnswer { .. } => ErrorCategory::InvalidInput,
+            LamaError::UnfinishedDeploy(_)
+            | LamaError::AlreadyDeployed(_)
+            | LamaError::CopyExists(_)
+            | LamaError::SwitchNameTaken { .. }
+            | LamaError::SwitchExists { .. }
+            | LamaError::SwitchesInUse { .. }
+            | LamaError::NewerVersion { .. } => ErrorCategory::Conflict,
+            LamaError::LinkLocalAddressTimeout { .. } => ErrorCategory::Timeout,
+            LamaError::ChecksumMismatch { .. } => ErrorCategory::Corrupted,
+            LamaError::DownloadFailed { .. } => ErrorCategory::Network,
+            LamaError::Internal(_) => ErrorCategory::Internal,
+            LamaError::RolledBack { .. } => ErrorCategory::RolledBack,
+            LamaError::CleanupIncomplete { .. } => ErrorCategory::CleanupIncomplete,
+            LamaError::Aborted => ErrorCategory::Aborted,
+        }
+    }
+}
+
+impl fmt::Display for LamaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LamaError::PathNotFound(path) => write!(f, "Path '{}' does not exist", path.display()),
+            LamaError::UrlNotFound(url) => write!(f, "'{}' was not found on the server", url),
+            LamaError::NotDeployed(lab_path) => write!(f, "Lab '{}' is not deployed", lab_path.display()),
+            LamaError::VmNotDeployed { vm_id, vm_path: Some(vm_path) } => write!(f, "VM {} in '{}' is not deployed", vm_id, vm_path.display()),
+            LamaError::VmNotDeployed { vm_id, vm_path: None } => write!(f, "VM {} is not deployed", vm_id),
+            LamaError::NothingToResume(lab_path) => write!(f, "No unfinished deploy of '{}' to resume", lab_path.display()),
+            LamaError::UnfinishedDeploy(lab_path) => write!(f, "A previous deploy of '{}' did not finish. Deploy with --resume to continue it or drop the lab", lab_path.display()),
+            LamaError::InvalidLab(msg) => write!(f, "{}", msg),
+            LamaError::BadFile { path, msg } => write!(f, "Failed to parse '{}': {}", path.display(), msg),
+            LamaError::NewerVersion { path, lama_version } => write!(f, "'{}' was written by a newer version of lama ({}). Please upgrade.", path.display(), lama_version),
+            LamaError::InvalidInput(msg) => write!(f, "{}", msg),
+            LamaError::NeedsAnswer { question, flags } => write!(f, "{}, and lama can't ask what to do since it's not running interactively. Pass {} to say up front", question, flags),
+            LamaError::AlreadyDeployed(lab_path) => write!(f, "Lab '{}' is already deployed. Pass --redeploy or --missing-only to say what to do about it", lab_path.display()),
+            LamaError::CopyExists(path) => write!(f, "'{}' already exists. Deploy from it, or remove it to copy the lab again", path.display()),
+            LamaError::SwitchNameTaken { name } => write!(f, "Switch '{}' already exists and belongs to another lab. Deploy with --name to give this lab's switches names of their own", name),
+            LamaError::SwitchExists { name, switch_type } => write!(f, "A {} switch named '{}' already exists and was not created by lama. Only an existing external switch is shared with the lab. Rename or remove the switch, or deploy with --name to give this lab's switches names of their own", format!("{:?}", switch_type).to_lowercase(), name),
+            LamaError::SwitchesInUse { names } => {
+                let quoted: Vec<String> = names.iter().map(|n| format!("'{}'", n)).collect();
+                let (switches, were, them) = if names.len() == 1 { ("Switch", "was", "it") } else { ("Switches", "were", "them") };
+                write!(f, "{} {} {} kept because VMs outside the lab are still connected to {}. Drop again once they're disconnected", switches, quoted.join(", "), were, them)
+            }
+            LamaError::LinkLocalAddressTimeout { vm_id } => write!(f, "Timed out waiting for VM {} to report a link-local address", vm_id),
+            LamaError::ChecksumMismatch { lab_path, mismatches } => write!(f, "The files of lab '{}' don't match its checksums:\n  {}", lab_path.display(), mismatches.join("\n  ")),
+            LamaError::MissingHash { url } => write!(f, "There's no '{}.sha256' to check '{}' against. Put the hash next to it the way sha256sum writes it, or deploy with --skip-verify", url, url),
+            LamaError::DownloadFailed { url, msg } => write!(f, "Failed to download '{}': {}", url, msg),
+            LamaError::Internal(msg) => write!(f, "{}", msg),
+            LamaError::RolledBack { cause } => write!(f, "{}\nThe partially deployed lab was rolled back", cause),
+            LamaError::CleanupIncomplete { cause, failures } => {
+                if let Some(cause) = cause {
+                    writeln!(f, "{}", cause)?;
+                }
+                write!(f, "Rolling back the partially deployed lab left some of it behind. Drop the lab to try again, or clean up by hand:\n  {}", failures.join("\n  "))
+            }
+            LamaError::Aborted => write!(f, "Aborted"),
+        }
+    }
+}
+
+/// What `--json` prints to stderr when lama fails
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub exit_code: i32,
+    pub category: ErrorCategory,
+    pub message: String,
+    /// For a deploy that was rolled back, or failed to be, the category of what made it fail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause_category: Option<ErrorCategory>,
+    /// The Hyper-V operation that failed, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<Operation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<CommandOutput>,
+}
+
+impl ErrorReport {
+    pub fn new(error: &Error) -> Self {
+        let category = ErrorCategory::of(error);
+        let cause = match error.downcast_ref::<LamaError>() {
+            Some(LamaError::RolledBack { cause }) => Some(cause),
+            Some(LamaError::CleanupIncomplete { cause: Some(cause), .. }) => Some(cause),
+            _ => None,
+        };
+        let backend_error = cause.unwrap_or(error).downcast_ref::<BackendError>();
+        Self {
+            exit_code: category.exit_code(),
+            category,
+            message: error.to_string(),
+            cause_category: cause.map(ErrorCategory::of),
+            operation: backend_error.map(|e| e.operation()),
+            output: backend_error.and_then(|e| e.output()).cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Scripts rely on these, so they must never change
+    const EXIT_CODES: &[(ErrorCategory, i32, &str)] = &[
+        (ErrorCategory::Internal, 1, "internal"),
+        (ErrorCategory::NotFound, 2, "not_found"),
+        (ErrorCategory::InvalidInput, 3, "invalid_input"),
+        (ErrorCategory::Conflict, 4, "conflict"),
+        (ErrorCategory::Incompatible, 5, "incompatible"),
+        (ErrorCategory::Backend, 6, "backend"),
+        (ErrorCategory::Timeout, 7, "timeout"),
+        (ErrorCategory::RolledBack, 8, "rolled_back"),
+        (ErrorCategory::Aborted, 9, "aborted"),
+        (ErrorCategory::Corrupted, 10, "corrupted"),
+        (ErrorCategory::Network, 11, "network"),
+        (ErrorCategory::CleanupIncomplete, 12, "cleanup_incomplete"),
+    ];
+
+    #[test]
+    fn exit_codes_are_stable() {
+        for (category, exit_code, name) in EXIT_CODES {
+            assert_eq!(category.exit_code(), *exit_code, "{:?}", category);
+            assert_eq!(serde_json::to_string(category).unwrap(), format!("\"{}\"", name));
+        }
+    }
+
+    #[test]
+    fn readme_lists_every_exit_code() {
+        let readme = include_str!("../README.md");
+        let rows: Vec<(i32, String)> = readme[readme.find("# Exit Codes").unwrap()..].lines()
+            .skip_while(|l| !l.starts_with("|---"))
+            .skip(1)
+            .take_while(|l| l.starts_with('|'))
+            .map(|l| l.split('|').map(|c| c.trim().trim_matches('`')).collect::<Vec<_>>())
+            .filter(|cells| cells[1] != "0")
+            .map(|cells| (cells[1].parse().unwrap(), cells[2].to_owned()))
+            .collect();
+        let expected: Vec<(i32, String)> = EXIT_CODES.iter().map(|(_, code, name)| (*code, (*name).to_owned())).collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn report_of_a_rolled_back_deploy_has_the_cause_category() {
+        let cause: Error = LamaError::LinkLocalAddressTimeout { vm_id: VmId::nil() }.into();
+        let report = ErrorReport::new(&LamaError::RolledBack { cause }.into());
+        assert_eq!(report.exit_code, 8);
+        assert_eq!(report.category, ErrorCategory::RolledBack);
+        assert_eq!(report.cause_category, Some(ErrorCategory::Timeout));
+
+        let report = ErrorReport::new(&LamaError::CleanupIncomplete { cause: None, failures: vec!["VM x".to_owned()] }.into());
+        assert_eq!(report.exit_code, 12);
+        assert_eq!(report.cause_category, None);
+    }
+}
@@ -0,0 +1,455 @@
+use crate::backend::{Backend, BackendError, CommandOutput, Operation, Result, Vm, VmId, ImportedVm, Adapter, Switch, SwitchStatus, SwitchKind, SwitchType, RenameAction, ProvisionTarget};
+use serde_derive::{Serialize, Deserialize};
+use uuid::Uuid;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// The host adapter external switches are bound to when the lab doesn't name one
+pub const DEFAULT_UPLINK_NAME: &str = "Ethernet";
+
+/// An in-memory backend for exercising the deploy/drop logic without a hypervisor.
+///
+/// VMs are "imported" from lab folders containing a dummy `.vmcx` file. The file may
+/// be empty, or contain JSON describing the VM's name and adapters:
+///
+/// ```json
+/// { "name": "dc01", "adapters": { "Network Adapter": "corp" } }
+/// ```
+///
+/// where each adapter is mapped to the name of the switch it was connected to on export
+/// (empty if it wasn't connected).
+/// Like Hyper-V, the import replaces the `.vmcx` file with one named after the new VM ID.
+///
+/// Every call is recorded and the backend can be made to fail on the Nth call.
+pub struct FakeBackend {
+    state: Mutex<FakeState>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    vms: HashMap<VmId, FakeVm>,
+    switches: HashMap<Uuid, FakeSwitch>,
+    calls: Vec<Call>,
+    fail_on_calls: Vec<usize>,
+    last_id: u128,
+    trusted_hosts: Vec<String>,
+}
+
+impl FakeState {
+    // IDs are handed out sequentially so that runs are deterministic
+    fn new_id(&mut self) -> Uuid {
+        self.last_id += 1;
+        Uuid::from_bytes(self.last_id.to_be_bytes())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeVm {
+    pub id: VmId,
+    pub name: String,
+    pub path: PathBuf,
+    pub running: bool,
+    /// Keyed by adapter ID
+    pub adapters: BTreeMap<String, FakeAdapter>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeAdapter {
+    pub name: String,
+    pub switch_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeSwitch {
+    pub id: Uuid,
+    pub name: String,
+    pub switch_type: SwitchKind,
+    pub net_adapter_name: Option<String>,
+    /// Address of the host's adapter on an internal switch
+    pub host_ip: Option<String>,
+    pub notes: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    GetVms,
+    ImportVm(PathBuf),
+    StartVm(VmId),
+    StopVm(VmId),
+    DeleteVm(VmId),
+    CreateSwitch(String),
+    DeleteSwitch(String),
+    ConnectAdapter(VmId, String, String),
+    ExportVm(VmId, PathBuf),
+    GetVmAdapters(VmId),
+    GetExportedVmAdapters(PathBuf),
+    GetSwitches,
+    GetSwitchVmIds(String),
+    GetVmLinkLocalAddress(VmId),
+    TrustHosts(Vec<String>),
+    RunProvisioner(PathBuf, Vec<VmId>),
+}
+
+impl Call {
+    fn operation(&self) -> Operation {
+        match self {
+            Call::GetVms => Operation::GetVms,
+            Call::ImportVm(_) => Operation::ImportVm,
+            Call::StartVm(_) => Operation::StartVm,
+            Call::StopVm(_) => Operation::StopVm,
+            Call::DeleteVm(_) => Operation::DeleteVm,
+            Call::CreateSwitch(_) => Operation::CreateSwitch,
+            Call::DeleteSwitch(_) => Operation::DeleteSwitch,
+            Call::ConnectAdapter(..) => Operation::ConnectAdapter,
+            Call::ExportVm(..) => Operation::ExportVm,
+            Call::GetVmAdapters(_) => Operation::GetVmAdapters,
+            Call::GetExportedVmAdapters(_) => Operation::GetExportedVmAdapters,
+            Call::GetSwitches => Operation::GetSwitches,
+            Call::GetSwitchVmIds(_) => Operation::GetSwitchVms,
+            Call::GetVmLinkLocalAddress(_) => Operation::GetVmLinkLocalAddress,
+            Call::TrustHosts(_) => Operation::TrustHosts,
+            Call::RunProvisioner(..) => Operation::RunProvisioner,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct FakeVmcx {
+    name: Option<String>,
+    #[serde(default)]
+    adapters: BTreeMap<String, String>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self { state: Mutex::new(FakeState::default()) }
+    }
+
+    /// Makes the `n`th call (1-based, counting all calls made so far) fail. Can be called more than once.
+    pub fn fail_on_call(&self, n: usize) {
+        self.lock().fail_on_calls.push(n);
+    }
+
+    /// Adds a switch that exists on the "host" before any lab is deployed
+    pub fn add_switch(&self, name: &str, switch_type: SwitchKind) -> Uuid {
+        let mut state = self.lock();
+        let id = state.new_id();
+        state.switches.insert(id, FakeSwitch { id, name: name.to_owned(), switch_type, net_adapter_name: None, host_ip: None, notes: String::new() });
+        id
+    }
+
+    /// Adds a VM from outside any lab with one adapter connected to each of the named switches
+    pub fn add_vm(&self, name: &str, switch_names: &[&str]) -> VmId {
+        let mut state = self.lock();
+        let id = state.new_id();
+        let mut adapters = BTreeMap::new();
+        for (i, switch_name) in switch_names.iter().enumerate() {
+            let switch_id = state.switches.values().find(|s| s.name == *switch_name).map(|s| s.id);
+            adapters.insert(format!("{}\\Adapter {}", id, i), FakeAdapter { name: format!("Adapter {}", i), switch_id });
+        }
+        state.vms.insert(id, FakeVm { id, name: name.to_owned(), path: PathBuf::new(), running: true, adapters });
+        id
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.lock().calls.clone()
+    }
+
+    pub fn vms(&self) -> Vec<FakeVm> {
+        self.lock().vms.values().cloned().collect()
+    }
+
+    pub fn switches(&self) -> Vec<FakeSwitch> {
+        self.lock().switches.values().cloned().collect()
+    }
+
+    pub fn trusted_hosts(&self) -> Vec<String> {
+        self.lock().trusted_hosts.clone()
+    }
+
+    /// Returns the name of every switch mapped to the names of the VMs connected to it
+    pub fn switch_graph(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let state = self.lock();
+        let mut graph: BTreeMap<String, BTreeSet<String>> = state.switches.values()
+            .map(|s| (s.name.clone(), BTreeSet::new()))
+            .collect();
+
+        for vm in state.vms.values() {
+            for switch_id in vm.adapters.values().filter_map(|a| a.switch_id.as_ref()) {
+                if let Some(switch) = state.switches.get(switch_id) {
+                    graph.entry(switch.name.clone()).or_default().insert(vm.name.clone());
+                }
+            }
+        }
+
+        graph
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, call: Call) -> Result<MutexGuard<'_, FakeState>> {
+        let operation = call.operation();
+        let mut state = self.lock();
+        state.calls.push(call);
+        if state.fail_on_calls.contains(&state.calls.len()) {
+            let output = CommandOutput { exit_code: 1, stdout: String::new(), stderr: format!("Injected failure on call {}", state.calls.len()) };
+            return Err(BackendError::CommandFailed { operation, target: None, output });
+        }
+
+        Ok(state)
+    }
+}
+
+impl Backend for FakeBackend {
+    fn get_vms(&self) -> Result<Vec<Vm>> {
+        let state = self.record(Call::GetVms)?;
+        let vms = state.vms.values()
+            .map(|vm| Vm {
+                id: vm.id,
+                name: vm.name.clone(),
+                state: if vm.running { "Running" } else { "Off" }.to_owned(),
+                uptime_secs: 0,
+            })
+            .collect();
+
+        Ok(vms)
+    }
+
+    fn import_vm_inplace_new_id(&self, path: &Path, rename_action: Option<RenameAction>) -> Result<ImportedVm> {
+        let mut state = self.record(Call::ImportVm(path.to_owned()))?;
+
+        let (vmcx_path, contents, vmcx) = read_vmcx_file(path, Operation::ImportVm)?;
+
+        let id = state.new_id();
+        let name = vmcx.name.unwrap_or_else(|| path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default());
+        let name = match rename_action {
+            None => name,
+            Some(RenameAction::NewName(n)) => n,
+            Some(RenameAction::AddPrefix(p)) => format!("{}_{}", p, name),
+        };
+
+        let mut adapters = BTreeMap::new();
+        let mut adapter_status = HashMap::new();
+        for (adapter_name, switch_name) in vmcx.adapters {
+            let adapter_id = format!("{}\\{}", id, adapter_name);
+            let switch_id = state.switches.values().find(|s| s.name == switch_name).map(|s| s.id);
+            let is_missing = !switch_name.is_empty() && switch_id.is_none();
+            adapters.insert(adapter_id.clone(), FakeAdapter { name: adapter_name.clone(), switch_id });
+            adapter_status.insert(adapter_id, SwitchStatus { name: switch_name, is_missing, adapter_name });
+        }
+
+        fs::remove_file(&vmcx_path)
+            .and_then(|_| fs::write(vmcx_path.with_file_name(format!("{}.vmcx", id.to_hyphenated())), contents))
+            .map_err(|e| BackendError::HostFailed { operation: Operation::ImportVm, msg: format!("Failed to replace '{}': {}", vmcx_path.display(), e) })?;
+
+        state.vms.insert(id, FakeVm { id, name: name.clone(), path: path.to_owned(), running: false, adapters });
+        Ok(ImportedVm { id, name, adapter_status })
+    }
+
+    fn start_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let mut state = self.record(Call::StartVm(*vm_id))?;
+        Ok(state.vms.get_mut(vm_id).map(|vm| vm.running = true).is_some())
+    }
+
+    fn stop_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let mut state = self.record(Call::StopVm(*vm_id))?;
+        Ok(state.vms.get_mut(vm_id).map(|vm| vm.running = false).is_some())
+    }
+
+    fn delete_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let mut state = self.record(Call::DeleteVm(*vm_id))?;
+        Ok(state.vms.remove(vm_id).is_some())
+    }
+
+    fn create_switch(&self, name: &str, switch_type: &SwitchType<&str>, notes: &str) -> Result<Uuid> {
+        let mut state = self.record(Call::CreateSwitch(name.to_owned()))?;
+        if name.is_empty() {
+            return Err(BackendError::InvalidArgument { operation: Operation::CreateSwitch, msg: "Empty string is not a legal switch name".to_owned() });
+        }
+
+        let (switch_type, net_adapter_name, host_ip) = match switch_type {
+            SwitchType::Private => (SwitchKind::Private, None, None),
+            SwitchType::Internal(host_ip) => (SwitchKind::Internal, None, host_ip.map(|ip| ip.to_owned())),
+            SwitchType::External(adapter_name) => (SwitchKind::External, Some(adapter_name.unwrap_or(DEFAULT_UPLINK_NAME).to_owned()), None),
+        };
+
+        let id = state.new_id();
+        state.switches.insert(id, FakeSwitch { id, name: name.to_owned(), switch_type, net_adapter_name, host_ip, notes: notes.to_owned() });
+        Ok(id)
+    }
+
+    fn delete_switch(&self, switch_id: &str) -> Result<bool> {
+        let mut state = self.record(Call::DeleteSwitch(switch_id.to_owned()))?;
+        let switch_id = match Uuid::parse_str(switch_id) {
+            Ok(id) => id,
+            Err(_) => return Ok(false),
+        };
+
+        if state.switches.remove(&switch_id).is_none() {
+            return Ok(false);
+        }
+
+        for vm in state.vms.values_mut() {
+            for adapter in vm.adapters.values_mut() {
+                if adapter.switch_id == Some(switch_id) {
+                    adapter.switch_id = None;
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn connect_adapter(&self, vm_id: &VmId, adapter_id: &str, switch_id: &str) -> Result<()> {
+        let mut state = self.record(Call::ConnectAdapter(*vm_id, adapter_id.to_owned(), switch_id.to_owned()))?;
+        let switch_id = Uuid::parse_str(switch_id)
+            .ok()
+            .filter(|id| state.switches.contains_key(id))
+            .ok_or_else(|| BackendError::SwitchNotFound { operation: Operation::ConnectAdapter, switch_id: switch_id.to_owned() })?;
+        let vm = state.vms.get_mut(vm_id)
+            .ok_or(BackendError::VmNotFound { operation: Operation::ConnectAdapter, vm_id: *vm_id })?;
+        let adapter = vm.adapters.get_mut(adapter_id)
+            .ok_or_else(|| BackendError::AdapterNotFound { operation: Operation::ConnectAdapter, vm_id: *vm_id, adapter_id: adapter_id.to_owned() })?;
+        adapter.switch_id = Some(switch_id);
+        Ok(())
+    }
+
+    fn export_vm(&self, vm_id: &VmId, dest_path: &Path) -> Result<()> {
+        let state = self.record(Call::ExportVm(*vm_id, dest_path.to_owned()))?;
+        let vm = state.vms.get(vm_id)
+            .ok_or(BackendError::VmNotFound { operation: Operation::ExportVm, vm_id: *vm_id })?;
+
+        let adapters = vm.adapters.values()
+            .map(|a| {
+                let switch_name = a.switch_id.and_then(|id| state.switches.get(&id)).map(|s| s.name.clone()).unwrap_or_default();
+                (a.name.clone(), switch_name)
+            })
+            .collect();
+        let vmcx = FakeVmcx { name: Some(vm.name.clone()), adapters };
+
+        let vm_config_dir = dest_path.join(&vm.name).join("Virtual Machines");
+        fs::create_dir_all(&vm_config_dir)
+            .and_then(|_| fs::write(vm_config_dir.join(format!("{}.vmcx", vm.id.to_hyphenated())), serde_json::to_string(&vmcx)?))
+            .map_err(|e| BackendError::HostFailed { operation: Operation::ExportVm, msg: format!("Failed to export VM {} to '{}': {}", vm_id, dest_path.display(), e) })?;
+
+        Ok(())
+    }
+
+    fn get_vm_adapters(&self, vm_id: &VmId) -> Result<Vec<Adapter>> {
+        let state = self.record(Call::GetVmAdapters(*vm_id))?;
+        let vm = state.vms.get(vm_id)
+            .ok_or(BackendError::VmNotFound { operation: Operation::GetVmAdapters, vm_id: *vm_id })?;
+
+        let adapters = vm.adapters.iter()
+            .map(|(id, a)| Adapter {
+                id: id.clone(),
+                name: a.name.clone(),
+                switch_name: a.switch_id.and_then(|id| state.switches.get(&id)).map(|s| s.name.clone()),
+                switch_id: a.switch_id,
+            })
+            .collect();
+
+        Ok(adapters)
+    }
+
+    fn get_exported_vm_adapters(&self, path: &Path) -> Result<Vec<SwitchStatus>> {
+        let state = self.record(Call::GetExportedVmAdapters(path.to_owned()))?;
+        let (_, _, vmcx) = read_vmcx_file(path, Operation::GetExportedVmAdapters)?;
+        let adapters = vmcx.adapters.into_iter()
+            .map(|(adapter_name, switch_name)| {
+                let is_missing = !switch_name.is_empty() && !state.switches.values().any(|s| s.name == switch_name);
+                SwitchStatus { name: switch_name, is_missing, adapter_name }
+            })
+            .collect();
+
+        Ok(adapters)
+    }
+
+    fn get_switches(&self) -> Result<Vec<Switch>> {
+        let state = self.record(Call::GetSwitches)?;
+        let switches = state.switches.values()
+            .map(|s| Switch { id: s.id, name: s.name.clone(), switch_type: s.switch_type, net_adapter_name: s.net_adapter_name.clone(), notes: s.notes.clone() })
+            .collect();
+
+        Ok(switches)
+    }
+
+    fn get_switch_vm_ids(&self, switch_id: &str) -> Result<Vec<VmId>> {
+        let state = self.record(Call::GetSwitchVmIds(switch_id.to_owned()))?;
+        let switch_id = Uuid::parse_str(switch_id).ok();
+        let vm_ids = state.vms.values()
+            .filter(|vm| vm.adapters.values().any(|a| a.switch_id.is_some() && a.switch_id == switch_id))
+            .map(|vm| vm.id)
+            .collect();
+
+        Ok(vm_ids)
+    }
+
+    fn get_vm_link_local_address(&self, vm_id: &VmId) -> Result<Option<String>> {
+        let state = self.record(Call::GetVmLinkLocalAddress(*vm_id))?;
+        let vm = state.vms.get(vm_id)
+            .ok_or(BackendError::VmNotFound { operation: Operation::GetVmLinkLocalAddress, vm_id: *vm_id })?;
+
+        // Only running VMs have addresses. Derive one from the ID so it's unique and stable.
+        let address = if vm.running {
+            let bytes = vm.id.as_bytes();
+            Some(format!("fe80::{:x}", u16::from_be_bytes([bytes[14], bytes[15]])))
+        } else {
+            None
+        };
+
+        Ok(address)
+    }
+
+    fn trust_hosts(&self, hosts: &[String]) -> Result<()> {
+        let mut state = self.record(Call::TrustHosts(hosts.to_vec()))?;
+        for host in hosts {
+            if !state.trusted_hosts.contains(host) {
+                state.trusted_hosts.push(host.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_provisioner(&self, script_path: &Path, targets: &[ProvisionTarget]) -> Result<String> {
+        let _state = self.record(Call::RunProvisioner(script_path.to_owned(), targets.iter().map(|t| t.id).collect()))?;
+        if !script_path.is_file() {
+            return Err(BackendError::InvalidArgument { operation: Operation::RunProvisioner, msg: format!("Provisioner '{}' does not exist", script_path.display()) });
+        }
+
+        Ok(String::new())
+    }
+}
+
+/// Returns the path of the VM's `.vmcx` file along with its contents, both as they are and parsed
+fn read_vmcx_file(vm_dir: &Path, operation: Operation) -> Result<(PathBuf, String, FakeVmcx)> {
+    let vmcx_path = find_vmcx_file(vm_dir, operation)?;
+    let contents = fs::read_to_string(&vmcx_path)
+        .map_err(|e| BackendError::HostFailed { operation, msg: format!("Failed to read '{}': {}", vmcx_path.display(), e) })?;
+    let vmcx: FakeVmcx = if contents.trim().is_empty() {
+        FakeVmcx::default()
+    } else {
+        serde_json::from_str(&contents)
+            .map_err(|e| BackendError::InvalidArgument { operation, msg: format!("Failed to parse '{}': {}", vmcx_path.display(), e) })?
+    };
+    Ok((vmcx_path, contents, vmcx))
+}
+
+fn find_vmcx_file(vm_dir: &Path, operation: Operation) -> Result<PathBuf> {
+    let vm_config_dir = vm_dir.join("Virtual Machines");
+    let read_failed = |e: std::io::Error| BackendError::HostFailed { operation, msg: format!("Failed to read '{}': {}", vm_config_dir.display(), e) };
+    let entries = fs::read_dir(&vm_config_dir).map_err(read_failed)?;
+    for entry in entries {
+        let path = entry.map_err(read_failed)?.path();
+        if path.extension().map(|e| e.to_string_lossy().to_lowercase() == "vmcx").unwrap_or(false) {
+            return Ok(path);
+        }
+    }
+
+    Err(BackendError::InvalidArgument { operation, msg: format!("No .vmcx file found in '{}'", vm_config_dir.display()) })
+}
@@ -1,29 +1,35 @@
-use powershell_rs::{PsCommand, Stdio, PsProcess, Stdout};
-use failure::Fail;
-use serde_derive::Deserialize;
+use crate::backend::{Backend, BackendError, CommandOutput, Operation, Result, Vm, VmId, ImportedVm, Adapter, Switch, SwitchStatus, SwitchType, RenameAction, ProvisionTarget};
+use crate::manifest::parse_cidr;
+use crate::ps_script::PsScript;
+use crate::ps_session::PsSession;
+use log::trace;
 use uuid::Uuid;
-use std::fmt;
 use std::path::Path;
-use std::collections::HashMap;
-use std::io::Read;
+use std::sync::Mutex;
 
-pub struct Hyperv;
+pub struct Hyperv {
+    /// Idle PowerShell sessions. Each call takes one (or starts a new one if there's none)
+    /// and puts it back when it's done, so calls made in parallel each get their own.
+    sessions: Mutex<Vec<PsSession>>,
+}
 
-pub type Result<T> = std::result::Result<T, HypervError>;
+impl Backend for Hyperv {
+    fn get_vms(&self) -> Result<Vec<Vm>> {
+        let script = PsScript::new().code(r#"
+            $vms = @(Get-VM | ForEach-Object {
+                @{ Id = $_.Id; Name = $_.Name; State = $_.State.ToString(); UptimeSeconds = [int64]$_.Uptime.TotalSeconds }
+            });
+            ConvertTo-Json -InputObject $vms"#);
 
-impl Hyperv {
-    pub fn get_vms() -> Result<Vec<Vm>> {
-        let process = Self::spawn(r#"$ErrorActionPreference = "Stop";get-vm|select-object -property Id,Name |convertto-json"#)?;
-        let stdout = process.stdout().ok_or_else(|| HypervError::new("Could not access stdout of powershell process"))?;
+        let stdout = self.run(Operation::GetVms, None, &script)?;
 
-        let vms: Vec<Vm> = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let vms: Vec<Vm> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetVms, msg: e.to_string() })?;
 
         Ok(vms)
     }
 
-    pub fn import_vm_inplace_new_id<P: AsRef<Path>, R: Into<Option<RenameAction>>>(path: P, rename_action: R) -> Result<ImportedVm> {
-        let rename_action = rename_action.into();
+    fn import_vm_inplace_new_id(&self, path: &Path, rename_action: Option<RenameAction>) -> Result<ImportedVm> {
         let (prefix, new_name) = match rename_action {
             None => ("".to_owned(), "".to_owned()),
             Some(RenameAction::NewName(n)) => ("".to_owned(), n),
@@ -31,322 +37,501 @@ impl Hyperv {
         };
 
         // TODO: add powershell statements in the command below to delete old config files and folders
-        let path = Self::validate_dir_path(path.as_ref())?;
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $vm_root_path = "{}";
+        let dir_path = Self::validate_dir_path(Operation::ImportVm, path)?;
+        let script = PsScript::new()
+            .set("vm_root_path", dir_path)
+            .set("prefix", &prefix)
+            .set("new_name", &new_name)
+            .code(r#"
             $virtual_machines_path = Join-Path $vm_root_path "Virtual Machines";
             $virtual_disks_path = Join-Path $vm_root_path "Virtual Hard Disks";
-            $config_file_path = Get-ChildItem -Path $virtual_machines_path -Filter *.vmcx -ErrorAction SilentlyContinue | Select-Object -First 1;
+            $config_file_path = Get-ChildItem -LiteralPath $virtual_machines_path -Filter *.vmcx -ErrorAction SilentlyContinue | Select-Object -First 1;
             $report = Compare-Vm -Path $config_file_path.FullName -VirtualMachinePath $vm_root_path -VhdDestinationPath $virtual_disks_path -GenerateNewId -Copy;
 
-            if ($null -eq $report) {{
+            if ($null -eq $report) {
                 Write-Host "Failed to generate compat report";
                 exit 1;
-            }}
+            }
 
             $MissingSwitchMsgId = 33012;
-            $adapter_status = @{{}};
+            $adapter_status = @{};
             foreach ($incompatibilty in $report.Incompatibilities)
-            {{
+            {
                 if ($incompatibilty.MessageId -eq $MissingSwitchMsgId)
-                {{
+                {
                     $switch_name = $incompatibilty.Message.TrimStart("Could not find Ethernet switch '").TrimEnd("'.");
-                    $adapter_status[$incompatibilty.Source.Id] = @{{ Name =  $switch_name; IsMissing = $true }};
+                    $adapter_status[$incompatibilty.Source.Id] = @{ Name =  $switch_name; IsMissing = $true; AdapterName = $incompatibilty.Source.Name };
                     $incompatibilty.Source |Disconnect-VMNetworkAdapter;
-                }}
-            }}
+                }
+            }
 
             $report = Compare-Vm -CompatibilityReport $report;
             if ($report.Incompatibilities.Length -gt 0) 
-            {{
+            {
                 Write-Host "Failed to resolve all incompatibilities:";
                 Write-Host ($report.Incompatibilities | Format-Table | Out-String);
                 exit 2;
-            }}
+            }
 
             $vm = Import-VM -CompatibilityReport $report;
 
-            if ($null -eq $vm) {{
+            if ($null -eq $vm) {
                 Write-Host "Failed to import VM";
                 exit 3;
-            }}
-
-            $prefix = "{}";
-            $new_name = "{}";
+            }
 
-            if (($null -ne $prefix) -and ($prefix -ne "")) {{
+            if (($null -ne $prefix) -and ($prefix -ne "")) {
                 $new_name = $prefix + "_" + $vm.Name;
-            }}
+            }
 
-            if (($null -ne $new_name) -and ($new_name -ne "")) {{
+            if (($null -ne $new_name) -and ($new_name -ne "")) {
                 Rename-VM -VM $vm -NewName $new_name;
-            }}
-
-            foreach ($adapter in $vm.NetworkAdapters) {{
-                $switch_name = $adapter.SwitchName;
-                if (($null -ne $switch_name) -and ("" -ne $switch_name)) {{
-                    $adapter_status[$adapter.Id] =  @{{ Name =  $switch_name; IsMissing = $false }};
-                }}
-            }}
+            }
+
+            foreach ($adapter in $vm.NetworkAdapters) {
+                if (-not $adapter_status.ContainsKey($adapter.Id)) {
+                    $switch_name = $adapter.SwitchName;
+                    if ($null -eq $switch_name) {
+                        $switch_name = "";
+                    }
+                    $adapter_status[$adapter.Id] =  @{ Name =  $switch_name; IsMissing = $false; AdapterName = $adapter.Name };
+                }
+            }
 
             $base_name_to_delete = $config_file_path.BaseName;
             $filter_to_delete = "*$base_name_to_delete*";
-            Get-ChildItem -Path $virtual_machines_path -Filter $filter_to_delete | Remove-Item;
+            Get-ChildItem -LiteralPath $virtual_machines_path -Filter $filter_to_delete | Remove-Item;
 
-            $output = @{{}};
+            $output = @{};
             $output.VmId = $vm.Id;
             $output.VmName = $vm.Name;
             $output.AdapterStatus = $adapter_status;
 
-            $output | ConvertTo-Json"#,
-        path,
-        prefix,
-        new_name);
+            $output | ConvertTo-Json"#);
 
-        let stdout = Self::spawn_and_wait(&command)?;
+        let stdout = self.run(Operation::ImportVm, None, &script).map_err(|e| match e {
+            BackendError::CommandFailed { output, .. } => match output.exit_code {
+                1 => BackendError::NoCompatibilityReport { path: path.to_owned(), output },
+                2 => BackendError::UnresolvedIncompatibilities { path: path.to_owned(), output },
+                3 => BackendError::ImportFailed { path: path.to_owned(), output },
+                _ => BackendError::CommandFailed { operation: Operation::ImportVm, target: Some(path.display().to_string()), output },
+            },
+            e => e,
+        })?;
 
-        let vm: ImportedVm = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let vm: ImportedVm = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::ImportVm, msg: e.to_string() })?;
 
         Ok(vm)
     }
 
-    pub fn start_vm(vm_id: &VmId) -> Result<bool> {
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $vm = Get-Vm -Id {0} -ErrorAction SilentlyContinue;
-            if ($null -ne $vm) {{
+    fn start_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -ne $vm) {
                 Start-VM -VM $vm -WarningAction SilentlyContinue;
                 $true |convertto-json
-            }} else {{
+            } else {
                 $false |convertto-json
-            }}"#,
-        vm_id);
+            }"#);
 
-        let stdout = Self::spawn_and_wait(&command)?;
+        let stdout = self.run(Operation::StartVm, Some(vm_id.to_string()), &script)?;
 
-        let vm_found_and_started: bool = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let vm_found_and_started: bool = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::StartVm, msg: e.to_string() })?;
         
         Ok(vm_found_and_started)
     }
 
-    pub fn stop_vm(vm_id: &VmId) -> Result<bool> {
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $vm = Get-Vm -Id {0} -ErrorAction SilentlyContinue;
-            if ($null -ne $vm) {{
+    fn stop_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -ne $vm) {
                 Stop-VM -VM $vm -Force -WarningAction SilentlyContinue;
                 $true |convertto-json
-            }} else {{
+            } else {
                 $false |convertto-json
-            }}"#,
-        vm_id);
+            }"#);
 
-        let stdout = Self::spawn_and_wait(&command)?;
+        let stdout = self.run(Operation::StopVm, Some(vm_id.to_string()), &script)?;
 
-        let vm_found_and_stopped: bool = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let vm_found_and_stopped: bool = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::StopVm, msg: e.to_string() })?;
         
         Ok(vm_found_and_stopped)
     }
 
-    pub fn delete_vm(vm_id: &VmId) -> Result<bool> {
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $vm = Get-Vm -Id {0} -ErrorAction SilentlyContinue;
-            if ($null -ne $vm) {{
+    fn delete_vm(&self, vm_id: &VmId) -> Result<bool> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -ne $vm) {
                 Remove-VM -VM $vm -Force -WarningAction SilentlyContinue;
                 $true |convertto-json
-            }} else {{
+            } else {
                 $false |convertto-json
-            }}"#,
-        vm_id);
+            }"#);
 
-        let stdout = Self::spawn_and_wait(&command)?;
+        let stdout = self.run(Operation::DeleteVm, Some(vm_id.to_string()), &script)?;
 
-        let vm_found_and_deleted: bool = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let vm_found_and_deleted: bool = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::DeleteVm, msg: e.to_string() })?;
         
         Ok(vm_found_and_deleted)
     }
 
-    pub fn create_switch<S: AsRef<str>>(name: S, switch_type: &SwitchType<S>) -> Result<Uuid> {
-        let name = name.as_ref();
+    fn create_switch(&self, name: &str, switch_type: &SwitchType<&str>, notes: &str) -> Result<Uuid> {
         if name.is_empty() {
-            return Err(HypervError::new("Empty string is not a legal switch name"));
+            return Err(BackendError::InvalidArgument { operation: Operation::CreateSwitch, msg: "Empty string is not a legal switch name".to_owned() });
         }
 
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $switch = New-VmSwitch -Name "{}" {};
-            $switch.Id.ToString()"#,
-        name,
-        match switch_type {
-            SwitchType::Private => "-SwitchType Private".to_owned(),
-            SwitchType::Internal => "-SwitchType Internal".to_owned(),
-            SwitchType::External(adapter_name) => format!("-SwitchType -NetAdapterName \"{}\"", adapter_name.as_ref()),
-        });
-
-        let mut stdout = Self::spawn_and_wait(&command)?;
+        let script = PsScript::new()
+            .set("name", name)
+            .set("notes", notes);
+        let script = match switch_type {
+            SwitchType::Private => script.code("$switch = New-VmSwitch -Name $name -Notes $notes -SwitchType Private;"),
+            SwitchType::Internal(None) => script.code("$switch = New-VmSwitch -Name $name -Notes $notes -SwitchType Internal;"),
+            SwitchType::Internal(Some(host_ip)) => {
+                let (address, prefix_length) = parse_cidr(host_ip)
+                    .ok_or_else(|| BackendError::InvalidArgument { operation: Operation::CreateSwitch, msg: format!("'{}' is not an address in CIDR notation", host_ip) })?;
+                // The host's adapter on the switch is found by its device ID since its name can be anything
+                script
+                    .set("address", &address.to_string())
+                    .set("prefix_length", &prefix_length.to_string())
+                    .code(r#"
+                    $switch = New-VmSwitch -Name $name -Notes $notes -SwitchType Internal;
+                    try {
+                        $host_adapter = Get-VMNetworkAdapter -ManagementOS | Where-Object { $_.SwitchId -eq $switch.Id } | Select-Object -First 1;
+                        $net_adapter = Get-NetAdapter | Where-Object { $_.DeviceID -eq $host_adapter.DeviceId } | Select-Object -First 1;
+                        New-NetIPAddress -InterfaceIndex $net_adapter.ifIndex -IPAddress $address -PrefixLength ([int]$prefix_length) | Out-Null;
+                    } catch {
+                        Remove-VMSwitch -VMSwitch $switch -Force;
+                        throw
+                    }"#)
+            }
+            SwitchType::External(Some(adapter_name)) => {
+                script
+                    .set("adapter_name", adapter_name)
+                    .code("$switch = New-VmSwitch -Name $name -Notes $notes -NetAdapterName $adapter_name;")
+            }
+            // The physical adapter the host reaches the outside world through
+            SwitchType::External(None) => script.code(r#"
+                $route = Get-NetRoute -DestinationPrefix "0.0.0.0/0" -ErrorAction SilentlyContinue | Sort-Object { $_.RouteMetric + $_.InterfaceMetric } | Select-Object -First 1;
+                if ($null -eq $route) {
+                    throw "The host has no default route to pick an adapter for the external switch from. Name one in lab.toml";
+                }
+                $uplink = Get-NetAdapter -Physical | Where-Object { $_.ifIndex -eq $route.ifIndex } | Select-Object -First 1;
+                if ($null -eq $uplink) {
+                    throw "The host's default route goes through '$($route.InterfaceAlias)', which is not a physical adapter an external switch can be bound to. Name one in lab.toml";
+                }
+                $switch = New-VmSwitch -Name $name -Notes $notes -NetAdapterName $uplink.Name;"#),
+        };
+        let script = script.code("$switch.Id.ToString()");
 
-        let mut uuid = String::new();
-        stdout.read_to_string(&mut uuid)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
-        let uuid = uuid.trim();
+        let stdout = self.run(Operation::CreateSwitch, Some(name.to_owned()), &script)?;
 
+        let uuid = stdout.trim();
         let switch_id = Uuid::parse_str(uuid)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+            .map_err(|e| BackendError::BadOutput { operation: Operation::CreateSwitch, msg: e.to_string() })?;
 
         Ok(switch_id)
     }
 
-    pub fn delete_switch(switch_id: &str) -> Result<bool> {
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $switch = Get-VmSwitch -Id {0} -ErrorAction SilentlyContinue;
-            if ($null -ne $switch) {{
+    fn delete_switch(&self, switch_id: &str) -> Result<bool> {
+        let script = PsScript::new()
+            .set("switch_id", switch_id)
+            .code(r#"
+            $switch = Get-VmSwitch -Id $switch_id -ErrorAction SilentlyContinue;
+            if ($null -ne $switch) {
                 Remove-VMSwitch -VMSwitch $switch -Force -WarningAction SilentlyContinue;
                 $true |convertto-json
-            }} else {{
+            } else {
                 $false |convertto-json
-            }}"#,
-        switch_id);
+            }"#);
 
-        let stdout = Self::spawn_and_wait(&command)?;
+        let stdout = self.run(Operation::DeleteSwitch, Some(switch_id.to_owned()), &script)?;
 
-        let switch_found_and_deleted: bool = serde_json::from_reader(stdout)
-            .map_err(|e| HypervError::new(format!("Failed to parse powershell output: {}", e)))?;
+        let switch_found_and_deleted: bool = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::DeleteSwitch, msg: e.to_string() })?;
 
         Ok(switch_found_and_deleted)
     }
 
-    pub fn connect_adapter(vm_id: &VmId, adapter_id: &str, switch_id: &str) -> Result<()> {
-        let command = &format!(
-            r#"$ErrorActionPreference = "Stop";
-            $vm = Get-Vm -Id {0};
-            if ($null -eq $vm) {{
-                Write-Host "Failed to get vm with Id {0}";
+    fn connect_adapter(&self, vm_id: &VmId, adapter_id: &str, switch_id: &str) -> Result<()> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .set("adapter_id", adapter_id)
+            .set("switch_id", switch_id)
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -eq $vm) {
+                Write-Host "Failed to get vm with Id $vm_id";
                 exit 1;
-            }}
-            $adapter = $vm.NetworkAdapters | Where-Object {{ $_.Id -eq "{1}"}} | Select-Object -First 1;
-            if ($null -eq $adapter) {{
-                Write-Host "Failed to get vm adapter with Id {1}";
+            }
+            $adapter = $vm.NetworkAdapters | Where-Object { $_.Id -eq $adapter_id } | Select-Object -First 1;
+            if ($null -eq $adapter) {
+                Write-Host "Failed to get vm adapter with Id $adapter_id";
                 exit 2;
-            }}
-            $switch = Get-VmSwitch -Id {2};
-            if ($null -eq $switch) {{
-                Write-Host "Failed to get switch with Id {2}";
+            }
+            $switch = Get-VmSwitch -Id $switch_id -ErrorAction SilentlyContinue;
+            if ($null -eq $switch) {
+                Write-Host "Failed to get switch with Id $switch_id";
                 exit 3;
-            }}
+            }
 
-            Connect-VMNetworkAdapter -VMNetworkAdapter $adapter -VMSwitch $switch"#,
-        vm_id,
-        adapter_id,
-        switch_id);
+            Connect-VMNetworkAdapter -VMNetworkAdapter $adapter -VMSwitch $switch"#);
 
-        Self::spawn_and_wait(&command)?;
+        self.run(Operation::ConnectAdapter, Some(vm_id.to_string()), &script).map_err(|e| match e {
+            BackendError::CommandFailed { output, .. } if output.exit_code == 1 => BackendError::VmNotFound { operation: Operation::ConnectAdapter, vm_id: *vm_id },
+            BackendError::CommandFailed { output, .. } if output.exit_code == 2 => BackendError::AdapterNotFound { operation: Operation::ConnectAdapter, vm_id: *vm_id, adapter_id: adapter_id.to_owned() },
+            BackendError::CommandFailed { output, .. } if output.exit_code == 3 => BackendError::SwitchNotFound { operation: Operation::ConnectAdapter, switch_id: switch_id.to_owned() },
+            e => e,
+        })?;
         Ok(())
     }
 
-    fn validate_dir_path(path: &Path) -> Result<&str> {
-        if !path.is_dir() {
-            Err(HypervError::new("Path does not point to a valid directory"))
-        } else {
-            let path = path.to_str().ok_or_else(|| HypervError { msg: "Bad path".to_owned() })?;
-            Ok(path)
-        }
+    fn export_vm(&self, vm_id: &VmId, dest_path: &Path) -> Result<()> {
+        let dest_path = Self::validate_dir_path(Operation::ExportVm, dest_path)?;
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .set("dest_path", dest_path)
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -eq $vm) {
+                Write-Host "Failed to get vm with Id $vm_id";
+                exit 1;
+            }
+
+            Export-VM -VM $vm -Path $dest_path"#);
+
+        self.run(Operation::ExportVm, Some(vm_id.to_string()), &script)
+            .map_err(|e| Self::map_vm_not_found(e, Operation::ExportVm, vm_id))?;
+        Ok(())
     }
 
+    fn get_vm_adapters(&self, vm_id: &VmId) -> Result<Vec<Adapter>> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -eq $vm) {
+                Write-Host "Failed to get vm with Id $vm_id";
+                exit 1;
+            }
+
+            $adapters = @($vm.NetworkAdapters | ForEach-Object {
+                $switch_id = $null;
+                if ($null -ne $_.SwitchId) {
+                    $switch_id = $_.SwitchId.ToString();
+                }
+                @{ Id = $_.Id; Name = $_.Name; SwitchName = $_.SwitchName; SwitchId = $switch_id }
+            });
+            ConvertTo-Json -InputObject $adapters"#);
+
+        let stdout = self.run(Operation::GetVmAdapters, Some(vm_id.to_string()), &script)
+            .map_err(|e| Self::map_vm_not_found(e, Operation::GetVmAdapters, vm_id))?;
+
+        let adapters: Vec<Adapter> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetVmAdapters, msg: e.to_string() })?;
 
-    fn spawn(command: &str) -> Result<PsProcess> {
-        PsCommand::new(command)
-            .stdout(Stdio::piped()) // TODO: use a tee like mechanism to pipe this to the logger as well when high log level is set
-            .spawn()
-            .map_err(|e| HypervError::new(format!("Failed to spawn PowerShell process: {}", e)))
+        Ok(adapters)
     }
 
-    fn spawn_and_wait(command: &str) -> Result<Stdout> {
-        let mut process = Self::spawn(command)?;
-        let status = process.wait()
-            .map_err(|e| HypervError::new(format!("Failed while waiting for PowerShell process: {}", e)))?;
-
-        if !status.success() {
-            let exit_code_str = status.code().map(|c| c.to_string()).unwrap_or_else(|| "<none>".to_owned());
-            let output = process.wait_with_output() //.map(|c| c.to_string()).unwrap_or_else(|| "<none>".to_owned());
-                .map_err(|e| HypervError::new(format!("Failed while waiting for PowerShell process: {}", e)))?;
-            let stdout = to_string_truncated(&output.stdout, 1000);
-            let stderr = to_string_truncated(&output.stderr, 1000);
-            fn handle_blank(s: String) -> String { if !s.is_empty() { s } else { "<empty>".to_owned() } }
-            Err(HypervError { msg: format!("Powershell returned failure exit code: {}.\nStdout: {} \nStderr: {}", exit_code_str, handle_blank(stdout), handle_blank(stderr)) })
-        } else {
-            let output = process.stdout()
-                .ok_or_else(|| HypervError::new("Failed obtain stdout of PowerShell process".to_owned()))?;
-            Ok(output)
-        }
+    fn get_exported_vm_adapters(&self, path: &Path) -> Result<Vec<SwitchStatus>> {
+        let dir_path = Self::validate_dir_path(Operation::GetExportedVmAdapters, path)?;
+        let script = PsScript::new()
+            .set("vm_root_path", dir_path)
+            .code(r#"
+            $virtual_machines_path = Join-Path $vm_root_path "Virtual Machines";
+            $config_file_path = Get-ChildItem -LiteralPath $virtual_machines_path -Filter *.vmcx -ErrorAction SilentlyContinue | Select-Object -First 1;
+            $report = Compare-Vm -Path $config_file_path.FullName;
+
+            if ($null -eq $report) {
+                Write-Host "Failed to generate compat report";
+                exit 1;
+            }
+
+            # Same as import: adapters whose switches don't exist on the host only show up in the incompatibilities
+            $MissingSwitchMsgId = 33012;
+            $adapter_status = @{};
+            foreach ($incompatibilty in $report.Incompatibilities)
+            {
+                if ($incompatibilty.MessageId -eq $MissingSwitchMsgId)
+                {
+                    $switch_name = $incompatibilty.Message.TrimStart("Could not find Ethernet switch '").TrimEnd("'.");
+                    $adapter_status[$incompatibilty.Source.Id] = @{ Name =  $switch_name; IsMissing = $true; AdapterName = $incompatibilty.Source.Name };
+                }
+            }
+
+            foreach ($adapter in $report.VM.NetworkAdapters) {
+                if (-not $adapter_status.ContainsKey($adapter.Id)) {
+                    $switch_name = $adapter.SwitchName;
+                    if ($null -eq $switch_name) {
+                        $switch_name = "";
+                    }
+                    $adapter_status[$adapter.Id] =  @{ Name =  $switch_name; IsMissing = $false; AdapterName = $adapter.Name };
+                }
+            }
+
+            ConvertTo-Json -InputObject @($adapter_status.Values)"#);
+
+        let stdout = self.run(Operation::GetExportedVmAdapters, Some(dir_path.to_owned()), &script)?;
+        let adapters: Vec<SwitchStatus> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetExportedVmAdapters, msg: e.to_string() })?;
+
+        Ok(adapters)
     }
-}
 
-#[derive(Debug, Deserialize)]
-pub struct ImportedVm {
-    #[serde(rename = "VmId")]
-    pub id: VmId,
-    #[serde(rename = "VmName")]
-    pub name: String,
-    #[serde(rename = "AdapterStatus")]
-    pub adapter_status: HashMap<String, SwitchStatus>,
-}
+    fn get_switches(&self) -> Result<Vec<Switch>> {
+        let script = PsScript::new().code(r#"
+            $switches = @(Get-VMSwitch | ForEach-Object {
+                $net_adapter_name = $null;
+                if ($_.NetAdapterInterfaceDescription) {
+                    $net_adapter_name = (Get-NetAdapter -InterfaceDescription $_.NetAdapterInterfaceDescription).Name;
+                }
+                @{ Id = $_.Id.ToString(); Name = $_.Name; SwitchType = $_.SwitchType.ToString().ToLower(); NetAdapterName = $net_adapter_name; Notes = $_.Notes }
+            });
+            ConvertTo-Json -InputObject $switches"#);
 
-#[derive(Debug, Deserialize)]
-pub struct SwitchStatus {
-    #[serde(rename = "Name")]
-    pub name: String,
-    #[serde(rename = "IsMissing")]
-    pub is_missing: bool,
-}
+        let stdout = self.run(Operation::GetSwitches, None, &script)?;
 
-#[derive(Debug, Deserialize)]
-pub struct Vm {
-    #[serde(rename = "Id")]
-    pub id: VmId,
-    #[serde(rename = "Name")]
-    pub name: String,
-}
+        let switches: Vec<Switch> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetSwitches, msg: e.to_string() })?;
 
-// TODO: should this be a newtype?
-pub type VmId = Uuid;
+        Ok(switches)
+    }
 
-pub enum SwitchType<S: AsRef<str>> {
-    Private,
-    Internal,
-    External(S),
-}
+    fn get_switch_vm_ids(&self, switch_id: &str) -> Result<Vec<VmId>> {
+        let script = PsScript::new()
+            .set("switch_id", switch_id)
+            .code(r#"
+            $vm_ids = @(Get-VM | Get-VMNetworkAdapter |
+                Where-Object { $_.SwitchId -eq $switch_id } |
+                ForEach-Object { $_.VMId.ToString() } |
+                Select-Object -Unique);
+            ConvertTo-Json -InputObject $vm_ids"#);
 
-pub enum RenameAction {
-    NewName(String), // TODO; can we find a way to use &str here instead of String
-    AddPrefix(String),
-}
+        let stdout = self.run(Operation::GetSwitchVms, Some(switch_id.to_owned()), &script)?;
 
-// TODO: We need to do proper design of error types. Just this one type is not enough
-#[derive(Debug, Fail)]
-pub struct HypervError  {
-    pub msg: String,
-}
+        let vm_ids: Vec<VmId> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetSwitchVms, msg: e.to_string() })?;
 
-impl HypervError {
-    fn new<T: Into<String>>(msg: T) -> Self {
-        Self { msg: msg.into() }
+        Ok(vm_ids)
+    }
+
+    fn get_vm_link_local_address(&self, vm_id: &VmId) -> Result<Option<String>> {
+        let script = PsScript::new()
+            .set("vm_id", &vm_id.to_string())
+            .code(r#"
+            $vm = Get-Vm -Id $vm_id -ErrorAction SilentlyContinue;
+            if ($null -eq $vm) {
+                Write-Host "Failed to get vm with Id $vm_id";
+                exit 1;
+            }
+
+            $address = $vm.NetworkAdapters | ForEach-Object { $_.IPAddresses } | Where-Object { $_ -like "fe80:*" } | Select-Object -First 1;
+            ConvertTo-Json -InputObject $address"#);
+
+        let stdout = self.run(Operation::GetVmLinkLocalAddress, Some(vm_id.to_string()), &script)
+            .map_err(|e| Self::map_vm_not_found(e, Operation::GetVmLinkLocalAddress, vm_id))?;
+
+        let address: Option<String> = serde_json::from_str(&stdout)
+            .map_err(|e| BackendError::BadOutput { operation: Operation::GetVmLinkLocalAddress, msg: e.to_string() })?;
+
+        Ok(address)
+    }
+
+    fn trust_hosts(&self, hosts: &[String]) -> Result<()> {
+        // We talk to the VMs over WinRM which refuses to talk to IPs that aren't in its TrustedHosts setting
+        let script = PsScript::new()
+            .set_array("hosts", hosts)
+            .code(r#"
+            $winrm = Get-Service -Name WinRM -ErrorAction SilentlyContinue;
+            if (($null -eq $winrm) -or ($winrm.Status -ne "Running")) {
+                Write-Host "WinRM service is not running";
+                exit 1;
+            }
+
+            $trusted_hosts = (Get-Item WSMan:\localhost\Client\TrustedHosts).Value;
+            $existing = @();
+            if ($trusted_hosts) {
+                $existing = @($trusted_hosts.Split(",") | ForEach-Object { $_.Trim() });
+            }
+
+            $to_add = @($hosts | Where-Object { ($existing -notcontains $_) -and ($existing -notcontains "*") });
+            if ($to_add.Count -gt 0) {
+                Set-Item WSMan:\localhost\Client\TrustedHosts -Value ($to_add -join ",") -Concatenate -Force;
+            }"#);
+
+        self.run(Operation::TrustHosts, None, &script)?;
+        Ok(())
     }
-}
 
-impl fmt::Display for HypervError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.msg)
+    fn run_provisioner(&self, script_path: &Path, targets: &[ProvisionTarget]) -> Result<String> {
+        if !script_path.is_file() {
+            return Err(BackendError::InvalidArgument { operation: Operation::RunProvisioner, msg: format!("Provisioner '{}' does not exist", script_path.display()) });
+        }
+        let script_path = script_path.to_str()
+            .ok_or_else(|| BackendError::InvalidArgument { operation: Operation::RunProvisioner, msg: "Bad path".to_owned() })?;
+        let targets = serde_json::to_string(targets)
+            .map_err(|e| BackendError::InvalidArgument { operation: Operation::RunProvisioner, msg: format!("Failed to serialize provisioning targets: {}", e) })?;
+        let script = PsScript::new()
+            .set("targets", &targets)
+            .set("script_path", script_path)
+            .code(r#"
+            $vms = @(ConvertFrom-Json -InputObject $targets);
+            & $script_path -Vms $vms"#);
+
+        let stdout = self.run(Operation::RunProvisioner, Some(script_path.to_owned()), &script)?;
+
+        Ok(stdout)
     }
 }
 
-fn to_string_truncated(bytes: &[u8], take: usize) -> String {
-    let len = std::cmp::min(bytes.len(), take);
-    String::from_utf8_lossy(&bytes[..len]).to_string()
-}
\ No newline at end of file
+impl Hyperv {
+    pub fn new() -> Self {
+        Self { sessions: Mutex::new(Vec::new()) }
+    }
+
+    fn validate_dir_path(operation: Operation, path: &Path) -> Result<&str> {
+        if !path.is_dir() {
+            Err(BackendError::InvalidArgument { operation, msg: format!("'{}' does not point to a valid directory", path.display()) })
+        } else {
+            let path = path.to_str().ok_or_else(|| BackendError::InvalidArgument { operation, msg: "Bad path".to_owned() })?;
+            Ok(path)
+        }
+    }
+
+    /// For the commands that `exit 1` when the VM they were given doesn't exist
+    fn map_vm_not_found(e: BackendError, operation: Operation, vm_id: &VmId) -> BackendError {
+        match e {
+            BackendError::CommandFailed { output, .. } if output.exit_code == 1 => BackendError::VmNotFound { operation, vm_id: *vm_id },
+            e => e,
+        }
+    }
+
+    fn run(&self, operation: Operation, target: Option<String>, script: &PsScript) -> Result<String> {
+        let session = self.sessions.lock().unwrap().pop();
+        let mut session = session.unwrap_or_else(PsSession::powershell);
+        trace!("Running PowerShell script to {}:\n{}", operation, script);
+        let output = session.run(script.as_str())
+            .map_err(|e| BackendError::HostFailed { operation, msg: e.to_string() })?;
+        self.sessions.lock().unwrap().push(session);
+        trace!("PowerShell script to {} exited with {}\nstdout:\n{}\nstderr:\n{}", operation, output.exit_code, output.stdout, output.stderr);
+
+        if output.exit_code != 0 {
+            Err(BackendError::CommandFailed {
+                operation,
+                target,
+                output: CommandOutput { exit_code: output.exit_code, stdout: output.stdout, stderr: output.stderr },
+            })
+        } else {
+            Ok(output.stdout)
+        }
+    }
+}
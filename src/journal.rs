@@ -0,0 +1,326 @@
+use crate::error::LamaError;
+use crate::{get_vm_id, restore_vm_config, discard_vm_config_backup};
+use crate::backend::{Backend, ImportedVm, SwitchStatus, VmId};
+use crate::plan::{Plan, Step};
+use crate::progress::Progress;
+use crate::state::{LabState, LAMA_DIR_NAME};
+use serde_derive::{Serialize, Deserialize};
+use failure::Error;
+use log::info;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE_NAME: &str = "journal.json";
+
+/// Everything a deploy has changed so far, in the order it happened, so that it can be undone or resumed.
+/// It's kept in `.lama/journal.json` under the lab root for as long as the deploy hasn't finished.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(skip)]
+    lab_path: PathBuf,
+    entries: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// The VM config files in `folder` (relative to the lab root) were backed up before being imported in place
+    BackedUpVmConfig { folder: PathBuf },
+    /// The VM in `folder` is about to be imported. `exported_id` is the ID in the name of its `.vmcx` file beforehand,
+    /// so that a VM the import created can be found if the deploy dies before it's recorded.
+    ImportingVm { folder: PathBuf, exported_id: Option<VmId> },
+    /// `adapters` is what the import reported, which is needed to wire up the VM if the deploy is resumed
+    ImportedVm { id: VmId, name: String, folder: PathBuf, adapters: HashMap<String, SwitchStatus> },
+    CreatedSwitch { id: Uuid, name: String },
+    ConnectedAdapter { vm_id: VmId, adapter_id: String },
+    StartedVm { id: VmId, name: String },
+}
+
+impl Journal {
+    pub fn new<P: AsRef<Path>>(lab_path: P) -> Self {
+        Self { lab_path: lab_path.as_ref().to_owned(), entries: Vec::new() }
+    }
+
+    /// Returns `None` if no deploy of the lab is in progress
+    pub fn load<P: AsRef<Path>>(lab_path: P) -> Result<Option<Journal>, Error> {
+        let journal_file_path = Self::file_path(lab_path.as_ref());
+        if !journal_file_path.is_file() {
+            return Ok(None);
+        }
+
+        let journal_file = fs::File::open(&journal_file_path)?;
+        let mut journal: Journal = serde_json::from_reader(journal_file)
+            .map_err(|e| LamaError::BadFile { path: journal_file_path.clone(), msg: e.to_string() })?;
+        journal.lab_path = lab_path.as_ref().to_owned();
+        Ok(Some(journal))
+    }
+
+    pub fn exists<P: AsRef<Path>>(lab_path: P) -> bool {
+        Self::file_path(lab_path.as_ref()).is_file()
+    }
+
+    pub fn remove<P: AsRef<Path>>(lab_path: P) -> Result<(), Error> {
+        let journal_file_path = Self::file_path(lab_path.as_ref());
+        if journal_file_path.is_file() {
+            fs::remove_file(&journal_file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the entry and saves the journal straight away so that it survives the process being killed
+    pub fn record(&mut self, entry: JournalEntry) -> Result<(), Error> {
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /// VMs that were imported but never started, i.e. whose deploy was interrupted half way
+    pub fn unfinished_vms(&self) -> Vec<(PathBuf, ImportedVm)> {
+        self.entries.iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::ImportedVm { id, name, folder, adapters } if !self.is_started(id) => {
+                    Some((folder.clone(), ImportedVm { id: *id, name: name.clone(), adapter_status: adapters.clone() }))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn is_connected(&self, vm_id: &VmId, adapter_id: &str) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            JournalEntry::ConnectedAdapter { vm_id: id, adapter_id: a } => id == vm_id && a == adapter_id,
+            _ => false,
+        })
+    }
+
+    fn is_imported(&self, vm_folder: &Path) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            JournalEntry::ImportedVm { folder, .. } => folder == vm_folder,
+            _ => false,
+        })
+    }
+
+    pub fn is_started(&self, vm_id: &VmId) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            JournalEntry::StartedVm { id, .. } => id == vm_id,
+            _ => false,
+        })
+    }
+
+    /// Puts back the config files of the VMs whose import was interrupted, since it may have
+    /// left them half-replaced, and forgets about them so that they get imported from scratch.
+    /// A VM the import got as far as creating is deleted first so that it isn't imported twice.
+    pub fn restore_interrupted_imports(&mut self, backend: &dyn Backend) -> Result<(), Error> {
+        let imported_folders: Vec<PathBuf> = self.entries.iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::ImportedVm { folder, .. } => Some(folder.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut interrupted_folders = Vec::new();
+        let mut exported_ids = HashMap::new();
+        self.entries.retain(|entry| match entry {
+            JournalEntry::BackedUpVmConfig { folder } if !imported_folders.contains(folder) => {
+                interrupted_folders.push(folder.clone());
+                false
+            }
+            JournalEntry::ImportingVm { folder, exported_id } if !imported_folders.contains(folder) => {
+                exported_ids.insert(folder.clone(), *exported_id);
+                false
+            }
+            _ => true,
+        });
+
+        for folder in &interrupted_folders {
+            if let Some(exported_id) = exported_ids.get(folder) {
+                if let Some(vm_id) = self.unrecorded_import(backend, folder, *exported_id)? {
+                    info!("==> Deleting VM {} left behind by the interrupted import of {}", vm_id, folder.display());
+                    backend.stop_vm(&vm_id)?;
+                    backend.delete_vm(&vm_id)?;
+                }
+            }
+
+            info!("==> Restoring config files of {} after interrupted import", folder.display());
+            restore_vm_config(&self.lab_path.join(folder))?;
+        }
+        self.save()
+    }
+
+    /// The VM that importing `folder` created, if the import went through but never made it into the journal.
+    /// Importing in place renames the `.vmcx` file after the new VM's ID, which is how it's found.
+    fn unrecorded_import(&self, backend: &dyn Backend, folder: &Path, exported_id: Option<VmId>) -> Result<Option<VmId>, Error> {
+        let vm_id = match get_vm_id(self.lab_path.join(folder))? {
+            Some(vm_id) if Some(vm_id) != exported_id => vm_id,
+            _ => return Ok(None),
+        };
+
+        let exists = backend.get_vms()?.iter().any(|vm| vm.id == vm_id);
+        Ok(if exists { Some(vm_id) } else { None })
+    }
+
+    /// Undoes every entry in reverse order, taking what was undone out of the journal and the state.
+    /// Carries on past failures so that as much as possible is cleaned up, and returns a description of each one.
+    /// What couldn't be undone is kept in both, so that dropping the lab can try again.
+    pub fn roll_back(&mut self, backend: &dyn Backend, state: &mut LabState) -> Result<Vec<String>, Error> {
+        let progress = Progress::new(false);
+        let mut failures = Vec::new();
+        let mut kept = Vec::new();
+        for entry in self.entries.iter().rev() {
+            let msg = match entry {
+                JournalEntry::StartedVm { name, .. } => format!("==> Stopping VM {}", name),
+                JournalEntry::ImportedVm { name, .. } => format!("==> Deleting VM {}", name),
+                JournalEntry::CreatedSwitch { name, .. } => format!("==> Deleting switch {}", name),
+                // Putting back the config files of a VM that is still there would pull them out from under it
+                JournalEntry::BackedUpVmConfig { folder } if kept.iter().any(|e| is_import_from(e, folder)) => {
+                    kept.push(entry.clone());
+                    continue;
+                }
+                JournalEntry::BackedUpVmConfig { folder } => format!("==> Restoring config files of {}", folder.display()),
+                JournalEntry::ImportingVm { folder, .. } if self.is_imported(folder) => continue, // Undone as the ImportedVm
+                JournalEntry::ImportingVm { folder, .. } => format!("==> Deleting any VM left behind by the interrupted import of {}", folder.display()),
+                JournalEntry::ConnectedAdapter { .. } => continue, // Goes away with the VM
+            };
+
+            match progress.step(&msg, || self.undo(backend, entry), |_| "Done".to_owned()) {
+                Ok(()) => forget(state, entry),
+                Err(e) => {
+                    failures.push(e);
+                    kept.push(entry.clone());
+                }
+            }
+        }
+
+        kept.reverse();
+        self.entries = kept;
+        if !failures.is_empty() {
+            self.save()?;
+        }
+        Ok(failures)
+    }
+
+    /// Adds what `roll_back` would do to the plan
+    pub fn plan_roll_back(&self, backend: &dyn Backend, plan: &mut Plan) -> Result<(), Error> {
+        for entry in self.entries.iter().rev() {
+            match entry {
+                JournalEntry::StartedVm { id, name } => plan.push(Step::StopVm { name: name.clone(), id: *id }),
+                JournalEntry::ImportedVm { id, name, .. } => plan.push(Step::DeleteVm { name: name.clone(), id: *id }),
+                JournalEntry::CreatedSwitch { id, name } => {
+                    let other_vm_count = self.other_vms_on_switch(backend, id)?.len();
+                    if other_vm_count > 0 {
+                        plan.push(Step::KeepSwitch { name: name.clone(), id: *id, reason: format!("{} VMs outside the lab are connected to it", other_vm_count) });
+                    } else {
+                        plan.push(Step::DeleteSwitch { name: name.clone(), id: *id });
+                    }
+                }
+                JournalEntry::BackedUpVmConfig { folder } => plan.push(Step::RestoreVmConfig { folder: folder.clone() }),
+                JournalEntry::ImportingVm { .. } | JournalEntry::ConnectedAdapter { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The IDs of the VMs the journal recorded as imported
+    pub fn imported_vm_ids(&self) -> Vec<VmId> {
+        self.entries.iter()
+            .filter_map(|entry| match entry { JournalEntry::ImportedVm { id, .. } => Some(*id), _ => None })
+            .collect()
+    }
+
+    /// The VMs connected to the switch that weren't imported by the deploy.
+    /// The lab's own VMs may still be connected, since they are only deleted after the switch.
+    fn other_vms_on_switch(&self, backend: &dyn Backend, switch_id: &Uuid) -> Result<Vec<VmId>, Error> {
+        let imported_vm_ids = self.imported_vm_ids();
+        Ok(backend.get_switch_vm_ids(&switch_id.to_hyphenated().to_string())?
+            .into_iter()
+            .filter(|vm_id| !imported_vm_ids.contains(vm_id))
+            .collect())
+    }
+
+    /// Takes everything the journal recorded as created out of the state
+    pub fn remove_from_state(&self, state: &mut LabState) {
+        for entry in &self.entries {
+            forget(state, entry);
+        }
+    }
+
+    /// Throws away the VM config backups and the journal itself once the deploy has finished
+    pub fn commit(&self) -> Result<(), Error> {
+        for entry in &self.entries {
+            if let JournalEntry::BackedUpVmConfig { folder } = entry {
+                let _ = discard_vm_config_backup(&self.lab_path.join(folder)); // Leaving a stale backup behind is harmless
+            }
+        }
+        Self::remove(&self.lab_path)
+    }
+
+    fn undo(&self, backend: &dyn Backend, entry: &JournalEntry) -> Result<(), String> {
+        match entry {
+            JournalEntry::StartedVm { id, .. } => backend.stop_vm(id).map(|_| ()).map_err(|e| e.to_string()),
+            JournalEntry::ImportedVm { id, .. } => {
+                backend.stop_vm(id)
+                    .and_then(|_| backend.delete_vm(id))
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+            JournalEntry::CreatedSwitch { id, name } => {
+                let other_vm_count = self.other_vms_on_switch(backend, id).map_err(|e| e.to_string())?.len();
+                if other_vm_count > 0 {
+                    return Err(format!("Switch {} was kept because {} VMs outside the lab are connected to it", name, other_vm_count));
+                }
+                backend.delete_switch(&id.to_hyphenated().to_string()).map(|_| ()).map_err(|e| e.to_string())
+            }
+            JournalEntry::BackedUpVmConfig { folder } => restore_vm_config(&self.lab_path.join(folder)).map_err(|e| e.to_string()),
+            JournalEntry::ImportingVm { folder, exported_id } => {
+                match self.unrecorded_import(backend, folder, *exported_id).map_err(|e| e.to_string())? {
+                    Some(vm_id) => {
+                        backend.stop_vm(&vm_id)
+                            .and_then(|_| backend.delete_vm(&vm_id))
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
+                    }
+                    None => Ok(()),
+                }
+            }
+            JournalEntry::ConnectedAdapter { .. } => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        let lama_dir_path = self.lab_path.join(LAMA_DIR_NAME);
+        if !lama_dir_path.is_dir() {
+            fs::create_dir_all(&lama_dir_path)?;
+        }
+
+        let journal_file_path = lama_dir_path.join(JOURNAL_FILE_NAME);
+        let temp_file_path = lama_dir_path.join(format!("{}.tmp", JOURNAL_FILE_NAME));
+        {
+            let temp_file = fs::File::create(&temp_file_path)?;
+            serde_json::to_writer_pretty(&temp_file, self)?;
+            temp_file.sync_all()?;
+        }
+        fs::rename(&temp_file_path, &journal_file_path)?;
+        Ok(())
+    }
+
+    fn file_path(lab_path: &Path) -> PathBuf {
+        lab_path.join(LAMA_DIR_NAME).join(JOURNAL_FILE_NAME)
+    }
+}
+
+/// Takes what the entry recorded as created out of the state
+fn forget(state: &mut LabState, entry: &JournalEntry) {
+    match entry {
+        JournalEntry::ImportedVm { id, .. } => state.vms.retain(|vm| vm.id != *id),
+        JournalEntry::CreatedSwitch { id, .. } => state.switches.retain(|s| s.id != *id),
+        _ => {}
+    }
+}
+
+fn is_import_from(entry: &JournalEntry, vm_folder: &Path) -> bool {
+    match entry {
+        JournalEntry::ImportedVm { folder, .. } | JournalEntry::ImportingVm { folder, .. } => folder == vm_folder,
+        _ => false,
+    }
+}
@@ -0,0 +1,126 @@
+use crate::state::LAMA_DIR_NAME;
+use chrono::{Local, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs;
+use std::io::{stdout, Write};
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const LOGS_DIR_NAME: &str = "logs";
+
+/// Records with this target only go to the log file, e.g. for lines already shown on the console some other way
+pub const FILE_ONLY: &str = "lama::file_only";
+
+/// Logs to the console at the level picked on the commandline, and everything down to
+/// trace level to a file in the lab's `.lama/logs` once the lab is known
+struct Logger;
+
+static LOGGER: Logger = Logger;
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static LOG_FILE: Mutex<LogFile> = Mutex::new(LogFile::Pending(Vec::new()));
+
+enum LogFile {
+    /// Records logged before the file was opened, which are written to it once it is
+    Pending(Vec<String>),
+    Open(fs::File),
+    /// The command doesn't keep a log file, or it couldn't be created
+    None,
+}
+
+/// `verbosity` is -1 for `-q`, 0 by default and 1 or more for each `-v`
+pub fn init(verbosity: i32) {
+    let console_level = match verbosity {
+        v if v < 0 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    CONSOLE_LEVEL.store(console_level as usize, Ordering::Relaxed);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+pub fn console_level() -> LevelFilter {
+    match CONSOLE_LEVEL.load(Ordering::Relaxed) {
+        l if l <= LevelFilter::Warn as usize => LevelFilter::Warn,
+        l if l == LevelFilter::Info as usize => LevelFilter::Info,
+        l if l == LevelFilter::Debug as usize => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Starts writing the log to a new file named after the time and `command` in the lab's `.lama/logs`,
+/// beginning with whatever was logged so far. A log file that can't be created isn't worth failing over.
+pub fn start_log_file(lab_path: &Path, command: &str) {
+    let logs_dir_path = lab_path.join(LAMA_DIR_NAME).join(LOGS_DIR_NAME);
+    // The process ID keeps runs started within the same second apart
+    let log_file_path = logs_dir_path.join(format!("{}-{}-{}.log", Local::now().format("%Y%m%d-%H%M%S"), command, process::id()));
+    match create_log_file(&logs_dir_path, &log_file_path) {
+        Ok(mut file) => {
+            let mut log_file = lock_log_file();
+            if let LogFile::Pending(lines) = mem::replace(&mut *log_file, LogFile::None) {
+                for line in lines {
+                    let _ = writeln!(file, "{}", line);
+                }
+            }
+            *log_file = LogFile::Open(file);
+            drop(log_file);
+            log::debug!("Logging to {}", log_file_path.display());
+        }
+        Err(e) => {
+            stop_log_file();
+            log::warn!("Failed to create log file '{}': {}", log_file_path.display(), e);
+        }
+    }
+}
+
+/// For commands that don't keep a log file, so that their records aren't held on to for nothing
+pub fn stop_log_file() {
+    *lock_log_file() = LogFile::None;
+}
+
+fn create_log_file(logs_dir_path: &Path, log_file_path: &Path) -> std::io::Result<fs::File> {
+    fs::create_dir_all(logs_dir_path)?;
+    fs::File::create(log_file_path)
+}
+
+fn lock_log_file() -> MutexGuard<'static, LogFile> {
+    LOG_FILE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() != FILE_ONLY && record.level() <= console_level() {
+            match record.level() {
+                Level::Error => eprintln!("Error: {}", record.args()),
+                Level::Warn => eprintln!("Warning: {}", record.args()),
+                _ => println!("{}", record.args()),
+            }
+        }
+
+        let line = || format!("{} {:<5} {}", Utc::now().to_rfc3339(), record.level(), record.args());
+        match &mut *lock_log_file() {
+            LogFile::Pending(lines) => lines.push(line()),
+            LogFile::Open(file) => {
+                let _ = writeln!(file, "{}", line());
+            }
+            LogFile::None => {}
+        }
+    }
+
+    fn flush(&self) {
+        let _ = stdout().flush();
+        if let LogFile::Open(file) = &mut *lock_log_file() {
+            let _ = file.flush();
+        }
+    }
+}
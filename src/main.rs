@@ -1,236 +1,1147 @@
+mod error;
+mod backend;
 mod hyperv;
+mod ps_script;
+mod ps_session;
+#[cfg(test)]
+mod fake;
+mod manifest;
+mod state;
+mod journal;
+mod status;
+mod progress;
+mod logging;
+mod plan;
+mod sha256;
+mod checksums;
+mod copy;
+mod remote;
+mod bundle;
+#[cfg(test)]
+mod tests;
 
-use crate::hyperv::VmId;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use quicli::prelude::*;
-use hyperv::{Hyperv, SwitchType, ImportedVm};
-use std::collections::HashMap;
+use backend::{Backend, SwitchType, SwitchKind, ImportedVm, VmId, RenameAction, ProvisionTarget};
+use error::{LamaError, ErrorReport};
+use hyperv::Hyperv;
+use manifest::{LabManifest, VmDef, SwitchDef, MANIFEST_FILE_NAME};
+use state::{LabState, VmState, AdapterState, SwitchState};
+use status::LabStatus;
+use journal::{Journal, JournalEntry};
+use progress::Progress;
+use plan::{Plan, Step};
+use checksums::{Checksums, LabIndex, CHECKSUMS_FILE_NAME};
+use bundle::Extraction;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, Component, Prefix};
-use exitfailure::ExitFailure;
 use std::fs;
-use std::fmt;
+use std::cmp;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::io::{stdin, stdout, Write};
+use std::thread;
+use std::process;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
-use failure::Fail;
-use fs_extra::{copy_items_with_progress, copy_items, dir::{CopyOptions, TransitProcessResult}};
-use pbr::ProgressBar;
+use fs_extra::{copy_items, dir::CopyOptions};
+
+#[derive(Debug, StructOpt)]
+struct Cli {
+    /// Print the output of status, and any error on stderr, as JSON
+    #[structopt(long = "json", raw(global = "true"))]
+    json: bool,
+    /// Show more of what's going on. Twice shows every PowerShell script run along with its output.
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences), raw(global = "true"))]
+    verbose: u8,
+    /// Show only warnings and errors
+    #[structopt(short = "q", long = "quiet", raw(global = "true", conflicts_with = "\"verbose\""))]
+    quiet: bool,
+    #[structopt(subcommand)]
+    subcommand: Subcommand,
+}
 
 #[derive(Debug, StructOpt)]
 enum Subcommand {
     #[structopt(name = "deploy")]
     Deploy { 
         path: PathBuf,
-        #[structopt(long = "provision")]
-        provisioner_path: Option<PathBuf>
+        #[structopt(flatten)]
+        options: DeployOptions,
     },
     #[structopt(name = "drop")]
-    Delete { path: PathBuf },
+    Delete {
+        path: PathBuf,
+        /// Print what would be deleted without deleting anything
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+    #[structopt(name = "provision")]
+    Provision {
+        path: PathBuf,
+        #[structopt(long = "ps")]
+        provisioner_path: Option<PathBuf>
+    },
+    #[structopt(name = "status")]
+    Status { path: PathBuf },
+    #[structopt(name = "export")]
+    Export {
+        path: PathBuf,
+        dest_path: PathBuf,
+    },
+    /// Check the files of an exported lab against the checksums written by export
+    #[structopt(name = "verify")]
+    Verify { path: PathBuf },
+    /// Pack an exported lab into a single .lama file that deploy can take
+    #[structopt(name = "pack")]
+    Pack {
+        path: PathBuf,
+        bundle_path: PathBuf,
+        /// Compress the bundle with zstd
+        #[structopt(long = "zstd")]
+        compress: bool,
+    },
 }
 
-fn main() -> CliResult {
-    match Subcommand::from_args() {
-        Subcommand::Deploy { path, provisioner_path } => deploy_lab(path, provisioner_path)?,
-        Subcommand::Delete { path } => delete_lab(path)?,
+impl Subcommand {
+    fn name(&self) -> &'static str {
+        match self {
+            Subcommand::Deploy { .. } => "deploy",
+            Subcommand::Delete { .. } => "drop",
+            Subcommand::Provision { .. } => "provision",
+            Subcommand::Status { .. } => "status",
+            Subcommand::Export { .. } => "export",
+            Subcommand::Verify { .. } => "verify",
+            Subcommand::Pack { .. } => "pack",
+        }
     }
-    
-    Ok(())
 }
 
-fn deploy_lab(mut lab_path: PathBuf, _provisioner_path: Option<PathBuf>) -> CliResult {
-    if !lab_path.is_dir() {
-        return Err(LamaError::new(format!("Path '{}' does not exist", lab_path.display())))?;
+#[derive(Debug, StructOpt)]
+struct DeployOptions {
+    #[structopt(long = "provision")]
+    provisioner_path: Option<PathBuf>,
+    /// Leave whatever was created behind if the deploy fails, e.g. to debug it
+    #[structopt(long = "no-rollback")]
+    no_rollback: bool,
+    /// Continue a deploy that was interrupted or failed with --no-rollback
+    #[structopt(long = "resume")]
+    resume: bool,
+    /// Number of VMs to import at the same time
+    #[structopt(long = "jobs", short = "j", default_value = "1")]
+    jobs: usize,
+    /// Answer yes to every question, e.g. copy a lab on a network share to the current directory
+    #[structopt(long = "yes", short = "y")]
+    yes: bool,
+    /// Never ask anything. Fail instead if a question isn't answered by the other flags.
+    #[structopt(long = "non-interactive")]
+    non_interactive: bool,
+    /// Where to copy the lab to if it's on a network share or a web server, or to extract it to if it's a bundle
+    #[structopt(long = "copy-to")]
+    copy_to: Option<PathBuf>,
+    /// Where to download labs from web servers to before they're copied. Defaults to %LOCALAPPDATA%\lama\cache.
+    #[structopt(long = "cache-dir")]
+    cache_dir: Option<PathBuf>,
+    /// Deploy even if the path doesn't look like a lab
+    #[structopt(long = "force-path")]
+    force_path: bool,
+    /// Drop the lab and deploy it again if it's already deployed
+    #[structopt(long = "redeploy")]
+    redeploy: bool,
+    /// Deploy only the VMs that have gone missing if the lab is already deployed
+    #[structopt(long = "missing-only", raw(conflicts_with = "\"redeploy\""))]
+    missing_only: bool,
+    /// Print what the deploy would do without changing anything
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// Prefix the names of the lab's VMs and switches with this, so that several copies of the lab can be deployed side by side
+    #[structopt(long = "name")]
+    name: Option<String>,
+    /// Deploy without checking the lab's files against the checksums written by export
+    #[structopt(long = "skip-verify")]
+    skip_verify: bool,
+}
+
+fn main() {
+    let cli = Cli::from_args();
+    logging::init(if cli.quiet { -1 } else { i32::from(cli.verbose) });
+
+    // deploy starts its log file itself since the lab may be copied somewhere else first
+    match &cli.subcommand {
+        Subcommand::Delete { path, dry_run: false } | Subcommand::Provision { path, .. } | Subcommand::Export { path, .. } if path.is_dir() => {
+            logging::start_log_file(path, cli.subcommand.name());
+        }
+        Subcommand::Deploy { .. } => {}
+        _ => logging::stop_log_file(),
+    }
+
+    let backend = Hyperv::new();
+    let result = match cli.subcommand {
+        Subcommand::Deploy { path, options } => deploy_lab(&backend, path, &options),
+        Subcommand::Delete { path, dry_run: false } => delete_lab(&backend, path),
+        Subcommand::Delete { path, dry_run: true } => show_drop_plan(&backend, path),
+        Subcommand::Provision { path, provisioner_path } => provision_deployed_lab(&backend, path, provisioner_path),
+        Subcommand::Status { path } => show_status(&backend, path, cli.json),
+        Subcommand::Export { path, dest_path } => export_lab(&backend, path, dest_path),
+        Subcommand::Verify { path } => verify_lab(path),
+        Subcommand::Pack { path, bundle_path, compress } => bundle::pack_lab(&path, &bundle_path, compress),
+    };
+
+    if let Err(e) = result {
+        let report = ErrorReport::new(&e);
+        error!(target: logging::FILE_ONLY, "{}", e);
+        if cli.json {
+            eprintln!("{}", serde_json::to_string_pretty(&report).unwrap_or_else(|_| report.message.clone()));
+        } else {
+            eprintln!("Error: {}", e);
+            for cause in e.iter_causes() {
+                eprintln!("Info: caused by {}", cause);
+            }
+        }
+        drop(backend); // process::exit() skips destructors, and the PowerShell sessions should get to exit cleanly
+        process::exit(report.exit_code);
+    }
+}
+
+fn deploy_lab(backend: &dyn Backend, mut lab_path: PathBuf, options: &DeployOptions) -> Result<(), Error> {
+    let url = remote::lab_url(&lab_path).map(|u| u.to_owned());
+    let is_bundle = url.is_none() && bundle::is_bundle(&lab_path);
+    if url.is_none() && !is_bundle && !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+    if bundle::is_partly_extracted(&lab_path) {
+        return Err(LamaError::InvalidLab(format!("'{}' was only partly extracted from a bundle. Drop it, then deploy the bundle again", lab_path.display())).into());
     }
 
     let lab_folder_name = lab_path.file_name();
 
     const YES_CHOICE: &str = "Y";
     const NO_CHOICE: &str = "N";
-    if lab_folder_name.is_none() {
+    let mut plan = Plan::new("deploy");
+    if url.is_none() && !is_bundle && lab_folder_name.is_none() && !options.force_path && !options.yes && !options.dry_run {
         let prompt = format!("'{}' does not seem to be a valid lab path. Are you sure you want to deploy from here? [{}] Yes [{}] No: ", lab_path.display(), YES_CHOICE, NO_CHOICE);
-        if prompt_user(prompt.as_str())?.as_str() != YES_CHOICE {
-            return Ok(());
+        let answer = ask(&prompt, options)?.ok_or_else(|| LamaError::NeedsAnswer {
+            question: format!("'{}' does not seem to be a valid lab path", lab_path.display()),
+            flags: "--force-path or --yes",
+        })?;
+        if answer.as_str() != YES_CHOICE {
+            return Err(LamaError::Aborted.into());
         }
     }
 
-    if is_remote_path(&lab_path)? {
-        println!("Cannot deploy from network location. Do you want me to copy the lab locally first and deploy from there?");
-        const DIFFERENT_LOC_CHOICE: &str = "D";
-        let dest_path: PathBuf = match prompt_user(&format!("[{}] Copy to current directory [{}] Copy to a different location [{}] Abort: ", YES_CHOICE, DIFFERENT_LOC_CHOICE, NO_CHOICE))?.to_uppercase().as_str() {
-            YES_CHOICE => ".".to_owned(),
-            DIFFERENT_LOC_CHOICE => {
-                prompt_user("Enter path (will be created if missing): ")?
-            }
-            NO_CHOICE => return Ok(()),
-            _ => {
-                return Err(LamaError::new("Invalid choice"))?;
+    let is_remote = url.is_some() || is_bundle || is_remote_path(&lab_path)?;
+    let mut copy_verified = false;
+    let cache_dir = options.cache_dir.clone().unwrap_or_else(remote::default_cache_dir);
+    if let (Some(url), true) = (&url, options.dry_run) {
+        if let Some(cached_path) = remote::cached_lab(url, &cache_dir)? {
+            lab_path = cached_path;
+        } else {
+            let dest_path = options.copy_to.clone().unwrap_or_else(|| PathBuf::from("."));
+            let cache_path = remote::cache_path(url, &cache_dir);
+            plan.push(Step::DownloadLab { url: url.clone(), to: cache_path.clone() });
+            if bundle::has_bundle_extension(&cache_path) {
+                plan.push(Step::ExtractBundle { from: cache_path, to: dest_path });
+            } else {
+                plan.push(Step::CopyLab { from: lab_path.clone(), to: dest_path });
             }
-        }.into();
+            info!("The rest of the deploy can't be planned until the lab is downloaded");
+            plan.print();
+            return Ok(());
+        }
+    }
+
+    if is_remote && options.dry_run && bundle::is_bundle(&lab_path) {
+        let manifest = bundle::read_manifest(&lab_path)?;
+        let dest_path = options.copy_to.clone().unwrap_or_else(|| PathBuf::from("."));
+        plan.push(Step::ExtractBundle { from: lab_path.clone(), to: dest_path.join(&manifest.name) });
+        info!("The rest of the deploy can't be planned until the lab is extracted");
+        plan.print();
+        return Ok(());
+    } else if is_remote && options.dry_run {
+        // The lab is planned from where it is, since that's what would be copied
+        let dest_path = options.copy_to.clone().unwrap_or_else(|| PathBuf::from("."));
+        plan.push(Step::CopyLab { from: lab_path.clone(), to: dest_path });
+    } else if is_remote {
+        let dest_path: PathBuf = if let Some(copy_to) = &options.copy_to {
+            copy_to.clone()
+        } else if options.yes {
+            PathBuf::from(".")
+        } else {
+            const DIFFERENT_LOC_CHOICE: &str = "D";
+            let prompt = if is_bundle {
+                format!("The lab has to be extracted from the bundle first. Where do you want me to extract it?\n[{}] Extract to current directory [{}] Extract to a different location [{}] Abort: ", YES_CHOICE, DIFFERENT_LOC_CHOICE, NO_CHOICE)
+            } else {
+                format!("Cannot deploy from a network location or web server. Do you want me to copy the lab locally first and deploy from there?\n[{}] Copy to current directory [{}] Copy to a different location [{}] Abort: ", YES_CHOICE, DIFFERENT_LOC_CHOICE, NO_CHOICE)
+            };
+            let answer = ask(&prompt, options)?.ok_or_else(|| LamaError::NeedsAnswer {
+                question: if is_bundle {
+                    format!("'{}' is a bundle and has to be extracted first", lab_path.display())
+                } else {
+                    format!("'{}' is on a network location and has to be copied locally first", lab_path.display())
+                },
+                flags: "--copy-to <dir> or --yes",
+            })?;
+            match answer.to_uppercase().as_str() {
+                YES_CHOICE => ".".to_owned(),
+                DIFFERENT_LOC_CHOICE => {
+                    prompt_user("Enter path (will be created if missing): ")?
+                }
+                NO_CHOICE => return Err(LamaError::Aborted)?,
+                _ => {
+                    return Err(LamaError::InvalidInput("Invalid choice".to_owned()).into());
+                }
+            }.into()
+        };
 
         if !dest_path.is_dir() {
             fs::create_dir_all(&dest_path)?;
-            println!("Created directory {}", dest_path.display());
+            info!("Created directory {}", dest_path.display());
         } 
 
-        let full_dest_path = match lab_folder_name {
-            Some(folder_name) => PathBuf::from(&dest_path).join(folder_name),
-            None => PathBuf::from(&dest_path),
-        };
-        println!("Copying to {}...", full_dest_path.display());
-        copy_lab(&lab_path, &dest_path)?;
-        lab_path = match lab_folder_name {
-            Some(folder_name) => PathBuf::from(dest_path).join(folder_name),
-            None => PathBuf::from(dest_path),
-        };
+        if let Some(url) = &url {
+            lab_path = remote::fetch_lab(url, &cache_dir, !options.skip_verify, remote::RETRY_DELAY)?;
+        }
+        if bundle::is_bundle(&lab_path) {
+            let extraction = Extraction::start(&lab_path, &dest_path)?;
+            return deploy_extracting(backend, &extraction, plan, options);
+        }
+        let copied_lab = copy::copy_lab(&lab_path, &dest_path, !options.skip_verify)?;
+        lab_path = copied_lab.path;
+        copy_verified = copied_lab.verified;
+    }
+    deploy_from(backend, &lab_path, copy_verified, plan, options, None)
+}
+
+/// Deploys the lab as it's extracted from its bundle, importing each VM once the files of its folder are there.
+/// If the deploy fails before the lab is all there, what was extracted of it is removed, unless VMs are left
+/// deployed from it.
+fn deploy_extracting(backend: &dyn Backend, extraction: &Extraction, plan: Plan, options: &DeployOptions) -> Result<(), Error> {
+    let lab_path = extraction.lab_path();
+    let (extracted, deployed) = crossbeam_utils::thread::scope(|scope| {
+        let extracting = scope.spawn(|_| extraction.run(!options.skip_verify));
+        let deployed = extraction.wait_for_layout()
+            .map_err(|e| extraction.take_error().unwrap_or(e))
+            // Every file of the bundle is checked against its hash as it's extracted
+            .and_then(|_| deploy_from(backend, lab_path, !options.skip_verify, plan, options, Some(extraction)));
+        if deployed.is_err() {
+            extraction.stop();
+        }
+        (extracting.join(), deployed)
+    }).map_err(|_| LamaError::Internal("A bundle extraction or deploy thread panicked".to_owned()))?;
+    extracted.map_err(|_| LamaError::Internal("The bundle extraction thread panicked".to_owned()))?;
+
+    if let Err(e) = deployed {
+        if !extraction.is_finished() && !Journal::exists(lab_path) && LabState::load(lab_path)?.is_none() {
+            logging::stop_log_file();
+            fs::remove_dir_all(lab_path)?;
+        }
+        return Err(e);
+    }
+    match extraction.take_error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Deploys the lab at `lab_path`, which is local by now. `copy_verified` says if its files were checked on the way there.
+fn deploy_from(backend: &dyn Backend, lab_path: &Path, copy_verified: bool, mut plan: Plan, options: &DeployOptions, extraction: Option<&Extraction>) -> Result<(), Error> {
+    if options.dry_run {
+        logging::stop_log_file();
+    } else {
+        logging::start_log_file(lab_path, "deploy");
+    }
+
+    let checksums = Checksums::load(lab_path)?;
+    if let Some(checksums) = &checksums {
+        if options.skip_verify {
+            warn!("Not checking the lab against its checksums as asked");
+        } else if !copy_verified {
+            check_lab_files(lab_path, checksums)?;
+        }
     }
 
-    // TOOD: for non-remote lab paths make sure that lab is not already deployed
-    import_lab(lab_path)?;
+    let mut existing_state = None;
+    let mut journal = None;
+    if options.resume {
+        journal = Some(Journal::load(lab_path)?
+            .ok_or_else(|| LamaError::NothingToResume(lab_path.to_owned()))?);
+        existing_state = LabState::load(lab_path)?;
+        info!("Resuming deploy");
+    } else if Journal::exists(lab_path) {
+        return Err(LamaError::UnfinishedDeploy(lab_path.to_owned()).into());
+    } else if let Some(mut state) = LabState::load(lab_path)? {
+        // A lab only counts as deployed if it has state. The IDs in the names of the .vmcx files
+        // can't be trusted for this because exported VMs keep the IDs of the VMs they were exported from.
+        if !state.vms.is_empty() {
+            let live_vm_ids: HashSet<VmId> = backend.get_vms()?.into_iter().map(|vm| vm.id).collect();
+            let live_count = state.vms.iter().filter(|vm| live_vm_ids.contains(&vm.id)).count();
+            info!("Lab is already deployed ({} of its {} VMs exist)", live_count, state.vms.len());
+
+            const REDEPLOY_CHOICE: &str = "R";
+            const MISSING_CHOICE: &str = "M";
+            const ABORT_CHOICE: &str = "A";
+            let some_missing = live_count < state.vms.len();
+            let answer = if options.redeploy {
+                REDEPLOY_CHOICE.to_owned()
+            } else if options.missing_only {
+                if !some_missing {
+                    info!("No VMs are missing. Nothing to deploy");
+                    return Ok(());
+                }
+                MISSING_CHOICE.to_owned()
+            } else if options.dry_run {
+                info!("A deploy would ask whether to redeploy the lab{}. Pass --redeploy or --missing-only to see what it would do then",
+                    if some_missing { " or deploy only the missing VMs" } else { "" });
+                return Ok(());
+            } else {
+                let prompt = if some_missing {
+                    format!("[{}] Redeploy (drop then deploy) [{}] Deploy only the missing VMs [{}] Abort: ", REDEPLOY_CHOICE, MISSING_CHOICE, ABORT_CHOICE)
+                } else {
+                    format!("[{}] Redeploy (drop then deploy) [{}] Abort: ", REDEPLOY_CHOICE, ABORT_CHOICE)
+                };
+                // --yes doesn't answer this one since neither choice is a plain yes
+                let answer = if options.yes { None } else { ask(&prompt, options)? };
+                answer.ok_or_else(|| LamaError::AlreadyDeployed(lab_path.to_owned()))?
+            };
+
+            match answer.to_uppercase().as_str() {
+                REDEPLOY_CHOICE if options.dry_run => plan_drop(backend, lab_path, &mut plan)?,
+                REDEPLOY_CHOICE => delete_lab(backend, lab_path)?,
+                MISSING_CHOICE if some_missing => {
+                    // Forget whatever no longer exists so that it gets recreated
+                    let live_switch_ids: HashSet<Uuid> = backend.get_switches()?.into_iter().map(|s| s.id).collect();
+                    state.vms.retain(|vm| live_vm_ids.contains(&vm.id));
+                    state.switches.retain(|s| live_switch_ids.contains(&s.id));
+                    existing_state = Some(state);
+                }
+                ABORT_CHOICE => return Err(LamaError::Aborted)?,
+                _ => {
+                    return Err(LamaError::InvalidInput("Invalid choice".to_owned()).into());
+                }
+            }
+        }
+    }
+
+    if options.dry_run {
+        plan_deploy(backend, lab_path, existing_state, journal.as_ref(), options, &mut plan)?;
+        plan.print();
+        return Ok(());
+    }
+
+    if checksums.is_some() {
+        Checksums::set_aside(lab_path)?;
+    }
+
+    let vms = import_lab(backend, lab_path, existing_state, journal, options, extraction)?;
+    let provisioners = get_provisioners(lab_path, &vms, options.provisioner_path.as_deref())?;
+    if !vms.is_empty() && !provisioners.is_empty() {
+        provision_lab(backend, &vms, &provisioners)?;
+    }
     Ok(())
 }
 
-fn delete_lab<P: AsRef<Path>>(path: P) -> CliResult {
+fn provision_deployed_lab<P: AsRef<Path>>(backend: &dyn Backend, path: P, provisioner_path: Option<PathBuf>) -> Result<(), Error> {
     let lab_path = path.as_ref();
     if !lab_path.is_dir() {
-        return Err(LamaError::new(format!("Path '{}' does not exist", lab_path.display())))?;
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
     }
 
-    for vm_path in get_vm_paths(lab_path)? {
-        let vm_id = get_vm_id(&vm_path)?;
-        if let Some(vm_id) = vm_id {
-            println!("==> Stopping VM {}... ", vm_id);
-            Hyperv::stop_vm(&vm_id)?;
-            print!("==> Deleting VM {}... ", vm_id);
-            back_up_vm_config(&vm_path)?; // Save the VM config files because delete-vm will delete them
-            if Hyperv::delete_vm(&vm_id)? {
-                println!("deleted");
-            } else {
-                println!("not found");
+    let vms = get_deployed_vms(lab_path, LabState::load(lab_path)?.as_ref())?;
+    if vms.is_empty() {
+        return Err(LamaError::NotDeployed(lab_path.to_owned()).into());
+    }
+
+    let provisioners = get_provisioners(lab_path, &vms, provisioner_path.as_deref())?;
+    if provisioners.is_empty() {
+        info!("Nothing to provision");
+        return Ok(());
+    }
+
+    provision_lab(backend, &vms, &provisioners)
+}
+
+fn delete_lab<P: AsRef<Path>>(backend: &dyn Backend, path: P) -> Result<(), Error> {
+    let lab_path = path.as_ref();
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+
+    if let Some(mut journal) = Journal::load(lab_path)? {
+        // Undo the unfinished deploy the way a failed one would be, so that its VM config files are restored too
+        info!("Rolling back unfinished deploy...");
+        let mut state = LabState::load(lab_path)?.unwrap_or_else(LabState::new);
+        let failures = roll_back_deploy(backend, lab_path, &mut journal, &mut state)?;
+        if !failures.is_empty() {
+            return Err(LamaError::CleanupIncomplete { cause: None, failures }.into());
+        }
+        if state.vms.is_empty() && state.switches.is_empty() {
+            return Ok(()); // The unfinished deploy was all there was
+        }
+    }
+
+    let progress = Progress::new(false);
+    let deleted_or_not_found = |deleted: &bool| if *deleted { "deleted" } else { "not found" }.to_owned();
+    let state = LabState::load(lab_path)?;
+    for (vm_path, vm_id) in get_deployed_vms(lab_path, state.as_ref())? {
+        progress.step(&format!("==> Stopping VM {}", vm_id), || backend.stop_vm(&vm_id), |_| "Done".to_owned())?;
+        back_up_vm_config(&vm_path)?; // Save the VM config files because delete-vm will delete them
+        progress.step(&format!("==> Deleting VM {}", vm_id), || backend.delete_vm(&vm_id), deleted_or_not_found)?;
+        restore_vm_config(&vm_path)?; // Restore the backed up config files now
+    }
+
+    // The lab's own VMs are gone by now, so any VM still connected is someone else's
+    let mut kept_switches = Vec::new();
+    for (switch, fate) in judge_lab_switches(backend, lab_path, state.as_ref(), &[])? {
+        match fate {
+            SwitchFate::Delete => {
+                let switch_id = switch.id.to_hyphenated().to_string();
+                progress.step(&format!("==> Deleting switch {}", switch.name), || backend.delete_switch(&switch_id), deleted_or_not_found)?;
+            }
+            SwitchFate::NotTheLabs => warn!("Switch {} (ID: {}) was not created for this lab. Leaving it alone", switch.name, switch.id),
+            SwitchFate::InUse(vm_names) => {
+                warn!("Not deleting switch {}: still used by VMs {}", switch.name, vm_names.join(", "));
+                kept_switches.push(switch);
             }
-            restore_vm_config(&vm_path)?; // Restore the backed up config files now
         }
     }
 
-    // Delete switches if .lama/switches.json file is present
-    let lama_dir_path = lab_path.join(".lama");
-    let switches_file_path = lama_dir_path.join("switches.json");
-    if switches_file_path.is_file() {
-        let mut switches_file = fs::File::open(&switches_file_path)?;
-        let switches: HashMap<String, Uuid> = serde_json::from_reader(&mut switches_file)?;
+    if kept_switches.is_empty() {
+        LabState::remove(lab_path)?;
+        Ok(())
+    } else {
+        // Keep track of the switches left behind so that the next drop has another go at them
+        let mut state = state.unwrap_or_else(LabState::new);
+        state.vms.clear();
+        state.switches = kept_switches;
+        state.save(lab_path)?;
+        Err(LamaError::SwitchesInUse { names: state.switches.into_iter().map(|s| s.name).collect() })?
+    }
+}
+
+/// What drop does with one of the lab's switches
+enum SwitchFate {
+    Delete,
+    /// The switch was tagged for another lab
+    NotTheLabs,
+    /// The switch is still used by these VMs from outside the lab
+    InUse(Vec<String>),
+}
 
-        for switch_name in switches.keys() {
-            // TODO: check if the switch is connected to any VM an don't delete it if it is
-            print!("==> Deleting switch {}... ", switch_name);
-            let switch_id = switches[switch_name];
-            if Hyperv::delete_switch(&switch_id.to_hyphenated().to_string())? {
-                println!("deleted");
-            } else {
-                println!("not found");
+/// Works out which of the lab's switches drop may delete. The VMs in `lab_vm_ids` don't count as using a switch,
+/// since drop deletes them before it gets to the switches.
+fn judge_lab_switches(backend: &dyn Backend, lab_path: &Path, state: Option<&LabState>, lab_vm_ids: &[VmId]) -> Result<Vec<(SwitchState, SwitchFate)>, Error> {
+    let switch_notes = LabState::switch_notes(lab_path)?;
+    let live_switches = backend.get_switches()?;
+    let lab_switches: Vec<SwitchState> = match state {
+        Some(state) => state.switches.clone(),
+        // Without the state file the lab's switches are found by the notes they were tagged with
+        None => live_switches.iter()
+            .filter(|s| s.notes == switch_notes)
+            .map(|s| SwitchState { id: s.id, name: s.name.clone(), switch_type: s.switch_type, adapter: s.net_adapter_name.clone(), host_ip: None })
+            .collect(),
+    };
+
+    let mut judged = Vec::new();
+    for switch in lab_switches {
+        let fate = match live_switches.iter().find(|s| s.id == switch.id) {
+            // Switches created before they were tagged have no notes
+            Some(live_switch) if !live_switch.notes.is_empty() && live_switch.notes != switch_notes => SwitchFate::NotTheLabs,
+            Some(_) => {
+                let vm_ids: Vec<VmId> = backend.get_switch_vm_ids(&switch.id.to_hyphenated().to_string())?
+                    .into_iter()
+                    .filter(|vm_id| !lab_vm_ids.contains(vm_id))
+                    .collect();
+                if vm_ids.is_empty() {
+                    SwitchFate::Delete
+                } else {
+                    SwitchFate::InUse(backend.get_vms()?.into_iter().filter(|vm| vm_ids.contains(&vm.id)).map(|vm| vm.name).collect())
+                }
+            }
+            None => SwitchFate::Delete,
+        };
+        judged.push((switch, fate));
+    }
+
+    Ok(judged)
+}
+
+fn show_drop_plan<P: AsRef<Path>>(backend: &dyn Backend, path: P) -> Result<(), Error> {
+    let lab_path = path.as_ref();
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+
+    let mut plan = Plan::new("drop");
+    plan_drop(backend, lab_path, &mut plan)?;
+    plan.print();
+    Ok(())
+}
+
+/// Adds what `delete_lab` would do to the plan
+fn plan_drop(backend: &dyn Backend, lab_path: &Path, plan: &mut Plan) -> Result<(), Error> {
+    let mut state = LabState::load(lab_path)?;
+    let mut lab_vm_ids = Vec::new();
+    if let Some(journal) = Journal::load(lab_path)? {
+        journal.plan_roll_back(backend, plan)?;
+        lab_vm_ids.extend(journal.imported_vm_ids());
+        match &mut state {
+            Some(state) => journal.remove_from_state(state),
+            None => return Ok(()),
+        }
+    }
+
+    for (vm_path, vm_id) in get_deployed_vms(lab_path, state.as_ref())? {
+        let name = state.as_ref()
+            .and_then(|s| s.vms.iter().find(|vm| vm.id == vm_id))
+            .map(|vm| vm.name.clone())
+            .unwrap_or_else(|| vm_path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default());
+        plan.push(Step::StopVm { name: name.clone(), id: vm_id });
+        plan.push(Step::DeleteVm { name, id: vm_id });
+        lab_vm_ids.push(vm_id);
+    }
+
+    for (switch, fate) in judge_lab_switches(backend, lab_path, state.as_ref(), &lab_vm_ids)? {
+        let (name, id) = (switch.name, switch.id);
+        plan.push(match fate {
+            SwitchFate::Delete => Step::DeleteSwitch { name, id },
+            SwitchFate::NotTheLabs => Step::KeepSwitch { name, id, reason: "it was not created for this lab".to_owned() },
+            SwitchFate::InUse(vm_names) => Step::KeepSwitch { name, id, reason: format!("still used by VMs {}", vm_names.join(", ")) },
+        });
+    }
+
+    Ok(())
+}
+
+fn show_status<P: AsRef<Path>>(backend: &dyn Backend, path: P, json: bool) -> Result<(), Error> {
+    let lab_path = path.as_ref();
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+
+    let status = LabStatus::query(backend, lab_path)?;
+    if json {
+        serde_json::to_writer_pretty(stdout(), &status)?;
+        println!();
+    } else {
+        status.print_table();
+    }
+
+    Ok(())
+}
+
+fn export_lab<P: AsRef<Path>, D: AsRef<Path>>(backend: &dyn Backend, path: P, dest_path: D) -> Result<(), Error> {
+    let lab_path = path.as_ref();
+    let dest_path = dest_path.as_ref();
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
+
+    if dest_path.is_dir() {
+        if fs::read_dir(dest_path)?.next().is_some() {
+            return Err(LamaError::InvalidInput(format!("Destination '{}' is not empty", dest_path.display())).into());
+        }
+    } else {
+        fs::create_dir_all(dest_path)?;
+        info!("Created directory {}", dest_path.display());
+    }
+
+    let source_manifest = LabManifest::load(lab_path)?;
+    let vm_names: HashMap<VmId, String> = backend.get_vms()?.into_iter().map(|vm| (vm.id, vm.name)).collect();
+    let switches: HashMap<Uuid, _> = backend.get_switches()?.into_iter().map(|s| (s.id, s)).collect();
+
+    // The exported lab gets a manifest so that the switch types survive the trip.
+    // Adapter wiring doesn't need to go in it because the exported VM configs keep it.
+    let progress = Progress::new(false);
+    let mut manifest = LabManifest::default();
+    if let Some(source_manifest) = &source_manifest {
+        manifest.provisioners = source_manifest.provisioners.clone();
+    }
+
+    for (vm_path, vm_id) in get_deployed_vms(lab_path, LabState::load(lab_path)?.as_ref())? {
+        let vm_name = vm_names.get(&vm_id)
+            .ok_or_else(|| LamaError::VmNotDeployed { vm_id, vm_path: Some(vm_path.to_owned()) })?;
+
+        progress.step(&format!("==> Exporting VM {}", vm_name), || backend.export_vm(&vm_id, dest_path), |_| "Done".to_owned())?;
+
+        for adapter in backend.get_vm_adapters(&vm_id)? {
+            if let Some(switch) = adapter.switch_id.and_then(|id| switches.get(&id)) {
+                if manifest.switch(&switch.name).is_none() {
+                    manifest.switches.push(SwitchDef {
+                        name: switch.name.clone(),
+                        switch_type: switch.switch_type,
+                        adapter: if switch.switch_type == SwitchKind::External { switch.net_adapter_name.clone() } else { None },
+                        // Hyper-V doesn't know about it, so it can only come from the lab's own manifest
+                        host_ip: source_manifest.as_ref().and_then(|m| m.switch(&switch.name)).and_then(|s| s.host_ip.clone()),
+                    });
+                }
             }
         }
 
-        // TODO: write the new switches file here based on switches being used just before the 'drop'
-        // so that next time 'deploy' is called the same envionrment as now is recreated.
+        let source_vm = source_manifest.as_ref()
+            .and_then(|m| m.vms.iter().find(|vm| lab_path.join(&vm.folder) == vm_path));
+        manifest.vms.push(VmDef {
+            name: vm_name.clone(),
+            folder: PathBuf::from(vm_name), // Export-VM puts each VM in a folder named after it
+            boot_order: source_vm.and_then(|vm| vm.boot_order),
+            provisioners: source_vm.map(|vm| vm.provisioners.clone()).unwrap_or_default(),
+            adapters: Vec::new(),
+        });
     }
 
+    let provisioners = manifest.provisioners.iter().chain(manifest.vms.iter().flat_map(|vm| vm.provisioners.iter()));
+    for provisioner in provisioners {
+        let provisioner_dest_path = dest_path.join(provisioner);
+        if !provisioner_dest_path.is_file() {
+            if let Some(parent) = provisioner_dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(lab_path.join(provisioner), &provisioner_dest_path)?;
+        }
+    }
+
+    manifest.save(dest_path)?;
+
+    let vm_folders: Vec<PathBuf> = manifest.vms.iter().map(|vm| vm.folder.clone()).collect();
+    let checksums = Checksums::compute(dest_path, &vm_folders, &progress)?;
+    checksums.save(dest_path)?;
+    LabIndex::compute(dest_path, &checksums)?.save(dest_path)?;
+
+    info!("Lab exported successfully to {}", dest_path.display());
     Ok(())
 }
 
-fn copy_lab<S: AsRef<Path>, D: AsRef<Path>>(source_path: S, dest_path: D) -> CliResult {
-    let count = 100;
-    let mut pb = ProgressBar::new(count);
-    pb.show_counter = false;
-    pb.show_speed = false;
-    pb.format("[=>-]");
+fn verify_lab<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let lab_path = path.as_ref();
+    if !lab_path.is_dir() {
+        return Err(LamaError::PathNotFound(lab_path.to_owned()).into());
+    }
 
-    let options = CopyOptions::new(); //Use default values for CopyOptions
-    let mut from_paths = Vec::new();
-    from_paths.push(source_path);
-    let _bytes = copy_items_with_progress(&from_paths, dest_path, &options, |process_info| {
-        pb.total = process_info.total_bytes;
-        pb.set(process_info.copied_bytes);
-        if process_info.copied_bytes == process_info.total_bytes {
-            pb.finish_print("done");
+    let checksums = match Checksums::load(lab_path)? {
+        Some(checksums) => checksums,
+        None if Checksums::set_aside_exists(lab_path) => {
+            return Err(LamaError::InvalidInput(format!("Lab '{}' has been deployed in place since it was exported, so its files no longer match its checksums", lab_path.display())).into());
         }
+        None => return Err(LamaError::PathNotFound(lab_path.join(CHECKSUMS_FILE_NAME)))?,
+    };
 
-        TransitProcessResult::ContinueOrAbort
-    })?;
+    check_lab_files(lab_path, &checksums)?;
+    info!("All {} files of the lab match their checksums", checksums.file_count());
     Ok(())
 }
 
-fn import_lab<P: AsRef<Path>>(path: P) -> CliResult {
-    let mut created_switches = HashMap::new();
-    let vm_paths = get_vm_paths(&path)?;
-    print!("Found {} VMs in lab", vm_paths.len());
-    if vm_paths.is_empty() {
-        println!(". Nothing to deploy");
-        return Ok(());
+/// Fails with every way the files of the lab differ from its checksums, if they do
+fn check_lab_files(lab_path: &Path, checksums: &Checksums) -> Result<(), Error> {
+    let mismatches = checksums.verify(lab_path, &Progress::new(false))?;
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(LamaError::ChecksumMismatch { lab_path: lab_path.to_owned(), mismatches: mismatches.iter().map(|m| m.to_string()).collect() })?
+    }
+}
+
+/// Imports the VMs of the lab that are not already in `existing_state`.
+/// When resuming, `journal` is the one of the interrupted deploy and the VMs it didn't finish are finished first.
+/// While the lab is being extracted from a bundle by `extraction` each VM waits for its files.
+fn import_lab<P: AsRef<Path>>(backend: &dyn Backend, path: P, existing_state: Option<LabState>, journal: Option<Journal>, options: &DeployOptions, extraction: Option<&Extraction>) -> Result<Vec<(PathBuf, VmId)>, Error> {
+    let path = path.as_ref();
+    if options.jobs == 0 {
+        return Err(LamaError::InvalidInput("--jobs must be at least 1".to_owned()).into());
+    }
+
+    let manifest = LabManifest::load(path)?;
+    let vms = get_lab_vms(path, manifest.as_ref())?;
+
+    let journal = match journal {
+        Some(mut journal) => {
+            journal.restore_interrupted_imports(backend)?;
+            journal
+        }
+        None => Journal::new(path),
+    };
+    let unfinished_vms = journal.unfinished_vms();
+
+    let mut state = existing_state.unwrap_or_else(LabState::new);
+    state.instance = get_instance_name(options, &state)?;
+    let total_count = vms.len();
+    let vms: Vec<_> = vms.into_iter()
+        .filter(|(vm_path, _)| !state.vms.iter().any(|vm| path.join(&vm.folder) == *vm_path))
+        .collect();
+
+    let mut found = format!("Found {} VMs in lab", total_count);
+    if total_count > vms.len() {
+        found += &format!(", {} already deployed", total_count - vms.len());
+    }
+    if vms.is_empty() && unfinished_vms.is_empty() {
+        info!("{}. Nothing to deploy", found);
+        journal.commit()?;
+        return Ok(Vec::new());
     } else {
-        println!("");
+        info!("{}", found);
     }
 
-    for vm_path in &vm_paths {
-        import_vm(vm_path, &mut created_switches)?;
+    state.save(path)?;
+
+    let deployment = Deployment {
+        backend,
+        lab_path: path,
+        instance: state.instance.clone(),
+        manifest: manifest.as_ref(),
+        switch_notes: LabState::switch_notes(path)?,
+        state: Mutex::new(state),
+        journal: Mutex::new(journal),
+        switch_lock: Mutex::new(()),
+        // The extraction logs its progress while VMs are deployed
+        progress: Progress::new(options.jobs > 1 || extraction.is_some()),
+        extraction,
+    };
+
+    let mut imported_vms = Vec::new();
+    let mut result = Ok(());
+    for (folder, vm) in unfinished_vms {
+        let vm_path = path.join(&folder);
+        let vm_def = deployment.manifest.and_then(|m| m.vms.iter().find(|d| d.folder == folder));
+        info!("Finishing VM {}", vm.name);
+        result = set_up_vm(&deployment, &vm, vm_def);
+        if result.is_err() {
+            break;
+        }
+        imported_vms.push((vm_path, vm.id));
     }
 
-    let lama_config_folder_path = path.as_ref().join(".lama");
+    // VMs that share a boot order have no say in which comes up first, so only they are imported side by side
+    let mut i = 0;
+    while result.is_ok() && i < vms.len() {
+        let boot_order = vms[i].1.and_then(|d| d.boot_order);
+        let batch_len = vms[i..].iter().take_while(|(_, d)| d.and_then(|d| d.boot_order) == boot_order).count();
+        match import_vms_in_parallel(&deployment, &vms[i..i + batch_len], options.jobs) {
+            Ok(batch) => imported_vms.extend(batch),
+            Err(e) => result = Err(e),
+        }
+        i += batch_len;
+    }
 
-    if !lama_config_folder_path.is_dir() {
-        fs::create_dir_all(&lama_config_folder_path)?;
+    // The lab isn't deployed until the rest of its files are there too
+    if let (Ok(()), Some(extraction)) = (&result, extraction) {
+        result = extraction.wait_for_all();
     }
 
-    let mut switches_file = fs::File::create(lama_config_folder_path.join("switches.json"))?;
-    serde_json::to_writer(&mut switches_file, &created_switches)?;
+    let mut state = deployment.state.into_inner().unwrap();
+    let mut journal = deployment.journal.into_inner().unwrap();
+    if let Err(e) = result {
+        // VMs waiting for their files only learn that the extraction failed, and not why
+        let e = extraction.and_then(|x| x.take_error()).unwrap_or(e);
+        if options.no_rollback {
+            warn!("Deploy failed. Leaving partially deployed lab behind as asked. Deploy with --resume to continue it");
+        } else {
+            warn!("Deploy failed. Rolling back...");
+            let failures = roll_back_deploy(backend, path, &mut journal, &mut state)?;
+            if !failures.is_empty() {
+                return Err(LamaError::CleanupIncomplete { cause: Some(e), failures }.into());
+            }
+            return Err(LamaError::RolledBack { cause: e }.into());
+        }
+        return Err(e);
+    }
+
+    journal.commit()?;
+    info!("Lab deployed successfully");
+    Ok(imported_vms)
+}
+
+/// The VMs to deploy in the order to deploy them in, along with their definitions if the lab has a manifest
+fn get_lab_vms<'a>(lab_path: &Path, manifest: Option<&'a LabManifest>) -> Result<Vec<(PathBuf, Option<&'a VmDef>)>, Error> {
+    match manifest {
+        Some(manifest) => {
+            manifest.validate(lab_path)?;
+            info!("Using {}", MANIFEST_FILE_NAME);
+            Ok(manifest.vms_in_boot_order().into_iter().map(|vm| (lab_path.join(&vm.folder), Some(vm))).collect())
+        }
+        None => Ok(get_vm_paths(lab_path)?.into_iter().map(|p| (p, None)).collect()),
+    }
+}
+
+/// Adds what `import_lab` and the provisioning after it would do to the plan
+fn plan_deploy(backend: &dyn Backend, lab_path: &Path, existing_state: Option<LabState>, journal: Option<&Journal>, options: &DeployOptions, plan: &mut Plan) -> Result<(), Error> {
+    let manifest = LabManifest::load(lab_path)?;
+    let vms = get_lab_vms(lab_path, manifest.as_ref())?;
+    let state = existing_state.unwrap_or_else(LabState::new);
+    let instance = get_instance_name(options, &state)?;
+    let instance = instance.as_deref();
+    let mut switch_names: HashSet<String> = state.switches.iter().map(|s| s.name.clone()).collect();
+    let mut plan_connect = |vm: &str, adapter: &str, lab_switch_name: &str, plan: &mut Plan| {
+        let switch_name = prefixed(instance, lab_switch_name);
+        if switch_names.insert(switch_name.clone()) {
+            let switch_def = manifest.as_ref().and_then(|m| m.switch(lab_switch_name));
+            plan.push(Step::CreateSwitch {
+                name: switch_name.clone(),
+                kind: switch_def.map(|s| s.switch_type).unwrap_or_default(),
+                adapter: switch_def.and_then(|s| s.adapter.clone()),
+                host_ip: switch_def.and_then(|s| s.host_ip.clone()),
+            });
+        }
+        plan.push(Step::ConnectAdapter { vm: vm.to_owned(), adapter: adapter.to_owned(), switch: switch_name });
+    };
+
+    let mut deployed_vms = Vec::new();
+    for (folder, vm) in journal.map(|j| j.unfinished_vms()).unwrap_or_default() {
+        let vm_def = manifest.as_ref().and_then(|m| m.vms.iter().find(|d| d.folder == folder));
+        for (adapter_id, status) in &vm.adapter_status {
+            let switch_name = vm_def.and_then(|d| d.adapter(&status.adapter_name)).map(|a| &a.switch).unwrap_or(&status.name);
+            if !switch_name.is_empty() && !journal.map(|j| j.is_connected(&vm.id, adapter_id)).unwrap_or(false) {
+                plan_connect(&vm.name, &status.adapter_name, switch_name, plan);
+            }
+        }
+        plan.push(Step::StartVm { name: vm.name.clone() });
+        deployed_vms.push((lab_path.join(folder), vm.name));
+    }
+
+    for (vm_path, vm_def) in vms {
+        if state.vms.iter().any(|vm| lab_path.join(&vm.folder) == vm_path) {
+            continue;
+        }
+
+        let folder = lab_folder(lab_path, &vm_path)?;
+        let folder_name = vm_path.file_name().unwrap_or_default().to_string_lossy();
+        let renamed = match (instance, vm_def) {
+            (_, Some(vm_def)) => Some(prefixed(instance, &vm_def.name)),
+            (Some(_), None) => Some(prefixed(instance, &folder_name)),
+            (None, None) => None,
+        };
+        let name = renamed.clone().unwrap_or_else(|| folder_name.into_owned());
+        plan.push(Step::ImportVm { folder, name: renamed });
+        for adapter in vm_def.map(|d| d.adapters.as_slice()).unwrap_or_default() {
+            plan_connect(&name, &adapter.name, &adapter.switch, plan);
+        }
+        // The manifest's wiring wins over whatever the adapter was connected to on export, as in set_up_vm
+        let mut exported_adapters = backend.get_exported_vm_adapters(&vm_path)?;
+        exported_adapters.sort_by(|a, b| a.adapter_name.cmp(&b.adapter_name));
+        for adapter in &exported_adapters {
+            if !adapter.name.is_empty() && vm_def.and_then(|d| d.adapter(&adapter.adapter_name)).is_none() {
+                plan_connect(&name, &adapter.adapter_name, &adapter.name, plan);
+            }
+        }
+        plan.push(Step::StartVm { name: name.clone() });
+        deployed_vms.push((vm_path, name));
+    }
+
+    // Same order as get_provisioners
+    if !deployed_vms.is_empty() {
+        if let Some(manifest) = &manifest {
+            for provisioner in &manifest.provisioners {
+                plan.push(Step::RunProvisioner { path: lab_path.join(provisioner), vm: None });
+            }
+            for vm_def in &manifest.vms {
+                if let Some((_, name)) = deployed_vms.iter().find(|(p, _)| *p == lab_path.join(&vm_def.folder)) {
+                    for provisioner in &vm_def.provisioners {
+                        plan.push(Step::RunProvisioner { path: lab_path.join(provisioner), vm: Some(name.clone()) });
+                    }
+                }
+            }
+        }
+        if let Some(provisioner_path) = &options.provisioner_path {
+            plan.push(Step::RunProvisioner { path: provisioner_path.clone(), vm: None });
+        }
+    }
 
-    println!("Lab deployed successfully");
     Ok(())
 }
 
-fn run_provisioner<P: AsRef<Path>>(vm_ids: Vec<VmId>, provisioner_path: P) -> CliResult {
-    // TODO :this is how to implement this method:
-    // 1. For each VM get Get the ipv6 link-local address.
-    // Why ipv6 link-local address? Because a. it is guaranteed
-    // to be there (IPv6 standard requires them to be always present)
-    // and b. they are _almost_ guaranteed to be unique amongst all VMs
-    // on the host machine including those in other labs. The same
-    // can't be said for regular ipv4 addresess. And uniqueness is
-    // important because otherwise you might end up provisioning a
-    // completely wrong VM.
-    // 2. Add this address to the TrustedHosts setting of local WinRM
-    // This is because we'll be communicating with the VM over WinRM
-    // using this IP and as per WinRM requirements you can't communicate
-    // with an IP unless it's added to the TrustedHosts setting.
-    // Before we do this we'd also want to check that the WinRm service
-    // is running on the host machine because otherwise adding to the
-    // TrustedHosts setting is going to fail. If the service isn't there
-    // then fail with an error.
-    // 3. Now run the provision script given at the 'provisioner_path'.
-    // Pass this script the list of all VM objects in this lab and 
-    // their above-mentioned ipv6 link-local addresses. The script will
-    // most likely make a PsSession with the VM and do whatever it wants
-    // to do on it.
-    // See this PR to know how Vagrant people do something similar:
-    // https://github.com/hashicorp/vagrant/pull/4400/files
-    // except that they don't use ipv6 link-local addresses like us.
+/// The instance name to deploy the lab under: the one given with --name, or the one it was deployed under before
+fn get_instance_name(options: &DeployOptions, state: &LabState) -> Result<Option<String>, Error> {
+    let is_deployed = !state.vms.is_empty() || !state.switches.is_empty();
+    match &options.name {
+        Some(name) if is_deployed && state.instance.as_ref() != Some(name) => {
+            let deployed_as = state.instance.as_ref().map(|i| format!("as '{}'", i)).unwrap_or_else(|| "without --name".to_owned());
+            Err(LamaError::InvalidInput(format!("The lab is already deployed {}. Drop it first to deploy it as '{}'", deployed_as, name)))?
+        }
+        Some(name) => {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(LamaError::InvalidInput(format!("Invalid name '{}'. Only letters, digits, '-' and '_' are allowed", name)).into());
+            }
+            Ok(Some(name.clone()))
+        }
+        None => Ok(state.instance.clone()),
+    }
+}
+
+/// What a VM or switch of the lab is called on the host when the lab is deployed as `instance`
+fn prefixed(instance: Option<&str>, name: &str) -> String {
+    match instance {
+        Some(instance) => format!("{}_{}", instance, name), // Same as RenameAction::AddPrefix
+        None => name.to_owned(),
+    }
+}
+
+/// Imports the VMs using up to `jobs` workers. Once one of them fails no new ones are started,
+/// and the first failure is returned after the ones already under way are done.
+fn import_vms_in_parallel(deployment: &Deployment, vms: &[(PathBuf, Option<&VmDef>)], jobs: usize) -> Result<Vec<(PathBuf, VmId)>, Error> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let imported_vms = Mutex::new(Vec::new());
+    let errors = Mutex::new(Vec::new());
+
+    crossbeam_utils::thread::scope(|scope| {
+        for _ in 0..cmp::min(jobs, vms.len()) {
+            scope.spawn(|_| {
+                while !failed.load(Ordering::SeqCst) {
+                    let (vm_path, vm_def) = match vms.get(next.fetch_add(1, Ordering::SeqCst)) {
+                        Some(vm) => vm,
+                        None => break,
+                    };
+
+                    match import_vm(deployment, vm_path, *vm_def) {
+                        Ok(vm) => imported_vms.lock().unwrap().push((vm_path.to_owned(), vm.id)),
+                        Err(e) => {
+                            failed.store(true, Ordering::SeqCst);
+                            errors.lock().unwrap().push(e);
+                        }
+                    }
+                }
+            });
+        }
+    }).map_err(|_| LamaError::Internal("A VM import worker panicked".to_owned()))?;
+
+    match errors.into_inner().unwrap().into_iter().next() {
+        Some(e) => Err(e),
+        None => {
+            // Keep the lab's order no matter which worker finished first
+            let mut imported_vms = imported_vms.into_inner().unwrap();
+            imported_vms.sort_by_key(|(vm_path, _)| vms.iter().position(|(p, _)| p == vm_path));
+            Ok(imported_vms)
+        }
+    }
+}
+
+// Returns each provisioner to run along with the VMs it's run against
+fn get_provisioners(lab_path: &Path, vms: &[(PathBuf, VmId)], provisioner_path: Option<&Path>) -> Result<Vec<(PathBuf, Vec<VmId>)>, Error> {
+    let all_vm_ids: Vec<VmId> = vms.iter().map(|(_, vm_id)| *vm_id).collect();
+
+    // Lab-wide scripts from the manifest go first, then per-VM ones and finally the one given on the commandline
+    let mut provisioners: Vec<(PathBuf, Vec<VmId>)> = Vec::new();
+    if let Some(manifest) = LabManifest::load(lab_path)? {
+        for provisioner in &manifest.provisioners {
+            provisioners.push((lab_path.join(provisioner), all_vm_ids.clone()));
+        }
 
+        for vm_def in &manifest.vms {
+            let vm_path = lab_path.join(&vm_def.folder);
+            if let Some((_, vm_id)) = vms.iter().find(|(p, _)| *p == vm_path) {
+                for provisioner in &vm_def.provisioners {
+                    provisioners.push((lab_path.join(provisioner), vec![*vm_id]));
+                }
+            }
+        }
+    }
+
+    if let Some(provisioner_path) = provisioner_path {
+        provisioners.push((provisioner_path.to_owned(), all_vm_ids));
+    }
+
+    Ok(provisioners)
+}
+
+fn provision_lab(backend: &dyn Backend, vms: &[(PathBuf, VmId)], provisioners: &[(PathBuf, Vec<VmId>)]) -> Result<(), Error> {
+    let all_vm_ids: Vec<VmId> = vms.iter().map(|(_, vm_id)| *vm_id).collect();
+    let targets = get_provision_targets(backend, &all_vm_ids)?;
+    for (provisioner_path, vm_ids) in provisioners {
+        let provisioner_targets: Vec<ProvisionTarget> = vm_ids.iter().map(|vm_id| targets[vm_id].clone()).collect();
+        run_provisioner(backend, &provisioner_targets, provisioner_path)?;
+    }
+
+    info!("Lab provisioned successfully");
     Ok(())
 }
 
-fn get_vm_paths<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, ExitFailure> {
+// We talk to the VMs using their ipv6 link-local addresses. Why ipv6 link-local
+// addresses? Because a. they are guaranteed to be there (IPv6 standard requires
+// them to be always present) and b. they are _almost_ guaranteed to be unique
+// amongst all VMs on the host machine including those in other labs. The same
+// can't be said for regular ipv4 addresess. And uniqueness is important because
+// otherwise you might end up provisioning a completely wrong VM.
+// See this PR to know how Vagrant people do something similar:
+// https://github.com/hashicorp/vagrant/pull/4400/files
+// except that they don't use ipv6 link-local addresses like us.
+fn get_provision_targets(backend: &dyn Backend, vm_ids: &[VmId]) -> Result<HashMap<VmId, ProvisionTarget>, Error> {
+    let vm_names: HashMap<VmId, String> = backend.get_vms()?.into_iter().map(|vm| (vm.id, vm.name)).collect();
+    let progress = Progress::new(false);
+    let mut targets = HashMap::new();
+    for vm_id in vm_ids {
+        let name = vm_names.get(vm_id)
+            .ok_or(LamaError::VmNotDeployed { vm_id: *vm_id, vm_path: None })?
+            .to_owned();
+        let address = progress.step(&format!("==> {}: Getting link-local address", name), || wait_for_link_local_address(backend, vm_id), |a| a.clone())?;
+        targets.insert(*vm_id, ProvisionTarget { id: *vm_id, name, address });
+    }
+
+    // We'll be communicating with the VMs over WinRM which won't talk
+    // to an IP unless it's added to its TrustedHosts setting
+    let addresses: Vec<String> = targets.values().map(|t| t.address.clone()).collect();
+    progress.step("==> Adding VM addresses to WinRM trusted hosts", || backend.trust_hosts(&addresses), |_| "Done".to_owned())?;
+
+    Ok(targets)
+}
+
+// The address only shows up once the VM has booted far enough to report it
+fn wait_for_link_local_address(backend: &dyn Backend, vm_id: &VmId) -> Result<String, Error> {
+    const TIMEOUT: Duration = Duration::from_secs(300);
+    const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    let start = Instant::now();
+    loop {
+        if let Some(address) = backend.get_vm_link_local_address(vm_id)? {
+            return Ok(address);
+        }
+
+        if start.elapsed() >= TIMEOUT {
+            return Err(LamaError::LinkLocalAddressTimeout { vm_id: *vm_id }.into());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn run_provisioner<P: AsRef<Path>>(backend: &dyn Backend, targets: &[ProvisionTarget], provisioner_path: P) -> Result<(), Error> {
+    let provisioner_path = provisioner_path.as_ref();
+    info!("==> Running provisioner {}...", provisioner_path.display());
+    let output = backend.run_provisioner(provisioner_path, targets)?;
+    for line in output.lines() {
+        info!("    {}", line);
+    }
+
+    Ok(())
+}
+
+fn get_deployed_vms<P: AsRef<Path>>(lab_path: P, state: Option<&LabState>) -> Result<Vec<(PathBuf, VmId)>, Error> {
+    let lab_path = lab_path.as_ref();
+    if let Some(state) = state {
+        return Ok(state.vms.iter().map(|vm| (lab_path.join(&vm.folder), vm.id)).collect());
+    }
+
+    // Without any state all we can do is go by the IDs in the names of the .vmcx files
+    let mut vms = Vec::new();
+    for vm_path in get_vm_paths(lab_path)? {
+        if let Some(vm_id) = get_vm_id(&vm_path)? {
+            vms.push((vm_path, vm_id));
+        }
+    }
+
+    Ok(vms)
+}
+
+fn get_vm_paths<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>, Error> {
     let mut vm_paths = Vec::new();
     for entry in fs::read_dir(path)? {
         let vm_path = entry?.path();
-        if vm_path.is_dir() {
-            if has_vmcx_file(&vm_path)? {
-                 vm_paths.push(vm_path);
-            }
+        if vm_path.is_dir() && has_vmcx_file(&vm_path)? {
+            vm_paths.push(vm_path);
         }
     }
 
     Ok(vm_paths)
 }
 
-fn get_vm_id<P: AsRef<Path>>(path: P) -> Result<Option<VmId>, ExitFailure> {
+fn get_vm_id<P: AsRef<Path>>(path: P) -> Result<Option<VmId>, Error> {
     let vmcx_path = get_single_vmcx_file_path(path.as_ref())?;
     let vm_id = match vmcx_path.map(|p| p.file_stem().map(|p| p.to_str().map(|p| VmId::parse_str(p).ok()))) {
         Some(Some(Some(opt))) => opt,
@@ -240,15 +1151,15 @@ fn get_vm_id<P: AsRef<Path>>(path: P) -> Result<Option<VmId>, ExitFailure> {
     Ok(vm_id)
 }
 
-fn has_vmcx_file(vm_dir: &Path) -> Result<bool, ExitFailure> {
-    Ok(get_single_vmcx_file_path(&vm_dir)?.is_some())
+fn has_vmcx_file(vm_dir: &Path) -> Result<bool, Error> {
+    Ok(get_single_vmcx_file_path(vm_dir)?.is_some())
 }
 
 // Returns error or there are more than one vcmx files
-fn get_single_vmcx_file_path(vm_dir: &Path) -> Result<Option<PathBuf>, ExitFailure> {
+fn get_single_vmcx_file_path(vm_dir: &Path) -> Result<Option<PathBuf>, Error> {
     let mut paths = get_vmcx_file_paths(vm_dir)?;
     if paths.len() > 1 {
-        Err(LamaError::new("More than one .vmcx files found".to_owned()))?
+        Err(LamaError::InvalidLab(format!("More than one .vmcx files found in '{}'", vm_dir.display())))?
     } else if paths.len() == 1 {
         let path = paths.remove(0); // Must remove first because otherwise we upset the borrowck
         Ok(Some(path))
@@ -257,7 +1168,7 @@ fn get_single_vmcx_file_path(vm_dir: &Path) -> Result<Option<PathBuf>, ExitFailu
     }
 }
 
-fn get_vmcx_file_paths(vm_dir: &Path) -> Result<Vec<PathBuf>, ExitFailure> {
+fn get_vmcx_file_paths(vm_dir: &Path) -> Result<Vec<PathBuf>, Error> {
     // We don't check path is a directory. We just assume it is.
     let vm_config_dir = vm_dir.join("Virtual Machines");
     let mut vmcx_paths = Vec::new();
@@ -279,14 +1190,14 @@ fn get_vmcx_file_paths(vm_dir: &Path) -> Result<Vec<PathBuf>, ExitFailure> {
     Ok(vmcx_paths)
 }
 
-fn back_up_vm_config(vm_dir: &Path) -> Result<(), ExitFailure> {
+fn back_up_vm_config(vm_dir: &Path) -> Result<(), Error> {
     let lama_dir = vm_dir.join(".lama");
     let vm_config_dir = vm_dir.join("Virtual Machines");
     copy_dir_contents(&vm_config_dir, &lama_dir)?;
     Ok(())
 }
 
-fn restore_vm_config(vm_dir: &Path) -> Result<(), ExitFailure> {
+fn restore_vm_config(vm_dir: &Path) -> Result<(), Error> {
     let lama_dir = vm_dir.join(".lama");
     let vm_config_dir = vm_dir.join("Virtual Machines");
     copy_dir_contents(&lama_dir, &vm_config_dir)?;
@@ -294,27 +1205,35 @@ fn restore_vm_config(vm_dir: &Path) -> Result<(), ExitFailure> {
     Ok(())
 }
 
-fn copy_dir_contents(src_dir: &Path, dest_dir: &Path) -> Result<(), ExitFailure> {
+fn discard_vm_config_backup(vm_dir: &Path) -> Result<(), Error> {
+    let lama_dir = vm_dir.join(".lama");
+    if lama_dir.is_dir() {
+        fs::remove_dir_all(&lama_dir)?;
+    }
+    Ok(())
+}
+
+fn copy_dir_contents(src_dir: &Path, dest_dir: &Path) -> Result<(), Error> {
     if src_dir.is_dir() {
         if dest_dir.is_dir() {
-            remove_dir_contents(&dest_dir)?; // Just to remove any pre-existing junk
+            remove_dir_contents(dest_dir)?; // Just to remove any pre-existing junk
         } else {
-            fs::create_dir(&dest_dir)?;
+            fs::create_dir(dest_dir)?;
         }
 
         let mut src_paths = Vec::new();
-        for entry in fs::read_dir(&src_dir)? {
+        for entry in fs::read_dir(src_dir)? {
             src_paths.push(entry?.path());
         }
 
         let options = CopyOptions::new(); //Use default values for CopyOptions
-        copy_items(&src_paths, &dest_dir, &options)?;
+        copy_items(&src_paths, dest_dir, &options)?;
     }
 
     Ok(())
 }
 
-fn remove_dir_contents(dir: &Path) -> Result<(), ExitFailure> {
+fn remove_dir_contents(dir: &Path) -> Result<(), Error> {
     if dir.exists() {
         for entry in fs::read_dir(dir)? {
             let path = entry?.path();
@@ -329,42 +1248,198 @@ fn remove_dir_contents(dir: &Path) -> Result<(), ExitFailure> {
     Ok(())
 }
 
-fn import_vm<P: AsRef<Path>>(path: P, created_switches: &mut HashMap<String, Uuid>) -> Result<ImportedVm, ExitFailure> {
+/// Returns what couldn't be undone, which is left in the state and the journal so that dropping the lab can try again
+fn roll_back_deploy(backend: &dyn Backend, lab_path: &Path, journal: &mut Journal, state: &mut LabState) -> Result<Vec<String>, Error> {
+    let failures = journal.roll_back(backend, state)?;
+    if state.vms.is_empty() && state.switches.is_empty() {
+        LabState::remove(lab_path)?;
+    } else {
+        state.save(lab_path)?;
+    }
+
+    if failures.is_empty() {
+        Journal::remove(lab_path)?;
+        info!("Rolled back successfully");
+    }
+
+    Ok(failures)
+}
+
+/// What the workers importing the VMs of a lab share
+struct Deployment<'a> {
+    backend: &'a dyn Backend,
+    lab_path: &'a Path,
+    /// Prefix of the names of the lab's VMs and switches, from `deploy --name`
+    instance: Option<String>,
+    manifest: Option<&'a LabManifest>,
+    /// What the switches created for the lab are tagged with, from `LabState::switch_notes`
+    switch_notes: String,
+    state: Mutex<LabState>,
+    journal: Mutex<Journal>,
+    /// Held while looking for a switch and creating it if it's not there, so that two VMs never both create it
+    switch_lock: Mutex<()>,
+    progress: Progress,
+    extraction: Option<&'a Extraction>,
+}
+
+impl<'a> Deployment<'a> {
+    fn record(&self, entry: JournalEntry) -> Result<(), Error> {
+        self.journal.lock().unwrap().record(entry)
+    }
+
+    fn update_state<F: FnOnce(&mut LabState)>(&self, f: F) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        state.save(self.lab_path)
+    }
+}
+
+fn import_vm<P: AsRef<Path>>(deployment: &Deployment, path: P, vm_def: Option<&VmDef>) -> Result<ImportedVm, Error> {
     let path = path.as_ref();
+    let folder = lab_folder(deployment.lab_path, path)?;
+    if let Some(extraction) = deployment.extraction {
+        extraction.wait_for_folder(&folder)?;
+    }
     let vm_folder_name = path.file_name()
-        .ok_or_else(|| LamaError::new("Bad VM folder name"))?
+        .ok_or_else(|| LamaError::InvalidLab(format!("Bad VM folder name '{}'", path.display())))?
         .to_str()
-        .ok_or_else(|| LamaError::new("Couldn't convert VM folder name to str"))?;
+        .ok_or_else(|| LamaError::InvalidLab(format!("Couldn't convert VM folder name '{}' to str", path.display())))?;
  
-    print!("Importing VM {}... ", vm_folder_name);
-    let vm = Hyperv::import_vm_inplace_new_id(&path, None)?;
-    println!("Done (ID: {})", vm.id);
+    let vm = deployment.progress.step(&format!("Importing VM {}", vm_folder_name), || -> Result<ImportedVm, Error> {
+        // Importing in place replaces the VM's config files. Keep the originals so that a rollback can put them back.
+        back_up_vm_config(path)?;
+        deployment.record(JournalEntry::BackedUpVmConfig { folder: folder.clone() })?;
+        deployment.record(JournalEntry::ImportingVm { folder: folder.clone(), exported_id: get_vm_id(path)? })?;
+        let instance = deployment.instance.as_deref();
+        let rename_action = match (instance, vm_def) {
+            (_, Some(vm_def)) => Some(RenameAction::NewName(prefixed(instance, &vm_def.name))),
+            (Some(instance), None) => Some(RenameAction::AddPrefix(instance.to_owned())),
+            (None, None) => None,
+        };
+        Ok(deployment.backend.import_vm_inplace_new_id(path, rename_action)?)
+    }, |vm| format!("Done (ID: {})", vm.id))?;
+    deployment.record(JournalEntry::ImportedVm {
+        id: vm.id,
+        name: vm.name.clone(),
+        folder: folder.clone(),
+        adapters: vm.adapter_status.clone(),
+    })?;
+    deployment.update_state(|state| state.vms.push(VmState { id: vm.id, name: vm.name.clone(), folder, adapters: Vec::new() }))?;
+
+    set_up_vm(deployment, &vm, vm_def)?;
+    Ok(vm)
+}
+
+/// The folder of a VM relative to the lab root, which is how the journal and the state file record it
+fn lab_folder(lab_path: &Path, vm_path: &Path) -> Result<PathBuf, Error> {
+    vm_path.strip_prefix(lab_path)
+        .map(|folder| folder.to_owned())
+        .map_err(|_| LamaError::InvalidLab(format!("VM folder '{}' is not inside the lab", vm_path.display())).into())
+}
+
+/// Connects the adapters of an imported VM and starts it, skipping whatever the journal says is already done
+fn set_up_vm(deployment: &Deployment, vm: &ImportedVm, vm_def: Option<&VmDef>) -> Result<(), Error> {
+    let backend = deployment.backend;
+    if let Some(vm_def) = vm_def {
+        for adapter in &vm_def.adapters {
+            if !vm.adapter_status.values().any(|s| s.adapter_name == adapter.name) {
+                return Err(LamaError::InvalidLab(format!("VM '{}' has no adapter named '{}'", vm_def.name, adapter.name)).into());
+            }
+        }
+    }
+
     for s in &vm.adapter_status {
         let adapter_id = s.0;
-        let switch_name = &s.1.name;
-        let switch_id = if !created_switches.contains_key(switch_name) {
-            print!("==> {}: Creating switch '{}'... ", vm.name, switch_name);
-            let switch_id = Hyperv::create_switch(switch_name, &SwitchType::Private)?;
-            println!("Done (ID: {})", switch_id);
-            created_switches.insert(switch_name.to_owned(), switch_id);
-            switch_id
-        } else {
-            created_switches[switch_name]
+        // The manifest's wiring wins over whatever the adapter was connected to on export
+        let lab_switch_name = match vm_def.and_then(|d| d.adapter(&s.1.adapter_name)) {
+            Some(adapter) => &adapter.switch,
+            None => &s.1.name,
+        };
+        let switch_name = &prefixed(deployment.instance.as_deref(), lab_switch_name);
+        if lab_switch_name.is_empty() || deployment.journal.lock().unwrap().is_connected(&vm.id, adapter_id) {
+            continue; // Adapter isn't connected to anything or was connected before the deploy was interrupted
+        }
+
+        let switch_id = {
+            let _switch_lock = deployment.switch_lock.lock().unwrap();
+            let existing_switch_id = deployment.state.lock().unwrap().switch(switch_name).map(|s| s.id);
+            match existing_switch_id {
+                Some(switch_id) => switch_id,
+                None => {
+                    let switch_def = deployment.manifest.and_then(|m| m.switch(lab_switch_name));
+                    let switch_state = |id, adapter| SwitchState {
+                        id,
+                        name: switch_name.to_owned(),
+                        switch_type: switch_def.map(|s| s.switch_type).unwrap_or_default(),
+                        adapter,
+                        host_ip: switch_def.and_then(|s| s.host_ip.clone()),
+                    };
+                    match backend.get_switches()?.into_iter().find(|s| s.name == *switch_name) {
+                        // Created for this lab by a deploy whose state file has since been lost
+                        Some(switch) if switch.notes == deployment.switch_notes => {
+                            info!("==> {}: Taking back switch '{}' (ID: {}) created for the lab before", vm.name, switch_name, switch.id);
+                            deployment.update_state(|state| state.switches.push(switch_state(switch.id, switch.net_adapter_name.clone())))?;
+                            switch.id
+                        }
+                        Some(switch) if LabState::is_lama_switch(&switch.notes) => {
+                            return Err(LamaError::SwitchNameTaken { name: switch_name.to_owned() }.into());
+                        }
+                        // An external switch set up by hand, which the lab is meant to reach the outside through.
+                        // It's used as it is and never deleted.
+                        Some(switch) if switch.switch_type == SwitchKind::External && switch_def.map_or(true, |d| d.switch_type == SwitchKind::External) => {
+                            info!("==> {}: Using existing external switch '{}' (ID: {})", vm.name, switch_name, switch.id);
+                            switch.id
+                        }
+                        // Anything else would join the lab to whatever the switch is there for
+                        Some(switch) => {
+                            return Err(LamaError::SwitchExists { name: switch_name.to_owned(), switch_type: switch.switch_type }.into());
+                        }
+                        None => {
+                            let switch_type = switch_def.map(|s| s.switch_type()).unwrap_or(SwitchType::Private);
+                            let switch_id = deployment.progress.step(
+                                &format!("==> {}: Creating switch '{}'", vm.name, switch_name),
+                                || backend.create_switch(switch_name, &switch_type, &deployment.switch_notes),
+                                |switch_id| format!("Done (ID: {})", switch_id))?;
+                            deployment.record(JournalEntry::CreatedSwitch { id: switch_id, name: switch_name.to_owned() })?;
+                            let adapter = match switch_type {
+                                // Find out which adapter was picked as the default uplink
+                                SwitchType::External(None) => {
+                                    let adapter = backend.get_switches()?.into_iter().find(|s| s.id == switch_id).and_then(|s| s.net_adapter_name);
+                                    if let Some(adapter) = &adapter {
+                                        info!("==> {}: Switch '{}' is bound to host adapter '{}'", vm.name, switch_name, adapter);
+                                    }
+                                    adapter
+                                }
+                                _ => switch_def.and_then(|s| s.adapter.clone()),
+                            };
+                            deployment.update_state(|state| state.switches.push(switch_state(switch_id, adapter)))?;
+                            switch_id
+                        }
+                    }
+                }
+            }
         };
 
-        print!("==> {}: Connecting to switch '{}'... ", vm.name, switch_name);
-        Hyperv::connect_adapter(&vm.id, &adapter_id, &switch_id.to_hyphenated().to_string())?;
-        println!("Done");
+        deployment.progress.step(
+            &format!("==> {}: Connecting to switch '{}'", vm.name, switch_name),
+            || backend.connect_adapter(&vm.id, adapter_id, &switch_id.to_hyphenated().to_string()),
+            |_| "Done".to_owned())?;
+        deployment.update_state(|state| {
+            if let Some(vm_state) = state.vm_mut(&vm.id) {
+                vm_state.adapters.retain(|a| a.id != *adapter_id);
+                vm_state.adapters.push(AdapterState { id: adapter_id.to_owned(), name: s.1.adapter_name.clone(), switch: switch_name.to_owned() });
+            }
+        })?;
+        deployment.record(JournalEntry::ConnectedAdapter { vm_id: vm.id, adapter_id: adapter_id.to_owned() })?;
     }
 
-    print!("==> {}: Starting VM... ", vm.name);
-    Hyperv::start_vm(&vm.id)?;
-    println!("Done");
+    deployment.progress.step(&format!("==> {}: Starting VM", vm.name), || backend.start_vm(&vm.id), |_| "Done".to_owned())?;
+    deployment.record(JournalEntry::StartedVm { id: vm.id, name: vm.name.clone() })?;
 
-    Ok(vm)
+    Ok(())
 }
 
-fn is_remote_path(path: &Path) -> Result<bool, ExitFailure> {
+fn is_remote_path(path: &Path) -> Result<bool, Error> {
     let res = match path.components().next() {
         Some(Component::Prefix(prefix_component)) => match prefix_component.kind() {
             Prefix::UNC(_, _) | Prefix::VerbatimUNC(_, _) => true, // TODO: also cater for network paths that point to localhost or 127.0.0.x
@@ -376,26 +1451,18 @@ fn is_remote_path(path: &Path) -> Result<bool, ExitFailure> {
     Ok(res)
 }
 
-pub fn prompt_user(prompt: &str) -> Result<String, ExitFailure> {
+/// Prompts the user, unless told not to or stdin isn't a terminal to answer from, in which case it returns `None`
+fn ask(prompt: &str, options: &DeployOptions) -> Result<Option<String>, Error> {
+    if options.non_interactive || !atty::is(atty::Stream::Stdin) {
+        return Ok(None);
+    }
+    prompt_user(prompt).map(Some)
+}
+
+pub fn prompt_user(prompt: &str) -> Result<String, Error> {
     print!("{}", prompt);
-    let _= stdout().flush()?;
+    stdout().flush()?;
     let mut input = String::new();
     stdin().read_line(&mut input)?;
     Ok(input.trim().to_owned())
-}
-
-
-#[derive(Debug, Fail)]
-pub struct LamaError(String);
-
-impl LamaError {
-    fn new<T: Into<String>>(msg: T) -> Self {
-        Self(msg.into())
-    }
-}
-
-impl fmt::Display for LamaError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
 }
\ No newline at end of file
@@ -0,0 +1,486 @@
+use crate::error::LamaError;
+use crate::{has_vmcx_file};
+use crate::backend::{SwitchKind, SwitchType};
+use serde_derive::{Serialize, Deserialize};
+use failure::Error;
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+
+pub const MANIFEST_FILE_NAME: &str = "lab.toml";
+
+/// Explicit description of a lab, read from `lab.toml` at the lab root.
+/// When it's absent the lab is whatever VM folders `get_vm_paths` finds.
+// Field order matters for serialization: TOML needs plain values before arrays of tables
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LabManifest {
+    /// Scripts run against every VM of the lab. Paths are relative to the lab root.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provisioners: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub switches: Vec<SwitchDef>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vms: Vec<VmDef>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SwitchDef {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub switch_type: SwitchKind,
+    /// Name of the host network adapter an external switch is bound to.
+    /// Without one it's bound to the adapter the host's default route goes through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    /// Address given to the host's adapter on an internal switch, in CIDR notation (e.g. "192.168.50.1/24")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_ip: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmDef {
+    pub name: String,
+    /// Folder of the exported VM, relative to the lab root
+    pub folder: PathBuf,
+    /// VMs are imported and started in ascending boot order. VMs without one come last.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_order: Option<u32>,
+    /// Scripts run against this VM only, after the lab-wide ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provisioners: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub adapters: Vec<AdapterDef>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdapterDef {
+    /// Name of the VM's network adapter as shown by Hyper-V (e.g. "Network Adapter")
+    pub name: String,
+    /// Name of the switch declared in `switches` that the adapter connects to
+    pub switch: String,
+}
+
+impl LabManifest {
+    /// Returns `None` if the lab has no manifest
+    pub fn load<P: AsRef<Path>>(lab_path: P) -> Result<Option<LabManifest>, Error> {
+        let manifest_path = lab_path.as_ref().join(MANIFEST_FILE_NAME);
+        if !manifest_path.is_file() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&manifest_path)?;
+        let manifest: LabManifest = toml::from_str(&contents)
+            .map_err(|e| LamaError::BadFile { path: manifest_path.clone(), msg: e.to_string() })?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, lab_path: P) -> Result<(), Error> {
+        let contents = toml::to_string(self)
+            .map_err(|e| LamaError::Internal(format!("Failed to serialize {}: {}", MANIFEST_FILE_NAME, e)))?;
+        fs::write(lab_path.as_ref().join(MANIFEST_FILE_NAME), contents)?;
+        Ok(())
+    }
+
+    /// Checks the manifest is self-consistent and matches what's on disk
+    pub fn validate<P: AsRef<Path>>(&self, lab_path: P) -> Result<(), Error> {
+        let lab_path = lab_path.as_ref();
+        let mut errors = Vec::new();
+
+        let mut switch_names = HashSet::new();
+        for switch in &self.switches {
+            if switch.name.is_empty() {
+                errors.push("Switch with empty name".to_owned());
+            } else if !switch_names.insert(switch.name.as_str()) {
+                errors.push(format!("Switch '{}' is declared more than once", switch.name));
+            }
+
+            if switch.adapter.is_some() && switch.switch_type != SwitchKind::External {
+                errors.push(format!("Switch '{}' has an adapter but is not external", switch.name));
+            }
+
+            if let Some(host_ip) = &switch.host_ip {
+                if switch.switch_type != SwitchKind::Internal {
+                    errors.push(format!("Switch '{}' has a host IP but is not internal", switch.name));
+                } else if parse_cidr(host_ip).is_none() {
+                    errors.push(format!("Host IP '{}' of switch '{}' is not an address with a prefix length, e.g. 192.168.50.1/24", host_ip, switch.name));
+                }
+            }
+        }
+
+        if self.vms.is_empty() {
+            errors.push("No VMs declared".to_owned());
+        }
+
+        let mut vm_names = HashSet::new();
+        let mut vm_folders = HashSet::new();
+        for vm in &self.vms {
+            if !vm_names.insert(vm.name.as_str()) {
+                errors.push(format!("VM '{}' is declared more than once", vm.name));
+            }
+
+            if !vm_folders.insert(&vm.folder) {
+                errors.push(format!("Folder '{}' is used by more than one VM", vm.folder.display()));
+            }
+
+            let vm_path = lab_path.join(&vm.folder);
+            if !vm.folder.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+                errors.push(format!("Folder '{}' of VM '{}' is not inside the lab", vm.folder.display(), vm.name));
+            } else if !vm_path.is_dir() {
+                errors.push(format!("Folder '{}' of VM '{}' does not exist", vm.folder.display(), vm.name));
+            } else if !has_vmcx_file(&vm_path)? {
+                errors.push(format!("Folder '{}' of VM '{}' has no .vmcx file", vm.folder.display(), vm.name));
+            }
+
+            let mut adapter_names = HashSet::new();
+            for adapter in &vm.adapters {
+                if !adapter_names.insert(adapter.name.as_str()) {
+                    errors.push(format!("Adapter '{}' of VM '{}' is declared more than once", adapter.name, vm.name));
+                }
+
+                if !switch_names.contains(adapter.switch.as_str()) {
+                    errors.push(format!("Adapter '{}' of VM '{}' connects to undeclared switch '{}'", adapter.name, vm.name, adapter.switch));
+                }
+            }
+
+            for provisioner in &vm.provisioners {
+                if !lab_path.join(provisioner).is_file() {
+                    errors.push(format!("Provisioner '{}' of VM '{}' does not exist", provisioner.display(), vm.name));
+                }
+            }
+        }
+
+        for provisioner in &self.provisioners {
+            if !lab_path.join(provisioner).is_file() {
+                errors.push(format!("Provisioner '{}' does not exist", provisioner.display()));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(LamaError::InvalidLab(format!("Invalid {}:\n  {}", MANIFEST_FILE_NAME, errors.join("\n  "))))?
+        }
+    }
+
+    pub fn switch(&self, name: &str) -> Option<&SwitchDef> {
+        self.switches.iter().find(|s| s.name == name)
+    }
+
+    pub fn vms_in_boot_order(&self) -> Vec<&VmDef> {
+        let mut vms: Vec<&VmDef> = self.vms.iter().collect();
+        vms.sort_by_key(|vm| vm.boot_order.unwrap_or(u32::MAX)); // Stable, so declaration order breaks ties
+        vms
+    }
+}
+
+impl VmDef {
+    pub fn adapter(&self, name: &str) -> Option<&AdapterDef> {
+        self.adapters.iter().find(|a| a.name == name)
+    }
+}
+
+impl SwitchDef {
+    pub fn switch_type(&self) -> SwitchType<&str> {
+        match self.switch_type {
+            SwitchKind::Private => SwitchType::Private,
+            SwitchKind::Internal => SwitchType::Internal(self.host_ip.as_deref()),
+            SwitchKind::External => SwitchType::External(self.adapter.as_deref()),
+        }
+    }
+}
+
+/// Splits an address in CIDR notation into the address and the prefix length, if it's valid
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = cidr.splitn(2, '/');
+    let address: IpAddr = parts.next()?.parse().ok()?;
+    let prefix_length: u8 = parts.next()?.parse().ok()?;
+    let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+    if prefix_length > max_prefix_length {
+        return None;
+    }
+    Some((address, prefix_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lab_with_files;
+    use tempfile::TempDir;
+
+    /// A lab with an empty `.vmcx` in each of the given VM folders
+    fn make_lab(folders: &[&str]) -> TempDir {
+        let files: Vec<(String, &str)> = folders.iter().map(|folder| (format!("{}/Virtual Machines/exported.vmcx", folder), "")).collect();
+        lab_with_files(&files)
+    }
+
+    fn parse(toml: &str) -> LabManifest {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// The validation errors of the manifest, one per line
+    fn errors(lab: &TempDir, toml: &str) -> Vec<String> {
+        let error = parse(toml).validate(lab.path()).unwrap_err();
+        match error.downcast::<LamaError>().unwrap() {
+            LamaError::InvalidLab(msg) => msg.lines().skip(1).map(|l| l.trim().to_owned()).collect(),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn valid_manifest_passes() {
+        let lab = make_lab(&["dc01", "web01"]);
+        fs::write(lab.path().join("setup.ps1"), "").unwrap();
+        parse(r#"
+            provisioners = ["setup.ps1"]
+
+            [[switches]]
+            name = "lan"
+            type = "internal"
+            host_ip = "192.168.50.1/24"
+
+            [[switches]]
+            name = "wan"
+            type = "external"
+            adapter = "Ethernet"
+
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+
+            [[vms.adapters]]
+            name = "Network Adapter"
+            switch = "lan"
+
+            [[vms]]
+            name = "web01"
+            folder = "web01"
+        "#).validate(lab.path()).unwrap();
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let lab = make_lab(&["dc01"]);
+        fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+            memory = 4096
+        "#).unwrap();
+        let error = LabManifest::load(lab.path()).unwrap_err();
+        assert!(matches!(error.downcast::<LamaError>().unwrap(), LamaError::BadFile { .. }));
+    }
+
+    #[test]
+    fn adapters_on_undeclared_switches_are_rejected() {
+        let lab = make_lab(&["dc01"]);
+        assert_eq!(errors(&lab, r#"
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+
+            [[vms.adapters]]
+            name = "Network Adapter"
+            switch = "lan"
+        "#), vec!["Adapter 'Network Adapter' of VM 'dc01' connects to undeclared switch 'lan'"]);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let lab = make_lab(&["dc01", "dc02"]);
+        assert_eq!(errors(&lab, r#"
+            [[switches]]
+            name = "lan"
+
+            [[switches]]
+            name = "lan"
+
+            [[vms]]
+            name = "dc"
+            folder = "dc01"
+
+            [[vms.adapters]]
+            name = "Network Adapter"
+            switch = "lan"
+
+            [[vms.adapters]]
+            name = "Network Adapter"
+            switch = "lan"
+
+            [[vms]]
+            name = "dc"
+            folder = "dc01"
+
+            [[vms]]
+            name = "dc02"
+            folder = "dc02"
+        "#), vec![
+            "Switch 'lan' is declared more than once",
+            "Adapter 'Network Adapter' of VM 'dc' is declared more than once",
+            "VM 'dc' is declared more than once",
+            "Folder 'dc01' is used by more than one VM",
+        ]);
+    }
+
+    #[test]
+    fn bad_host_ips_are_rejected() {
+        let lab = make_lab(&["dc01"]);
+        for host_ip in &["192.168.50.1", "192.168.50.1/33", "192.168.50/24", "fe80::1/129", "lan/24", ""] {
+            let toml = format!(r#"
+                [[switches]]
+                name = "lan"
+                type = "internal"
+                host_ip = "{}"
+
+                [[vms]]
+                name = "dc01"
+                folder = "dc01"
+            "#, host_ip);
+            assert_eq!(errors(&lab, &toml).len(), 1, "{}", host_ip);
+        }
+    }
+
+    #[test]
+    fn switch_settings_must_match_the_type() {
+        let lab = make_lab(&["dc01"]);
+        assert_eq!(errors(&lab, r#"
+            [[switches]]
+            name = "lan"
+            adapter = "Ethernet"
+            host_ip = "192.168.50.1/24"
+
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+        "#), vec![
+            "Switch 'lan' has an adapter but is not external",
+            "Switch 'lan' has a host IP but is not internal",
+        ]);
+    }
+
+    #[test]
+    fn missing_folders_and_files_are_rejected() {
+        let lab = make_lab(&["dc01"]);
+        fs::create_dir(lab.path().join("empty")).unwrap();
+        assert_eq!(errors(&lab, r#"
+            provisioners = ["setup.ps1"]
+
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+            provisioners = ["dc.ps1"]
+
+            [[vms]]
+            name = "web01"
+            folder = "web01"
+
+            [[vms]]
+            name = "client01"
+            folder = "empty"
+        "#), vec![
+            "Provisioner 'dc.ps1' of VM 'dc01' does not exist",
+            "Folder 'web01' of VM 'web01' does not exist",
+            "Folder 'empty' of VM 'client01' has no .vmcx file",
+            "Provisioner 'setup.ps1' does not exist",
+        ]);
+    }
+
+    #[test]
+    fn folders_outside_the_lab_are_rejected() {
+        let lab = make_lab(&["dc01", "web01"]);
+        let absolute = lab.path().join("dc01");
+        assert_eq!(errors(&lab, &format!(r#"
+            [[vms]]
+            name = "dc01"
+            folder = '{}'
+
+            [[vms]]
+            name = "web01"
+            folder = "../web01"
+
+            [[vms]]
+            name = "client01"
+            folder = "web01/../dc01"
+        "#, absolute.display())), vec![
+            format!("Folder '{}' of VM 'dc01' is not inside the lab", absolute.display()),
+            "Folder '../web01' of VM 'web01' is not inside the lab".to_owned(),
+            "Folder 'web01/../dc01' of VM 'client01' is not inside the lab".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn a_lab_needs_vms() {
+        let lab = make_lab(&[]);
+        assert_eq!(errors(&lab, ""), vec!["No VMs declared"]);
+    }
+
+    #[test]
+    fn vms_are_sorted_by_boot_order_then_declaration() {
+        let manifest = parse(r#"
+            [[vms]]
+            name = "a"
+            folder = "a"
+
+            [[vms]]
+            name = "b"
+            folder = "b"
+            boot_order = 2
+
+            [[vms]]
+            name = "c"
+            folder = "c"
+
+            [[vms]]
+            name = "d"
+            folder = "d"
+            boot_order = 1
+
+            [[vms]]
+            name = "e"
+            folder = "e"
+            boot_order = 2
+        "#);
+        let names: Vec<&str> = manifest.vms_in_boot_order().iter().map(|vm| vm.name.as_str()).collect();
+        assert_eq!(names, vec!["d", "b", "e", "a", "c"]);
+    }
+
+    #[test]
+    fn cidrs_are_parsed() {
+        assert_eq!(parse_cidr("192.168.50.1/24"), Some(("192.168.50.1".parse().unwrap(), 24)));
+        assert_eq!(parse_cidr("fd00::1/64"), Some(("fd00::1".parse().unwrap(), 64)));
+        assert_eq!(parse_cidr("10.0.0.1/32").map(|(_, p)| p), Some(32));
+        assert_eq!(parse_cidr("10.0.0.1/33"), None);
+        assert_eq!(parse_cidr("10.0.0.1"), None);
+        assert_eq!(parse_cidr("10.0.0.1/"), None);
+        assert_eq!(parse_cidr("10.0.0.1/-1"), None);
+    }
+
+    #[test]
+    fn saved_manifest_loads_back() {
+        let lab = make_lab(&["dc01"]);
+        let manifest = parse(r#"
+            provisioners = ["setup.ps1"]
+
+            [[switches]]
+            name = "lan"
+            type = "internal"
+            host_ip = "192.168.50.1/24"
+
+            [[vms]]
+            name = "dc01"
+            folder = "dc01"
+            boot_order = 1
+
+            [[vms.adapters]]
+            name = "Network Adapter"
+            switch = "lan"
+        "#);
+        manifest.save(lab.path()).unwrap();
+        let loaded = LabManifest::load(lab.path()).unwrap().unwrap();
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", manifest));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::backend::{SwitchKind, VmId};
+use log::info;
+use uuid::Uuid;
+use std::fmt;
+use std::path::PathBuf;
+
+/// What a deploy or drop would do, worked out for `--dry-run`. The hypervisor is only ever queried for it, never changed.
+pub struct Plan {
+    command: &'static str,
+    steps: Vec<Step>,
+}
+
+pub enum Step {
+    DownloadLab { url: String, to: PathBuf },
+    CopyLab { from: PathBuf, to: PathBuf },
+    ExtractBundle { from: PathBuf, to: PathBuf },
+    RestoreVmConfig { folder: PathBuf },
+    StopVm { name: String, id: VmId },
+    DeleteVm { name: String, id: VmId },
+    DeleteSwitch { name: String, id: Uuid },
+    /// A switch of the lab that would be left behind, and why
+    KeepSwitch { name: String, id: Uuid, reason: String },
+    ImportVm { folder: PathBuf, name: Option<String> },
+    /// `adapter` and `host_ip` are as in the lab's `SwitchDef`
+    CreateSwitch { name: String, kind: SwitchKind, adapter: Option<String>, host_ip: Option<String> },
+    ConnectAdapter { vm: String, adapter: String, switch: String },
+    StartVm { name: String },
+    /// `vm` is `None` for provisioners run against every VM of the lab
+    RunProvisioner { path: PathBuf, vm: Option<String> },
+}
+
+impl Plan {
+    pub fn new(command: &'static str) -> Self {
+        Self { command, steps: Vec::new() }
+    }
+
+    pub fn push(&mut self, step: Step) {
+        self.steps.push(step);
+    }
+
+    #[cfg(test)]
+    pub fn lines(&self) -> Vec<String> {
+        self.steps.iter().map(|step| step.to_string()).collect()
+    }
+
+    pub fn print(&self) {
+        if self.steps.is_empty() {
+            info!("Dry run: {} would do nothing", self.command);
+            return;
+        }
+
+        info!("Dry run: nothing has been changed. {} would:", self.command);
+        for (i, step) in self.steps.iter().enumerate() {
+            info!("{:>4}. {}", i + 1, step);
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::DownloadLab { url, to } => write!(f, "Download the lab from '{}' to '{}'", url, to.display()),
+            Step::CopyLab { from, to } => write!(f, "Copy the lab from '{}' to '{}'", from.display(), to.display()),
+            Step::ExtractBundle { from, to } => write!(f, "Extract the lab from '{}' to '{}'", from.display(), to.display()),
+            Step::RestoreVmConfig { folder } => write!(f, "Restore the original config files of {}", folder.display()),
+            Step::StopVm { name, id } => write!(f, "Stop VM {} (ID: {})", name, id),
+            Step::DeleteVm { name, id } => write!(f, "Delete VM {} (ID: {})", name, id),
+            Step::DeleteSwitch { name, id } => write!(f, "Delete switch '{}' (ID: {})", name, id),
+            Step::KeepSwitch { name, id, reason } => write!(f, "Keep switch '{}' (ID: {}): {}", name, id, reason),
+            Step::ImportVm { folder, name: Some(name) } => write!(f, "Import VM {} as '{}'", folder.display(), name),
+            Step::ImportVm { folder, name: None } => write!(f, "Import VM {}", folder.display()),
+            Step::CreateSwitch { name, kind, adapter, host_ip } => {
+                write!(f, "Create {} switch '{}'", format!("{:?}", kind).to_lowercase(), name)?;
+                match (kind, adapter, host_ip) {
+                    (SwitchKind::External, Some(adapter), _) => write!(f, " bound to host adapter '{}'", adapter),
+                    (SwitchKind::External, None, _) => write!(f, " bound to the host adapter of the default route"),
+                    (SwitchKind::Internal, _, Some(host_ip)) => write!(f, " with host address {}", host_ip),
+                    _ => Ok(()),
+                }
+            }
+            Step::ConnectAdapter { vm, adapter, switch } => write!(f, "Connect adapter '{}' of VM {} to switch '{}'", adapter, vm, switch),
+            Step::StartVm { name } => write!(f, "Start VM {}", name),
+            Step::RunProvisioner { path, vm: Some(vm) } => write!(f, "Run provisioner '{}' against VM {}", path.display(), vm),
+            Step::RunProvisioner { path, vm: None } => write!(f, "Run provisioner '{}' against every VM of the lab", path.display()),
+        }
+    }
+}
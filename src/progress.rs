@@ -0,0 +1,41 @@
+use crate::logging::{self, FILE_ONLY};
+use log::{info, LevelFilter};
+use std::io::{stdout, Write};
+
+/// Prints the steps of a deploy. On a plain console a step's message is shown as soon as it starts and its
+/// outcome is added to the same line when it's over. When VMs are deployed in parallel, or more verbose
+/// output could get in between, each step is only logged once it's over, as a single line.
+pub struct Progress {
+    inline: bool,
+}
+
+impl Progress {
+    pub fn new(parallel: bool) -> Self {
+        Self { inline: !parallel && logging::console_level() == LevelFilter::Info }
+    }
+
+    /// Runs `f`, printing `msg` followed by whatever `done` makes of the result, or by "Failed"
+    pub fn step<T, E, F, D>(&self, msg: &str, f: F, done: D) -> Result<T, E>
+        where F: FnOnce() -> Result<T, E>,
+              D: FnOnce(&T) -> String
+    {
+        if self.inline {
+            print!("{}... ", msg);
+            let _ = stdout().flush();
+        }
+
+        let result = f();
+        let outcome = match &result {
+            Ok(value) => done(value),
+            Err(_) => "Failed".to_owned(),
+        };
+
+        if self.inline {
+            println!("{}", outcome);
+            info!(target: FILE_ONLY, "{}... {}", msg, outcome);
+        } else {
+            info!("{}... {}", msg, outcome);
+        }
+        result
+    }
+}
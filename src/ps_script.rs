@@ -0,0 +1,165 @@
+use std::fmt;
+
+/// A PowerShell script for `PsSession::run`. Values such as names and paths never become part of its code:
+/// they are only ever assigned to variables as single-quoted string literals, in which PowerShell expands
+/// nothing, and the code refers to them by variable. The code itself has to be `'static`, so that it can't
+/// be put together with `format!` from values at run time.
+pub struct PsScript {
+    text: String,
+}
+
+impl PsScript {
+    /// A script that stops at the first error
+    pub fn new() -> Self {
+        Self { text: "$ErrorActionPreference = \"Stop\";\n".to_owned() }
+    }
+
+    /// Sets `$name` to `value`
+    pub fn set(mut self, name: &str, value: &str) -> Self {
+        check_name(name);
+        self.text += &format!("${} = {};\n", name, quote(value));
+        self
+    }
+
+    /// Sets `$name` to an array of `values`
+    pub fn set_array<S: AsRef<str>>(mut self, name: &str, values: &[S]) -> Self {
+        check_name(name);
+        let values: Vec<String> = values.iter().map(|v| quote(v.as_ref())).collect();
+        self.text += &format!("${} = @({});\n", name, values.join(", "));
+        self
+    }
+
+    /// Appends `code`, which runs after the variables set so far have been
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.text += code;
+        self.text += "\n";
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+impl fmt::Display for PsScript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Quotes `value` as a single-quoted string literal. The only special characters in one are the
+/// quotes that end it, which are escaped by doubling them. PowerShell counts the typographic
+/// single quotes as quotes too, so those are doubled as well.
+pub fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for c in value.chars() {
+        if let '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' = c {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Variable names come from lama's own code, so a bad one is a bug
+fn check_name(name: &str) {
+    assert!(!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "Bad PowerShell variable name '{}'", name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE_NAMES: &[&str] = &[
+        "it's",
+        "'",
+        "''",
+        "'; Remove-Item C:\\ -Recurse; '",
+        "\u{2018}left\u{2019} \u{201A}low\u{201B}",
+        "\u{2019}; Stop-Computer; \u{2018}",
+        "$(Stop-Computer)",
+        "$env:USERNAME ${x} $x",
+        "`n`t`$x `'",
+        "a; b | c & d",
+        "line one\nline two\r\n'; Stop-Computer\n",
+        "@' here-string '@",
+        "",
+    ];
+
+    fn is_quote(c: char) -> bool {
+        matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}')
+    }
+
+    /// Reads a single-quoted string literal the way PowerShell does, returning its value and whatever follows it
+    fn read_literal(text: &str) -> (String, &str) {
+        let mut chars = text.char_indices().peekable();
+        assert!(chars.next().is_some_and(|(_, c)| is_quote(c)), "'{}' doesn't start with a quote", text);
+        let mut value = String::new();
+        while let Some((i, c)) = chars.next() {
+            if is_quote(c) {
+                match chars.peek() {
+                    Some(&(_, next)) if is_quote(next) => {
+                        value.push(next);
+                        chars.next();
+                    }
+                    _ => return (value, &text[i + c.len_utf8()..]),
+                }
+            } else {
+                value.push(c);
+            }
+        }
+        panic!("'{}' isn't terminated", text);
+    }
+
+    #[test]
+    fn quote_keeps_hostile_names_inside_the_literal() {
+        for name in HOSTILE_NAMES {
+            let quoted = quote(name);
+            let (value, rest) = read_literal(&quoted);
+            assert_eq!(value, *name);
+            assert_eq!(rest, "");
+        }
+    }
+
+    #[test]
+    fn quote_only_doubles_quotes() {
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote("\u{2018}x\u{2019}"), "'\u{2018}\u{2018}x\u{2019}\u{2019}'");
+        assert_eq!(quote("\u{201A}\u{201B}"), "'\u{201A}\u{201A}\u{201B}\u{201B}'");
+        assert_eq!(quote("$(whoami) `n; \"x\""), "'$(whoami) `n; \"x\"'");
+        assert_eq!(quote("a\nb"), "'a\nb'");
+    }
+
+    #[test]
+    fn set_only_ever_assigns_hostile_names() {
+        for name in HOSTILE_NAMES {
+            let script = PsScript::new().set("Name", name).code("Get-VM -Name $Name");
+            let text = script.as_str();
+            let assignment = text.strip_prefix("$ErrorActionPreference = \"Stop\";\n$Name = ").unwrap();
+            let (value, rest) = read_literal(assignment);
+            assert_eq!(value, *name);
+            assert_eq!(rest, ";\nGet-VM -Name $Name\n");
+        }
+    }
+
+    #[test]
+    fn set_array_only_ever_assigns_hostile_names() {
+        let script = PsScript::new().set_array("Names", HOSTILE_NAMES);
+        let text = script.as_str();
+        let mut rest = text.strip_prefix("$ErrorActionPreference = \"Stop\";\n$Names = @(").unwrap();
+        for (i, name) in HOSTILE_NAMES.iter().enumerate() {
+            let (value, after) = read_literal(rest);
+            assert_eq!(value, *name);
+            rest = if i + 1 < HOSTILE_NAMES.len() { after.strip_prefix(", ").unwrap() } else { after };
+        }
+        assert_eq!(rest, ");\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad PowerShell variable name")]
+    fn set_refuses_variable_names_that_are_not_plain() {
+        let _ = PsScript::new().set("x; Stop-Computer; $y", "value");
+    }
+}
@@ -0,0 +1,309 @@
+use serde_derive::{Serialize, Deserialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+// Must match what HOST_SCRIPT writes
+const RESPONSE_HEADER: &str = "LAMA-RESPONSE";
+
+/// Runs each request in a script file of its own, so that an `exit` in it only ends that script
+/// and shows up in `$LASTEXITCODE` instead of taking the whole host down.
+/// A script that dies of an uncaught error gets -1, so that it can't be mistaken for its own `exit 1`.
+/// The script files get a BOM since without one Windows PowerShell reads them in the ANSI code page, which
+/// garbles anything that isn't ASCII and can even turn it into quotes that end a string early.
+const HOST_SCRIPT: &str = r#"$ErrorActionPreference = "Continue";
+$ProgressPreference = "SilentlyContinue";
+$utf8 = New-Object System.Text.UTF8Encoding $false;
+$utf8_with_bom = New-Object System.Text.UTF8Encoding $true;
+[Console]::InputEncoding = $utf8;
+[Console]::OutputEncoding = $utf8;
+$out = [Console]::Out;
+
+while ($true) {
+    $line = [Console]::In.ReadLine();
+    if ($null -eq $line) {
+        break;
+    }
+
+    $request = ConvertFrom-Json -InputObject $line;
+    $script_path = Join-Path $env:TEMP ("lama-" + [guid]::NewGuid().ToString() + ".ps1");
+    [IO.File]::WriteAllText($script_path, $request.Script, $utf8_with_bom);
+
+    $global:LASTEXITCODE = 0;
+    $exit_code = 0;
+    $output = @();
+    try {
+        $output = @(& $script_path *>&1);
+        if ($LASTEXITCODE) {
+            $exit_code = $LASTEXITCODE;
+        }
+    } catch {
+        $exit_code = -1;
+        $output += $_;
+    } finally {
+        Remove-Item -Path $script_path -ErrorAction SilentlyContinue;
+    }
+
+    $stdout = @($output | Where-Object { $_ -isnot [System.Management.Automation.ErrorRecord] } | ForEach-Object { $_.ToString() }) -join "`n";
+    $stderr = @($output | Where-Object { $_ -is [System.Management.Automation.ErrorRecord] } | ForEach-Object { $_.ToString() }) -join "`n";
+    $response = ConvertTo-Json -Compress -InputObject @{ Id = $request.Id; ExitCode = $exit_code; Stdout = $stdout; Stderr = $stderr };
+    $out.Write("LAMA-RESPONSE " + $utf8.GetByteCount($response) + "`n" + $response);
+    $out.Flush();
+}"#;
+
+/// A long-lived PowerShell process that scripts are sent to one after another, which saves
+/// starting a new one (and loading the Hyper-V module into it) for every single operation.
+///
+/// The protocol is plain enough for any program to speak, e.g. a stub host in tests:
+/// each request is one line of JSON `{"Id": <n>, "Script": "<script>"}` on the host's stdin, and
+/// each response is a `LAMA-RESPONSE <length>` line on its stdout followed by exactly that many bytes
+/// of JSON `{"Id": <n>, "ExitCode": <code>, "Stdout": "...", "Stderr": "..."}`. Anything else the
+/// host prints between responses is ignored.
+pub struct PsSession {
+    program: String,
+    args: Vec<String>,
+    host: Option<Host>,
+    next_id: u64,
+}
+
+struct Host {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    #[serde(rename = "Id")]
+    id: u64,
+    #[serde(rename = "Script")]
+    script: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PsOutput {
+    #[serde(rename = "Id")]
+    id: u64,
+    #[serde(rename = "ExitCode")]
+    pub exit_code: i32,
+    #[serde(rename = "Stdout")]
+    pub stdout: String,
+    #[serde(rename = "Stderr")]
+    pub stderr: String,
+}
+
+impl PsSession {
+    /// A session with a real PowerShell host. It's only started when the first script is run.
+    pub fn powershell() -> Self {
+        let encoded_script = encode_command(HOST_SCRIPT);
+        Self::new("powershell", &["-NoLogo", "-NoProfile", "-NonInteractive", "-ExecutionPolicy", "Bypass", "-EncodedCommand", &encoded_script])
+    }
+
+    /// A session with the host started by running `program` with `args`
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: args.iter().map(|a| (*a).to_owned()).collect(),
+            host: None,
+            next_id: 1,
+        }
+    }
+
+    /// Runs the script and returns what it printed along with its exit code.
+    /// A host that has died since the last script is restarted first. If it dies while running
+    /// this one, the script is not retried, since it may have done some of its work already.
+    pub fn run(&mut self, script: &str) -> io::Result<PsOutput> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = serde_json::to_string(&Request { id, script })?;
+
+        if self.host.as_mut().map(|h| h.has_exited()).unwrap_or(true) {
+            self.start()?;
+        }
+
+        // A failed write means the host was gone before it could read the request, so it's safe to try again
+        if self.send(&request).is_err() {
+            self.start()?;
+            if let Err(e) = self.send(&request) {
+                self.kill();
+                return Err(e);
+            }
+        }
+
+        match self.receive() {
+            Ok(output) if output.id == id => Ok(output),
+            Ok(output) => {
+                self.kill();
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("PowerShell host answered request {} with response {}", id, output.id)))
+            }
+            Err(e) => {
+                self.kill();
+                Err(io::Error::new(e.kind(), format!("PowerShell host died while running the script: {}", e)))
+            }
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.kill();
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to start PowerShell host '{}': {}", self.program, e)))?;
+
+        let stdin = child.stdin.take().ok_or_else(|| io::Error::other("Failed to obtain stdin of PowerShell host"))?;
+        let stdout = child.stdout.take().ok_or_else(|| io::Error::other("Failed to obtain stdout of PowerShell host"))?;
+
+        self.host = Some(Host { child, stdin, stdout: BufReader::new(stdout) });
+        Ok(())
+    }
+
+    fn send(&mut self, request: &str) -> io::Result<()> {
+        let host = self.host.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "PowerShell host is not running"))?;
+        host.stdin.write_all(request.as_bytes())?;
+        host.stdin.write_all(b"\n")?;
+        host.stdin.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<PsOutput> {
+        let host = self.host.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "PowerShell host is not running"))?;
+        loop {
+            let mut line = Vec::new();
+            if host.stdout.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "PowerShell host exited"));
+            }
+
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_start_matches('\u{feff}').trim();
+            if !line.starts_with(RESPONSE_HEADER) {
+                continue; // Not ours
+            }
+
+            let len: usize = line[RESPONSE_HEADER.len()..].trim().parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Bad response header from PowerShell host: '{}'", line)))?;
+            let mut response = vec![0; len];
+            host.stdout.read_exact(&mut response)?;
+            return serde_json::from_slice(&response)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Bad response from PowerShell host: {}", e)));
+        }
+    }
+
+    fn kill(&mut self) {
+        if let Some(mut host) = self.host.take() {
+            let _ = host.child.kill();
+            let _ = host.child.wait();
+        }
+    }
+}
+
+impl Drop for PsSession {
+    fn drop(&mut self) {
+        // Closing stdin lets the host leave its loop on its own
+        if let Some(host) = self.host.take() {
+            let Host { mut child, stdin, .. } = host;
+            drop(stdin);
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Host {
+    fn has_exited(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(Some(_)) | Err(_) => true,
+            Ok(None) => false,
+        }
+    }
+}
+
+/// Encodes the script the way `powershell -EncodedCommand` wants it: base64 of its UTF-16LE bytes.
+/// Passing the host script like this spares us from quoting it for the command line.
+fn encode_command(script: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes: Vec<u8> = script.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Speaks the protocol for scripts made of a single word: it answers with the script as its output,
+    /// except that `die` exits without answering, `wrong-id` answers another request and `noisy` prints
+    /// something else first
+    const STUB_HOST: &str = r#"
+        while IFS= read -r line; do
+            id=$(printf '%s' "$line" | sed 's/.*"Id":\([0-9]*\).*/\1/')
+            script=$(printf '%s' "$line" | sed 's/.*"Script":"\(.*\)"}$/\1/')
+            case "$script" in
+                die) exit 0 ;;
+                wrong-id) id=$((id + 100)) ;;
+                noisy) printf 'WARNING: something the host printed on its own\n\n' ;;
+            esac
+            response=$(printf '{"Id":%s,"ExitCode":0,"Stdout":"%s","Stderr":""}' "$id" "$script")
+            printf 'LAMA-RESPONSE %s\n%s' "$(printf '%s' "$response" | wc -c | tr -d ' ')" "$response"
+        done
+    "#;
+
+    fn stub_session() -> PsSession {
+        PsSession::new("sh", &["-c", STUB_HOST])
+    }
+
+    #[test]
+    fn responses_are_framed_by_their_length() {
+        let mut session = stub_session();
+        // Back to back, with no newline after the JSON, and with lengths counted in bytes rather than chars
+        for script in &["first", "zweite", "trzecié", "noisy", "ünf"] {
+            let output = session.run(script).unwrap();
+            assert_eq!(output.stdout, *script);
+            assert_eq!(output.exit_code, 0);
+        }
+    }
+
+    #[test]
+    fn host_is_restarted_after_it_dies() {
+        let mut session = stub_session();
+        assert_eq!(session.run("before").unwrap().stdout, "before");
+
+        let error = session.run("die").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        assert_eq!(session.run("after").unwrap().stdout, "after");
+    }
+
+    #[test]
+    fn response_to_another_request_is_an_error() {
+        let mut session = stub_session();
+        let error = session.run("wrong-id").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // The host that got out of step is replaced
+        assert_eq!(session.run("again").unwrap().stdout, "again");
+    }
+
+    #[test]
+    fn host_that_does_not_start_is_an_error() {
+        let mut session = PsSession::new("lama-no-such-powershell-host", &[]);
+        assert_eq!(session.run("anything").unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn commands_are_encoded_as_base64_of_utf16() {
+        assert_eq!(encode_command(""), "");
+        assert_eq!(encode_command("dir"), "ZABpAHIA");
+        assert_eq!(encode_command("ls"), "bABzAA==");
+        assert_eq!(encode_command("é"), "6QA=");
+    }
+}
@@ -0,0 +1,691 @@
+use crate::bundle::BUNDLE_EXTENSION;
+use crate::checksums::{self, LabIndex, Mismatch, INDEX_FILE_NAME};
+use crate::copy::{self, staged_size, CopyProgress, STAGING_SUFFIX};
+use crate::error::{ErrorCategory, LamaError};
+use crate::progress::Progress;
+use crate::has_vmcx_file;
+use crate::sha256::Sha256;
+use failure::Error;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use ureq::{Agent, AgentBuilder, Response};
+
+const ARCHIVE_EXTENSION: &str = ".tar";
+/// Next to an archive, holding its SHA-256 hash the way `sha256sum` writes it
+const ARCHIVE_HASH_EXTENSION: &str = ".sha256";
+const DOWNLOAD_ATTEMPTS: u64 = 3;
+/// How long to wait before trying a failed download again the first time. Each time after that waits longer.
+pub const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Where a lab is downloaded from
+enum Source {
+    /// The URL of the lab's `index.json`. Its files are next to it.
+    Index(String),
+    /// A tar archive of the lab
+    Archive,
+    /// A bundle written by `pack`, which is kept as it is
+    Bundle,
+}
+
+/// The URL `path` holds if it's an `http(s)://` one
+pub fn lab_url(path: &Path) -> Option<&str> {
+    path.to_str().filter(|p| {
+        let p = p.to_ascii_lowercase();
+        p.starts_with("http://") || p.starts_with("https://")
+    })
+}
+
+/// `%LOCALAPPDATA%\lama\cache`, or a folder in the temp folder where there's no such thing
+pub fn default_cache_dir() -> PathBuf {
+    env::var_os("LOCALAPPDATA").map(PathBuf::from).unwrap_or_else(env::temp_dir).join("lama").join("cache")
+}
+
+/// Where the lab at `url` is downloaded to, whether or not it is yet
+pub fn cache_path(url: &str, cache_dir: &Path) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    let lab_path = cache_dir.join(&hasher.finish()[..16]).join(lab_name(url));
+    match source(url) {
+        Source::Bundle => lab_path.with_file_name(format!("{}.{}", lab_name(url), BUNDLE_EXTENSION)),
+        _ => lab_path,
+    }
+}
+
+/// The lab or bundle downloaded earlier from `url`, if there's one
+pub fn cached_lab(url: &str, cache_dir: &Path) -> Result<Option<PathBuf>, Error> {
+    let lab_path = cache_path(url, cache_dir);
+    if lab_path.is_dir() {
+        Ok(Some(lab_root(&lab_path)?))
+    } else if lab_path.is_file() {
+        Ok(Some(lab_path))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Downloads the lab at `url` into the cache and returns where it is. `url` is either that of a lab's `index.json`,
+/// or of the folder it's in, or of a tar archive of the lab, or of a bundle, which is returned as it is rather than
+/// extracted. A download that was interrupted is picked up where it stopped. The files are checked against their
+/// hashes in the index, and with `verify` an archive or bundle against the hash in the `.sha256` file next to it,
+/// which then has to be there. Downloads that fail are tried again after `retry_delay`.
+pub fn fetch_lab(url: &str, cache_dir: &Path, verify: bool, retry_delay: Duration) -> Result<PathBuf, Error> {
+    let lab_path = cache_path(url, cache_dir);
+    let mut staging_name = lab_path.file_name().unwrap_or_default().to_owned();
+    staging_name.push(STAGING_SUFFIX);
+    let staging_path = lab_path.with_file_name(staging_name);
+    fs::create_dir_all(lab_path.parent().unwrap_or(cache_dir))?;
+
+    let agent = AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(60))
+        .build();
+    match source(url) {
+        Source::Index(index_url) => fetch_indexed_lab(&agent, url, &index_url, &lab_path, &staging_path, retry_delay)?,
+        Source::Archive => fetch_archive(&agent, url, &lab_path, &staging_path, verify, retry_delay)?,
+        Source::Bundle => {
+            if lab_path.is_file() {
+                info!("Using the bundle downloaded earlier to {}", lab_path.display());
+            } else {
+                download_archive(&agent, url, &lab_path, verify, retry_delay)?;
+            }
+            return Ok(lab_path);
+        }
+    }
+    lab_root(&lab_path)
+}
+
+fn fetch_indexed_lab(agent: &Agent, url: &str, index_url: &str, lab_path: &Path, staging_path: &Path, retry_delay: Duration) -> Result<(), Error> {
+    let index = LabIndex::from_slice(&get_bytes(agent, index_url)?, Path::new(index_url))?;
+    let bad_paths: Vec<&str> = index.files().map(|(path, _, _)| path).filter(|p| !checksums::is_safe_key(p)).collect();
+    if !bad_paths.is_empty() {
+        return Err(LamaError::InvalidLab(format!("The index at '{}' has paths outside the lab: {}", index_url, bad_paths.join(", "))).into());
+    }
+
+    if lab_path.is_dir() {
+        let old_index = LabIndex::load(lab_path)?;
+        if old_index.as_ref() == Some(&index) {
+            info!("Using the lab downloaded earlier to {}", lab_path.display());
+            return Ok(());
+        }
+
+        // Only the files that changed are downloaded again
+        info!("The lab changed since it was downloaded to {}. Updating it", lab_path.display());
+        if staging_path.exists() {
+            fs::remove_dir_all(lab_path)?;
+        } else {
+            fs::rename(lab_path, staging_path)?;
+            if let Some(old_index) = &old_index {
+                for path in index.changed_since(old_index) {
+                    let file_path = checksums::from_key(staging_path, path);
+                    if file_path.is_file() {
+                        fs::remove_file(file_path)?;
+                    }
+                }
+            }
+        }
+    }
+
+    let total: u64 = index.files().map(|(_, size, _)| size).sum();
+    let staged: u64 = index.files().map(|(path, size, _)| copy::resumable_size(&checksums::from_key(staging_path, path), size)).sum();
+    if staged > 0 {
+        info!("Resuming the download in {} ({} of {} downloaded)", staging_path.display(), copy::format_bytes(staged), copy::format_bytes(total));
+    } else {
+        info!("Downloading {} files ({}) to {}", index.files().count(), copy::format_bytes(total), lab_path.display());
+    }
+
+    fs::create_dir_all(staging_path)?;
+    let mut progress = CopyProgress::new(total - staged);
+    let result = download_indexed_files(agent, index_url, &index, staging_path, &mut progress, retry_delay);
+    progress.finish();
+    match result {
+        Ok(mismatches) if mismatches.is_empty() => {}
+        Ok(mismatches) => return Err(LamaError::ChecksumMismatch { lab_path: PathBuf::from(url), mismatches })?,
+        Err(e) => {
+            warn!("The partial download is kept in '{}'. Deploying again picks it up where it stopped", staging_path.display());
+            return Err(e);
+        }
+    }
+
+    // Files left over from an earlier version of the lab
+    let indexed: HashSet<&str> = index.files().map(|(path, _, _)| path).collect();
+    for path in checksums::list_files(staging_path, "")? {
+        if !indexed.contains(path.as_str()) {
+            fs::remove_file(checksums::from_key(staging_path, &path))?;
+        }
+    }
+
+    index.save(staging_path)?;
+    fs::rename(staging_path, lab_path)?;
+    info!("Downloaded the lab to {}", lab_path.display());
+    Ok(())
+}
+
+/// Downloads the files in `index` into the staging folder and returns how any that are still wrong after a second try differ from it
+fn download_indexed_files(agent: &Agent, index_url: &str, index: &LabIndex, staging_path: &Path, progress: &mut CopyProgress, retry_delay: Duration) -> Result<Vec<String>, Error> {
+    let base_url = &index_url[..index_url.rfind('/').map_or(0, |i| i + 1)];
+    let mut mismatches = Vec::new();
+    for (path, size, _) in index.files() {
+        let file_url = format!("{}{}", base_url, path.split('/').map(percent_encode).collect::<Vec<_>>().join("/"));
+        let dest_path = checksums::from_key(staging_path, path);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        progress.start_file(path, size);
+        let (actual_size, sha256) = download_file(agent, &file_url, &dest_path, Some(size), progress, retry_delay)?;
+        let mismatch = match index.check_file(path, actual_size, &sha256) {
+            Some(mismatch) => {
+                // A resumed file may have picked up garbage from the interrupted download, so it's downloaded again from scratch
+                warn!("{} after downloading it. Downloading it again", mismatch);
+                fs::remove_file(&dest_path)?;
+                progress.add_to_total(size);
+                progress.start_file(path, size);
+                let (actual_size, sha256) = download_file(agent, &file_url, &dest_path, Some(size), progress, retry_delay)?;
+                index.check_file(path, actual_size, &sha256)
+            }
+            None => None,
+        };
+        if let Some(mismatch) = mismatch {
+            mismatches.push(mismatch.to_string());
+        }
+    }
+    Ok(mismatches)
+}
+
+fn fetch_archive(agent: &Agent, url: &str, lab_path: &Path, staging_path: &Path, verify: bool, retry_delay: Duration) -> Result<(), Error> {
+    if lab_path.is_dir() {
+        info!("Using the lab downloaded earlier to {}", lab_path.display());
+        return Ok(());
+    }
+
+    let archive_name = lab_path.file_name().unwrap_or_default().to_string_lossy().into_owned() + ARCHIVE_EXTENSION;
+    let archive_path = lab_path.with_file_name(&archive_name);
+    if !archive_path.is_file() {
+        download_archive(agent, url, &archive_path, verify, retry_delay)?;
+    }
+
+    // Extracting isn't resumable, so whatever an interrupted one left is thrown away
+    if staging_path.exists() {
+        fs::remove_dir_all(staging_path)?;
+    }
+    Progress::new(false).step(&format!("==> Extracting {}", archive_name), || -> Result<(), Error> {
+        let mut archive = tar::Archive::new(fs::File::open(&archive_path)?);
+        archive.unpack(staging_path)?;
+        Ok(())
+    }, |_| "Done".to_owned())?;
+    fs::rename(staging_path, lab_path)?;
+    fs::remove_file(&archive_path)?;
+    info!("Downloaded the lab to {}", lab_path.display());
+    Ok(())
+}
+
+/// Downloads the archive or bundle at `url` to `archive_path`. With `verify` it's checked against the hash in the `.sha256` file next to it.
+fn download_archive(agent: &Agent, url: &str, archive_path: &Path, verify: bool, retry_delay: Duration) -> Result<(), Error> {
+    let expected_sha256 = if !verify {
+        warn!("Not checking '{}' against its hash as asked", url);
+        None
+    } else {
+        let bytes = get_bytes(agent, &format!("{}{}", url, ARCHIVE_HASH_EXTENSION)).map_err(|e| match ErrorCategory::of(&e) {
+            ErrorCategory::NotFound => LamaError::MissingHash { url: url.to_owned() }.into(),
+            _ => e,
+        })?;
+        let text = String::from_utf8_lossy(&bytes);
+        let sha256 = text.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(LamaError::BadFile { path: PathBuf::from(format!("{}{}", url, ARCHIVE_HASH_EXTENSION)), msg: "Not a SHA-256 hash".to_owned() }.into());
+        }
+        Some(sha256)
+    };
+
+    let archive_name = archive_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let part_path = archive_path.with_file_name(format!("{}{}", archive_name, STAGING_SUFFIX));
+    if staged_size(&part_path) > 0 {
+        info!("Resuming the download of {}", url);
+    } else {
+        info!("Downloading {}", url);
+    }
+
+    let mut progress = CopyProgress::new(0);
+    progress.start_file(&archive_name, 0);
+    let mut result = download_file(agent, url, &part_path, None, &mut progress, retry_delay);
+    if let (Ok((_, sha256)), Some(expected)) = (&result, &expected_sha256) {
+        if sha256 != expected {
+            // A resumed download may have picked up garbage from the interrupted one, so it's downloaded again from scratch
+            warn!("{} after downloading it. Downloading it again", Mismatch::WrongHash(archive_name.clone()));
+            fs::remove_file(&part_path)?;
+            progress.start_file(&archive_name, 0);
+            result = download_file(agent, url, &part_path, None, &mut progress, retry_delay);
+        }
+    }
+    progress.finish();
+    let (_, sha256) = result.inspect_err(|_| {
+        warn!("The partial download is kept in '{}'. Deploying again picks it up where it stopped", part_path.display());
+    })?;
+    if let Some(expected) = expected_sha256 {
+        if sha256 != expected {
+            fs::remove_file(&part_path)?;
+            return Err(LamaError::ChecksumMismatch { lab_path: PathBuf::from(url), mismatches: vec![Mismatch::WrongHash(archive_name).to_string()] }.into());
+        }
+    }
+
+    fs::rename(&part_path, archive_path)?;
+    Ok(())
+}
+
+/// Downloads `url` to `dest`, carrying on from the end of `dest` if it's already there, and trying again a couple
+/// of times if the connection fails, `retry_delay` later and then twice that. `size` is what the file should come
+/// to, if it's known. Returns the size and hash of the file.
+fn download_file(agent: &Agent, url: &str, dest: &Path, size: Option<u64>, progress: &mut CopyProgress, retry_delay: Duration) -> Result<(u64, String), Error> {
+    let mut attempt = 1;
+    loop {
+        match try_download_file(agent, url, dest, size, progress) {
+            Err(ref e) if attempt < DOWNLOAD_ATTEMPTS && ErrorCategory::of(e) == ErrorCategory::Network => {
+                warn!("{}. Trying again in {} seconds", e, retry_delay.as_secs() * attempt);
+                thread::sleep(retry_delay * attempt as u32);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn try_download_file(agent: &Agent, url: &str, dest: &Path, size: Option<u64>, progress: &mut CopyProgress) -> Result<(u64, String), Error> {
+    let mut hasher = Sha256::new();
+    let (mut dest_file, mut offset) = copy::open_to_resume(dest, size.unwrap_or(u64::MAX), &mut hasher)?;
+    progress.restart_file();
+    progress.skip(offset);
+    if Some(offset) == size {
+        return Ok((offset, hasher.finish()));
+    }
+
+    let (response, resumed) = get(agent, url, offset)?;
+    if !resumed && offset > 0 {
+        // The server sent the whole file, so what's there is thrown away
+        dest_file.set_len(0)?;
+        dest_file.seek(SeekFrom::Start(0))?;
+        hasher = Sha256::new();
+        progress.add_to_total(offset);
+        progress.restart_file();
+        offset = 0;
+    }
+
+    if size.is_none() {
+        if let Some(length) = response.header("Content-Length").and_then(|l| l.parse::<u64>().ok()) {
+            progress.add_to_total(length);
+            progress.set_file_size(offset + length);
+        }
+    }
+
+    let mut reader = response.into_reader();
+    let appended = copy::append(&mut reader, &mut dest_file, &mut hasher, progress)
+        .map_err(|e| LamaError::DownloadFailed { url: url.to_owned(), msg: e.to_string() })?;
+    Ok((offset + appended, hasher.finish()))
+}
+
+/// GETs `url` from byte `offset` on. Returns the response and whether it starts there,
+/// which it doesn't if the server sent the whole file instead.
+fn get(agent: &Agent, url: &str, offset: u64) -> Result<(Response, bool), Error> {
+    let mut request = agent.get(url);
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={}-", offset));
+    }
+
+    match request.call() {
+        Ok(response) if offset > 0 && response.status() == 206 => {
+            let range_start = format!("bytes {}-", offset);
+            if !response.header("Content-Range").is_some_and(|r| r.starts_with(&range_start)) {
+                return Err(LamaError::DownloadFailed { url: url.to_owned(), msg: "The server sent the wrong part of the file".to_owned() }.into());
+            }
+            Ok((response, true))
+        }
+        Ok(response) => Ok((response, false)),
+        Err(ureq::Error::Status(404, _)) => Err(LamaError::UrlNotFound(url.to_owned()))?,
+        // What's already there is longer than the file, which must have changed since
+        Err(ureq::Error::Status(416, _)) if offset > 0 => get(agent, url, 0),
+        Err(ureq::Error::Status(code, response)) => {
+            Err(LamaError::DownloadFailed { url: url.to_owned(), msg: format!("The server answered {} {}", code, response.status_text()) })?
+        }
+        Err(e) => Err(LamaError::DownloadFailed { url: url.to_owned(), msg: e.to_string() })?,
+    }
+}
+
+fn get_bytes(agent: &Agent, url: &str) -> Result<Vec<u8>, Error> {
+    let (response, _) = get(agent, url, 0)?;
+    let mut bytes = Vec::new();
+    response.into_reader().read_to_end(&mut bytes)
+        .map_err(|e| LamaError::DownloadFailed { url: url.to_owned(), msg: e.to_string() })?;
+    Ok(bytes)
+}
+
+fn source(url: &str) -> Source {
+    let path = url_path(url).to_ascii_lowercase();
+    if path.ends_with(ARCHIVE_EXTENSION) {
+        Source::Archive
+    } else if path.ends_with(&format!(".{}", BUNDLE_EXTENSION)) {
+        Source::Bundle
+    } else if path.ends_with(".json") {
+        Source::Index(url.to_owned())
+    } else if url.ends_with('/') {
+        Source::Index(format!("{}{}", url, INDEX_FILE_NAME))
+    } else {
+        Source::Index(format!("{}/{}", url, INDEX_FILE_NAME))
+    }
+}
+
+/// Names the lab after the folder its index is in, or after its archive or bundle
+fn lab_name(url: &str) -> String {
+    let path = url_path(url);
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if path.to_ascii_lowercase().ends_with(".json") {
+        segments.pop();
+    }
+
+    let name = segments.last().map(|s| percent_decode(s)).unwrap_or_default();
+    let bundle_extension = format!(".{}", BUNDLE_EXTENSION);
+    let name = match [ARCHIVE_EXTENSION, bundle_extension.as_str()].iter().find(|e| name.to_ascii_lowercase().ends_with(*e)) {
+        Some(extension) => name[..name.len() - extension.len()].to_owned(),
+        None => name,
+    };
+    if checksums::is_safe_key(&name) && !name.contains(|c: char| "\\:*?\"<>|".contains(c)) {
+        name
+    } else {
+        "lab".to_owned()
+    }
+}
+
+/// The path of `url`, leaving out the scheme, host, query and fragment
+fn url_path(url: &str) -> &str {
+    let after_scheme = url.find("://").map_or(url, |i| &url[i + 3..]);
+    let path = after_scheme.find('/').map_or("", |i| &after_scheme[i..]);
+    path.split(&['?', '#'][..]).next().unwrap_or_default()
+}
+
+/// A lab that was archived as a single folder is in that folder, unless that's the folder of its only VM
+fn lab_root(lab_path: &Path) -> Result<PathBuf, Error> {
+    let entries = fs::read_dir(lab_path)?.collect::<Result<Vec<_>, _>>()?;
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() && !has_vmcx_file(&entry.path())? => Ok(entry.path()),
+        _ => Ok(lab_path.to_owned()),
+    }
+}
+
+fn percent_encode(segment: &str) -> String {
+    segment.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = if bytes[i] == b'%' { segment.get(i + 1..i + 3).and_then(|h| u8::from_str_radix(h, 16).ok()) } else { None };
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{contents, hash};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// What goes wrong the first time a file is asked for
+    enum Fault {
+        /// The connection drops after this many bytes of the body
+        Truncate(usize),
+        /// A byte of the body is flipped
+        Corrupt,
+    }
+
+    /// The path and `Range` header of each request
+    type Requests = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    /// Serves files over HTTP the way a plain web server does, with `Range` requests, from a thread that's never stopped
+    struct TestServer {
+        url: String,
+        requests: Requests,
+    }
+
+    impl TestServer {
+        fn start(files: HashMap<String, Vec<u8>>, faults: HashMap<String, Fault>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Requests::default();
+            let server_requests = Arc::clone(&requests);
+            let mut faults = faults;
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let _ = serve(stream.unwrap(), &files, &mut faults, &server_requests);
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn requests(&self) -> Vec<(String, Option<String>)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn serve(mut stream: TcpStream, files: &HashMap<String, Vec<u8>>, faults: &mut HashMap<String, Fault>, requests: &Requests) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let path = percent_decode(request_line.split_whitespace().nth(1).unwrap_or_default());
+        let mut range = None;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("range") {
+                    range = Some(value.trim().to_owned());
+                }
+            }
+        }
+        requests.lock().unwrap().push((path.clone(), range.clone()));
+
+        let body = match files.get(&path) {
+            Some(body) => body,
+            None => return stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+        };
+        let start: usize = range.as_ref().and_then(|r| r.strip_prefix("bytes=")).and_then(|r| r.trim_end_matches('-').parse().ok()).unwrap_or(0);
+        if start > body.len() {
+            return stream.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+
+        let mut part = body[start..].to_vec();
+        let status = if range.is_some() {
+            format!("206 Partial Content\r\nContent-Range: bytes {}-{}/{}", start, body.len().saturating_sub(1), body.len())
+        } else {
+            "200 OK".to_owned()
+        };
+        write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, part.len())?;
+        match faults.remove(&path) {
+            Some(Fault::Truncate(length)) => part.truncate(length),
+            Some(Fault::Corrupt) => part[0] ^= 0xff,
+            None => {}
+        }
+        stream.write_all(&part)
+    }
+
+    /// The files of a small lab, keyed by their path in it
+    fn lab_files() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("lab.toml", b"".to_vec()),
+            ("vm01/Virtual Machines/vm.vmcx", b"config".to_vec()),
+            ("vm01/disk.vhdx", contents(3 * 1024 * 1024 + 17, 0)),
+        ]
+    }
+
+    /// Serves the lab's files and their index under `/lab/`
+    fn serve_indexed_lab(faults: HashMap<String, Fault>) -> TestServer {
+        let mut index = LabIndex::new();
+        let mut files = HashMap::new();
+        for (path, data) in lab_files() {
+            index.insert(path, data.len() as u64, hash(&data));
+            files.insert(format!("/lab/{}", path), data);
+        }
+        files.insert("/lab/index.json".to_owned(), serde_json::to_vec(&index).unwrap());
+        TestServer::start(files, faults)
+    }
+
+    /// Serves the lab as `/lab.tar`, and next to it the hash `hash_of` gives for it, if any
+    fn serve_archived_lab(hash_of: impl Fn(&[u8]) -> Option<String>, faults: HashMap<String, Fault>) -> TestServer {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in lab_files() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, format!("lab/{}", path), data.as_slice()).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let mut files = HashMap::new();
+        if let Some(sha256) = hash_of(&archive) {
+            files.insert("/lab.tar.sha256".to_owned(), format!("{}  lab.tar\n", sha256).into_bytes());
+        }
+        files.insert("/lab.tar".to_owned(), archive);
+        TestServer::start(files, faults)
+    }
+
+    fn assert_lab_files(lab_path: &Path) {
+        for (path, data) in lab_files() {
+            assert_eq!(fs::read(checksums::from_key(lab_path, path)).unwrap(), data, "{}", path);
+        }
+    }
+
+    /// `fetch_lab` without waiting before trying again
+    fn fetch(url: &str, cache_dir: &Path, verify: bool) -> Result<PathBuf, Error> {
+        fetch_lab(url, cache_dir, verify, Duration::from_secs(0))
+    }
+
+    fn requests_for<'a>(requests: &'a [(String, Option<String>)], path: &str) -> Vec<Option<&'a str>> {
+        requests.iter().filter(|(p, _)| p == path).map(|(_, range)| range.as_deref()).collect()
+    }
+
+    #[test]
+    fn fetch_lab_downloads_the_files_in_the_index() {
+        let server = serve_indexed_lab(HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+        let url = format!("{}/lab/", server.url);
+
+        let lab_path = fetch(&url, cache.path(), true).unwrap();
+        assert_eq!(lab_path, cache_path(&url, cache.path()));
+        assert_lab_files(&lab_path);
+        assert!(lab_path.join(INDEX_FILE_NAME).is_file());
+        assert_eq!(cached_lab(&url, cache.path()).unwrap(), Some(lab_path.clone()));
+
+        // The index is all that's downloaded again while the lab hasn't changed
+        let request_count = server.requests().len();
+        assert_eq!(fetch(&url, cache.path(), true).unwrap(), lab_path);
+        assert_eq!(server.requests()[request_count..], [("/lab/index.json".to_owned(), None)]);
+    }
+
+    #[test]
+    fn fetch_lab_resumes_a_truncated_download_with_a_range_request() {
+        let server = serve_indexed_lab(vec![("/lab/vm01/disk.vhdx".to_owned(), Fault::Truncate(1024 * 1024 + 5))].into_iter().collect());
+        let cache = tempfile::tempdir().unwrap();
+
+        let lab_path = fetch(&format!("{}/lab/index.json", server.url), cache.path(), true).unwrap();
+        assert_lab_files(&lab_path);
+        assert_eq!(requests_for(&server.requests(), "/lab/vm01/disk.vhdx"), vec![None, Some("bytes=1048581-")]);
+    }
+
+    #[test]
+    fn fetch_lab_downloads_a_corrupted_file_again() {
+        let server = serve_indexed_lab(vec![("/lab/vm01/disk.vhdx".to_owned(), Fault::Corrupt)].into_iter().collect());
+        let cache = tempfile::tempdir().unwrap();
+
+        let lab_path = fetch(&format!("{}/lab", server.url), cache.path(), true).unwrap();
+        assert_lab_files(&lab_path);
+        // The second download starts over rather than trusting what's already there
+        assert_eq!(requests_for(&server.requests(), "/lab/vm01/disk.vhdx"), vec![None, None]);
+    }
+
+    #[test]
+    fn fetch_lab_refuses_an_index_with_paths_outside_the_lab() {
+        let mut index = LabIndex::new();
+        index.insert("../evil.txt", 0, hash(b""));
+        let files = vec![("/lab/index.json".to_owned(), serde_json::to_vec(&index).unwrap())].into_iter().collect();
+        let server = TestServer::start(files, HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+
+        let e = fetch(&format!("{}/lab/", server.url), cache.path(), true).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::InvalidInput);
+        assert!(!cache.path().join("evil.txt").exists());
+    }
+
+    #[test]
+    fn fetch_lab_downloads_and_extracts_an_archive() {
+        let server = serve_archived_lab(|archive| Some(hash(archive)), HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+        let url = format!("{}/lab.tar", server.url);
+
+        let lab_path = fetch(&url, cache.path(), true).unwrap();
+        assert_eq!(lab_path, cache_path(&url, cache.path()).join("lab"));
+        assert_lab_files(&lab_path);
+        assert!(!cache_path(&url, cache.path()).with_file_name("lab.tar").exists());
+    }
+
+    #[test]
+    fn fetch_lab_downloads_an_archive_again_when_the_resumed_download_is_corrupted() {
+        let server = serve_archived_lab(|archive| Some(hash(archive)), HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+        let url = format!("{}/lab.tar", server.url);
+        // Left by an interrupted download, but not the start of the archive
+        let part_path = cache_path(&url, cache.path()).with_file_name(format!("lab.tar{}", STAGING_SUFFIX));
+        fs::create_dir_all(part_path.parent().unwrap()).unwrap();
+        fs::write(&part_path, vec![0xffu8; 1000]).unwrap();
+
+        let lab_path = fetch(&url, cache.path(), true).unwrap();
+        assert_lab_files(&lab_path);
+        assert_eq!(requests_for(&server.requests(), "/lab.tar"), vec![Some("bytes=1000-"), None]);
+    }
+
+    #[test]
+    fn fetch_lab_fails_when_an_archive_does_not_match_its_hash() {
+        let server = serve_archived_lab(|_| Some(hash(b"something else")), HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+
+        let e = fetch(&format!("{}/lab.tar", server.url), cache.path(), true).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::Corrupted);
+    }
+
+    #[test]
+    fn fetch_lab_needs_the_hash_of_an_archive_unless_told_not_to_verify() {
+        let server = serve_archived_lab(|_| None, HashMap::new());
+        let cache = tempfile::tempdir().unwrap();
+        let url = format!("{}/lab.tar", server.url);
+
+        let e = fetch(&url, cache.path(), true).unwrap_err();
+        assert!(matches!(e.downcast_ref::<LamaError>(), Some(LamaError::MissingHash { .. })));
+        assert!(requests_for(&server.requests(), "/lab.tar").is_empty());
+
+        let lab_path = fetch(&url, cache.path(), false).unwrap();
+        assert_lab_files(&lab_path);
+        assert_eq!(requests_for(&server.requests(), "/lab.tar.sha256").len(), 1);
+    }
+}
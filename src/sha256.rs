@@ -0,0 +1,89 @@
+use sha2::Digest;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// SHA-256 of data fed to it in pieces, as lowercase hex
+pub struct Sha256 {
+    hasher: sha2::Sha256,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self { hasher: sha2::Sha256::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// The hash as lowercase hex
+    pub fn finish(self) -> String {
+        self.hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Hashes the file at `path` without reading all of it into memory
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::hash;
+
+    // The test vectors of FIPS 180-4, from the NIST examples and NESSIE
+    #[test]
+    fn hash_matches_the_fips_test_vectors() {
+        assert_eq!(hash(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hash(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        assert_eq!(hash(&vec![b'a'; 1_000_000]), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    #[test]
+    fn hash_does_not_depend_on_how_the_data_is_split() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let expected = hash(&data);
+        // Splits on either side of the 64 byte blocks and of the 56 bytes where the padding no longer fits
+        for split in &[0, 1, 55, 56, 57, 63, 64, 65, 128, 999, 1000] {
+            let mut hasher = Sha256::new();
+            hasher.update(&data[..*split]);
+            hasher.update(&[]);
+            hasher.update(&data[*split..]);
+            assert_eq!(hasher.finish(), expected, "split at {}", split);
+        }
+
+        let mut hasher = Sha256::new();
+        for byte in &data {
+            hasher.update(&[*byte]);
+        }
+        assert_eq!(hasher.finish(), expected);
+    }
+
+    #[test]
+    fn hash_file_matches_hashing_the_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let data = vec![b'a'; 3 * 1024 * 1024 + 17];
+        fs::write(&path, &data).unwrap();
+        assert_eq!(hash_file(&path).unwrap(), hash(&data));
+    }
+}
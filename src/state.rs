@@ -0,0 +1,302 @@
+use crate::error::LamaError;
+use crate::{get_vm_paths, get_vm_id};
+use crate::backend::{SwitchKind, VmId};
+use serde_derive::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use log::info;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const LAMA_DIR_NAME: &str = ".lama";
+const STATE_FILE_NAME: &str = "state.json";
+const LEGACY_SWITCHES_FILE_NAME: &str = "switches.json";
+
+/// Bump this whenever the format changes in a way older versions of lama can't read
+pub const STATE_VERSION: u32 = 1;
+
+/// Start of the notes lama gives the switches it creates, which are followed by the path of the lab they belong to
+const SWITCH_NOTES_PREFIX: &str = "Created by lama for the lab at ";
+
+/// What lama has deployed from a lab, kept in `.lama/state.json` under the lab root.
+/// It's saved after every step of a deploy so that it's accurate even if the deploy dies halfway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabState {
+    pub version: u32,
+    pub lama_version: String,
+    /// `None` for state migrated from older versions which didn't record it
+    pub deployed_at: Option<DateTime<Utc>>,
+    /// Name given with `deploy --name`, which the names of the lab's VMs and switches are prefixed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    pub vms: Vec<VmState>,
+    pub switches: Vec<SwitchState>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmState {
+    pub id: VmId,
+    pub name: String,
+    /// Relative to the lab root
+    pub folder: PathBuf,
+    pub adapters: Vec<AdapterState>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdapterState {
+    pub id: String,
+    pub name: String,
+    /// Name of the switch the adapter is connected to
+    pub switch: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchState {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub switch_type: SwitchKind,
+    /// Host network adapter of an external switch
+    pub adapter: Option<String>,
+    /// Address of the host's adapter on an internal switch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_ip: Option<String>,
+}
+
+impl LabState {
+    pub fn new() -> Self {
+        Self {
+            version: STATE_VERSION,
+            lama_version: env!("CARGO_PKG_VERSION").to_owned(),
+            deployed_at: Some(Utc::now()),
+            instance: None,
+            vms: Vec::new(),
+            switches: Vec::new(),
+        }
+    }
+
+    /// Returns `None` if nothing has been deployed from the lab.
+    /// State left behind by older versions of lama is migrated on the way, but only in memory:
+    /// it's written in the new format the next time it's saved, so that reading it never changes anything.
+    pub fn load<P: AsRef<Path>>(lab_path: P) -> Result<Option<LabState>, Error> {
+        let lab_path = lab_path.as_ref();
+        let state_file_path = lab_path.join(LAMA_DIR_NAME).join(STATE_FILE_NAME);
+        if !state_file_path.is_file() {
+            return Self::migrate_legacy(lab_path);
+        }
+
+        let state_file = fs::File::open(&state_file_path)?;
+        let state: LabState = serde_json::from_reader(state_file)
+            .map_err(|e| LamaError::BadFile { path: state_file_path.clone(), msg: e.to_string() })?;
+
+        if state.version > STATE_VERSION {
+            return Err(LamaError::NewerVersion { path: state_file_path, lama_version: state.lama_version })?;
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Writes the state to a temp file first and then renames it over the old one
+    /// so that a crash never leaves a half-written state file behind
+    pub fn save<P: AsRef<Path>>(&self, lab_path: P) -> Result<(), Error> {
+        let lama_dir_path = lab_path.as_ref().join(LAMA_DIR_NAME);
+        if !lama_dir_path.is_dir() {
+            fs::create_dir_all(&lama_dir_path)?;
+        }
+
+        let state_file_path = lama_dir_path.join(STATE_FILE_NAME);
+        let temp_file_path = lama_dir_path.join(format!("{}.tmp", STATE_FILE_NAME));
+        {
+            let temp_file = fs::File::create(&temp_file_path)?;
+            serde_json::to_writer_pretty(&temp_file, self)?;
+            temp_file.sync_all()?;
+        }
+        fs::rename(&temp_file_path, &state_file_path)?;
+        Self::remove_legacy(lab_path.as_ref())
+    }
+
+    pub fn remove<P: AsRef<Path>>(lab_path: P) -> Result<(), Error> {
+        let state_file_path = lab_path.as_ref().join(LAMA_DIR_NAME).join(STATE_FILE_NAME);
+        if state_file_path.is_file() {
+            fs::remove_file(&state_file_path)?;
+        }
+        Self::remove_legacy(lab_path.as_ref())
+    }
+
+    pub fn switch(&self, name: &str) -> Option<&SwitchState> {
+        self.switches.iter().find(|s| s.name == name)
+    }
+
+    pub fn vm_mut(&mut self, vm_id: &VmId) -> Option<&mut VmState> {
+        self.vms.iter_mut().find(|vm| vm.id == *vm_id)
+    }
+
+    /// The notes that mark a switch as created for the lab at `lab_path`.
+    /// They are kept on the host with the switch, so they still tell whose it is if the state file is lost.
+    pub fn switch_notes<P: AsRef<Path>>(lab_path: P) -> Result<String, Error> {
+        Ok(format!("{}{}", SWITCH_NOTES_PREFIX, fs::canonicalize(lab_path)?.display()))
+    }
+
+    /// Whether a switch with these notes was created by lama, for whichever lab
+    pub fn is_lama_switch(notes: &str) -> bool {
+        notes.starts_with(SWITCH_NOTES_PREFIX)
+    }
+
+    // Older versions only kept a switch name -> ID map in .lama/switches.json
+    // and found the VMs by parsing the names of their .vmcx files
+    fn migrate_legacy(lab_path: &Path) -> Result<Option<LabState>, Error> {
+        let switches_file_path = lab_path.join(LAMA_DIR_NAME).join(LEGACY_SWITCHES_FILE_NAME);
+        if !switches_file_path.is_file() {
+            return Ok(None);
+        }
+
+        info!("Reading {} left by an older version of lama", switches_file_path.display());
+        let switches_file = fs::File::open(&switches_file_path)?;
+        let switches: HashMap<String, Uuid> = serde_json::from_reader(switches_file)
+            .map_err(|e| LamaError::BadFile { path: switches_file_path.clone(), msg: e.to_string() })?;
+
+        let mut state = LabState::new();
+        state.deployed_at = None;
+        state.switches = switches.into_iter()
+            .map(|(name, id)| SwitchState { id, name, switch_type: SwitchKind::Private, adapter: None, host_ip: None }) // Older versions only created private switches
+            .collect();
+
+        for vm_path in get_vm_paths(lab_path)? {
+            if let Some(vm_id) = get_vm_id(&vm_path)? {
+                let folder = PathBuf::from(vm_path.file_name().ok_or_else(|| LamaError::InvalidLab(format!("Bad VM folder name '{}'", vm_path.display())))?);
+                let name = folder.to_string_lossy().into_owned();
+                state.vms.push(VmState { id: vm_id, name, folder, adapters: Vec::new() });
+            }
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Once the state has been saved or removed the legacy file is out of date, and would be migrated again if kept
+    fn remove_legacy(lab_path: &Path) -> Result<(), Error> {
+        let switches_file_path = lab_path.join(LAMA_DIR_NAME).join(LEGACY_SWITCHES_FILE_NAME);
+        if switches_file_path.is_file() {
+            fs::remove_file(&switches_file_path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lab_with_files;
+    use tempfile::TempDir;
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_bytes(n.to_be_bytes())
+    }
+
+    /// A lab as older versions of lama left it: imported VMs' `.vmcx` files named after their IDs,
+    /// and the switches they created in `switches.json`
+    fn legacy_lab(vms: &[(&str, Option<VmId>)], switches: &[(&str, Uuid)]) -> TempDir {
+        let mut files: Vec<(String, String)> = vms.iter()
+            .map(|(folder, vm_id)| {
+                let file_name = vm_id.map(|id| id.to_string().to_uppercase()).unwrap_or_else(|| "exported".to_owned());
+                (format!("{}/Virtual Machines/{}.vmcx", folder, file_name), String::new())
+            })
+            .collect();
+        let switches: HashMap<&str, Uuid> = switches.iter().cloned().collect();
+        files.push((format!("{}/{}", LAMA_DIR_NAME, LEGACY_SWITCHES_FILE_NAME), serde_json::to_string(&switches).unwrap()));
+        lab_with_files(&files)
+    }
+
+    #[test]
+    fn legacy_state_is_migrated() {
+        let dc_id = id(1);
+        let web_id = id(2);
+        let switch_id = id(3);
+        let lab = legacy_lab(&[("dc01", Some(dc_id)), ("web01", Some(web_id)), ("client01", None)], &[("corp", switch_id)]);
+
+        let state = LabState::load(lab.path()).unwrap().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.deployed_at, None);
+
+        let mut vms: Vec<(VmId, &str, &Path)> = state.vms.iter().map(|vm| (vm.id, vm.name.as_str(), vm.folder.as_path())).collect();
+        vms.sort_by_key(|vm| vm.1);
+        assert_eq!(vms, vec![(dc_id, "dc01", Path::new("dc01")), (web_id, "web01", Path::new("web01"))]);
+
+        assert_eq!(state.switches.len(), 1);
+        let switch = state.switch("corp").unwrap();
+        assert_eq!(switch.id, switch_id);
+        assert_eq!(switch.switch_type, SwitchKind::Private);
+    }
+
+    #[test]
+    fn loading_legacy_state_writes_nothing() {
+        let lab = legacy_lab(&[("dc01", Some(id(1)))], &[("corp", id(2))]);
+        let lama_dir = lab.path().join(LAMA_DIR_NAME);
+        LabState::load(lab.path()).unwrap().unwrap();
+        LabState::load(lab.path()).unwrap().unwrap();
+
+        let files: Vec<_> = fs::read_dir(&lama_dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, vec![LEGACY_SWITCHES_FILE_NAME]);
+    }
+
+    #[test]
+    fn saved_migrated_state_replaces_legacy_state() {
+        let lab = legacy_lab(&[("dc01", Some(id(1)))], &[("corp", id(2))]);
+        let migrated = LabState::load(lab.path()).unwrap().unwrap();
+        migrated.save(lab.path()).unwrap();
+
+        let lama_dir = lab.path().join(LAMA_DIR_NAME);
+        assert!(lama_dir.join(STATE_FILE_NAME).is_file());
+        assert!(!lama_dir.join(LEGACY_SWITCHES_FILE_NAME).exists());
+        let loaded = LabState::load(lab.path()).unwrap().unwrap();
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", migrated));
+    }
+
+    #[test]
+    fn removed_state_takes_legacy_state_with_it() {
+        let lab = legacy_lab(&[("dc01", Some(id(1)))], &[("corp", id(2))]);
+        LabState::remove(lab.path()).unwrap();
+        assert!(LabState::load(lab.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_legacy_state_is_left_alone() {
+        let lab = legacy_lab(&[], &[]);
+        let switches_file_path = lab.path().join(LAMA_DIR_NAME).join(LEGACY_SWITCHES_FILE_NAME);
+        fs::write(&switches_file_path, "{ not json").unwrap();
+
+        let error = LabState::load(lab.path()).unwrap_err();
+        assert!(matches!(error.downcast::<LamaError>().unwrap(), LamaError::BadFile { .. }));
+        assert!(switches_file_path.is_file());
+        assert!(!lab.path().join(LAMA_DIR_NAME).join(STATE_FILE_NAME).exists());
+    }
+
+    #[test]
+    fn lab_without_state_has_none() {
+        let lab = tempfile::tempdir().unwrap();
+        assert!(LabState::load(lab.path()).unwrap().is_none());
+        assert!(!lab.path().join(LAMA_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn state_from_a_newer_version_is_refused() {
+        let lab = tempfile::tempdir().unwrap();
+        let mut state = LabState::new();
+        state.version = STATE_VERSION + 1;
+        state.save(lab.path()).unwrap();
+
+        let error = LabState::load(lab.path()).unwrap_err();
+        assert!(matches!(error.downcast::<LamaError>().unwrap(), LamaError::NewerVersion { .. }));
+    }
+
+    #[test]
+    fn switch_notes_name_the_lab() {
+        let lab = tempfile::tempdir().unwrap();
+        let notes = LabState::switch_notes(lab.path()).unwrap();
+        assert!(LabState::is_lama_switch(&notes));
+        assert!(notes.ends_with(&fs::canonicalize(lab.path()).unwrap().display().to_string()));
+        assert!(!LabState::is_lama_switch(""));
+        assert!(!LabState::is_lama_switch("Uplink to the office"));
+    }
+}
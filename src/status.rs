@@ -0,0 +1,202 @@
+use crate::error::LamaError;
+use crate::backend::{Backend, SwitchKind, VmId};
+use crate::state::LabState;
+use serde_derive::Serialize;
+use failure::Error;
+use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+/// Live state of a deployed lab as reported by the backend, compared against what was recorded at deploy time
+#[derive(Debug, Serialize)]
+pub struct LabStatus {
+    pub vms: Vec<VmStatus>,
+    pub switches: Vec<SwitchStatus>,
+    pub drift: Vec<Drift>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VmStatus {
+    pub name: String,
+    pub id: VmId,
+    /// `None` if the VM no longer exists
+    pub state: Option<String>,
+    pub uptime_secs: Option<u64>,
+    pub adapters: Vec<AdapterStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AdapterStatus {
+    pub name: String,
+    pub switch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SwitchStatus {
+    pub name: String,
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub switch_type: SwitchKind,
+    pub exists: bool,
+}
+
+/// A difference between the recorded and the live state of a lab
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    MissingVm { vm: String },
+    MissingSwitch { switch: String },
+    MissingAdapter { vm: String, adapter: String },
+    ExtraAdapter { vm: String, adapter: String },
+    RewiredAdapter { vm: String, adapter: String, expected: String, actual: Option<String> },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Drift::MissingVm { vm } => write!(f, "VM '{}' no longer exists", vm),
+            Drift::MissingSwitch { switch } => write!(f, "Switch '{}' no longer exists", switch),
+            Drift::MissingAdapter { vm, adapter } => write!(f, "VM '{}' no longer has adapter '{}'", vm, adapter),
+            Drift::ExtraAdapter { vm, adapter } => write!(f, "VM '{}' has adapter '{}' which was not deployed", vm, adapter),
+            Drift::RewiredAdapter { vm, adapter, expected, actual: Some(actual) } => write!(f, "Adapter '{}' of VM '{}' is connected to '{}' instead of '{}'", adapter, vm, actual, expected),
+            Drift::RewiredAdapter { vm, adapter, expected, actual: None } => write!(f, "Adapter '{}' of VM '{}' is not connected to '{}'", adapter, vm, expected),
+        }
+    }
+}
+
+impl LabStatus {
+    pub fn query<P: AsRef<Path>>(backend: &dyn Backend, lab_path: P) -> Result<LabStatus, Error> {
+        let lab_path = lab_path.as_ref();
+        let state = LabState::load(lab_path)?
+            .ok_or_else(|| LamaError::NotDeployed(lab_path.to_owned()))?;
+
+        let live_vms: HashMap<VmId, _> = backend.get_vms()?.into_iter().map(|vm| (vm.id, vm)).collect();
+        let live_switch_ids: HashSet<Uuid> = backend.get_switches()?.into_iter().map(|s| s.id).collect();
+
+        let mut vms = Vec::new();
+        let mut drift = Vec::new();
+        for vm_state in &state.vms {
+            let live_vm = match live_vms.get(&vm_state.id) {
+                Some(live_vm) => live_vm,
+                None => {
+                    drift.push(Drift::MissingVm { vm: vm_state.name.clone() });
+                    vms.push(VmStatus { name: vm_state.name.clone(), id: vm_state.id, state: None, uptime_secs: None, adapters: Vec::new() });
+                    continue;
+                }
+            };
+
+            let live_adapters = backend.get_vm_adapters(&vm_state.id)?;
+            for adapter_state in &vm_state.adapters {
+                match live_adapters.iter().find(|a| a.id == adapter_state.id) {
+                    None => drift.push(Drift::MissingAdapter { vm: live_vm.name.clone(), adapter: adapter_state.name.clone() }),
+                    Some(live_adapter) if live_adapter.switch_name.as_ref() != Some(&adapter_state.switch) => {
+                        drift.push(Drift::RewiredAdapter {
+                            vm: live_vm.name.clone(),
+                            adapter: adapter_state.name.clone(),
+                            expected: adapter_state.switch.clone(),
+                            actual: live_adapter.switch_name.clone(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+
+            // Adapters that weren't connected at deploy time aren't recorded, so only connected ones count as extra
+            for live_adapter in &live_adapters {
+                if live_adapter.switch_name.is_some() && !vm_state.adapters.iter().any(|a| a.id == live_adapter.id) {
+                    drift.push(Drift::ExtraAdapter { vm: live_vm.name.clone(), adapter: live_adapter.name.clone() });
+                }
+            }
+
+            vms.push(VmStatus {
+                name: live_vm.name.clone(),
+                id: live_vm.id,
+                state: Some(live_vm.state.clone()),
+                uptime_secs: Some(live_vm.uptime_secs),
+                adapters: live_adapters.into_iter().map(|a| AdapterStatus { name: a.name, switch: a.switch_name }).collect(),
+            });
+        }
+
+        let mut switches = Vec::new();
+        for switch_state in &state.switches {
+            let exists = live_switch_ids.contains(&switch_state.id);
+            if !exists {
+                drift.push(Drift::MissingSwitch { switch: switch_state.name.clone() });
+            }
+            switches.push(SwitchStatus { name: switch_state.name.clone(), id: switch_state.id, switch_type: switch_state.switch_type, exists });
+        }
+
+        Ok(LabStatus { vms, switches, drift })
+    }
+
+    pub fn print_table(&self) {
+        let vm_rows: Vec<Vec<String>> = self.vms.iter()
+            .map(|vm| vec![
+                vm.name.clone(),
+                vm.id.to_hyphenated().to_string(),
+                vm.state.clone().unwrap_or_else(|| "Missing".to_owned()),
+                vm.uptime_secs.map(format_uptime).unwrap_or_else(|| "-".to_owned()),
+                vm.adapters.iter()
+                    .map(|a| format!("{} -> {}", a.name, a.switch.as_deref().unwrap_or("<none>")))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ])
+            .collect();
+        print_table(&["VM", "ID", "STATE", "UPTIME", "ADAPTERS"], &vm_rows);
+        println!();
+
+        let switch_rows: Vec<Vec<String>> = self.switches.iter()
+            .map(|s| vec![
+                s.name.clone(),
+                s.id.to_hyphenated().to_string(),
+                format!("{:?}", s.switch_type),
+                if s.exists { "OK" } else { "Missing" }.to_owned(),
+            ])
+            .collect();
+        print_table(&["SWITCH", "ID", "TYPE", "STATUS"], &switch_rows);
+        println!();
+
+        if self.drift.is_empty() {
+            println!("No drift detected");
+        } else {
+            println!("Drift detected:");
+            for d in &self.drift {
+                println!("  {}", d);
+            }
+        }
+    }
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = std::cmp::max(*width, cell.chars().count());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_owned()
+    };
+
+    println!("{}", format_row(headers.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(|c| c.as_str()).collect()));
+    }
+}
+
+fn format_uptime(secs: u64) -> String {
+    let days = secs / 86400;
+    let time = format!("{:02}:{:02}:{:02}", (secs % 86400) / 3600, (secs % 3600) / 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}", days, time)
+    } else {
+        time
+    }
+}
@@ -0,0 +1,785 @@
+//! End-to-end runs of deploy and drop against `FakeBackend`, on labs made of dummy `.vmcx` files
+
+use super::*;
+use crate::error::{ErrorCategory, ErrorReport};
+use crate::fake::{Call, FakeBackend};
+use std::collections::{BTreeMap, BTreeSet};
+use tempfile::TempDir;
+
+/// Writes each of `files` to its path in `dir`, creating the folders on the way
+pub fn write_files<P: AsRef<Path>, D: AsRef<[u8]>>(dir: &Path, files: &[(P, D)]) {
+    for (path, data) in files {
+        let file_path = dir.join(path);
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        fs::write(file_path, data).unwrap();
+    }
+}
+
+/// A lab made of `files`, keyed by their path in it
+pub fn lab_with_files<P: AsRef<Path>, D: AsRef<[u8]>>(files: &[(P, D)]) -> TempDir {
+    let lab = tempfile::tempdir().unwrap();
+    write_files(lab.path(), files);
+    lab
+}
+
+/// Bytes that don't repeat within 251 of them and are never zero, starting `seed` bytes into the pattern
+pub fn contents(size: usize, seed: usize) -> Vec<u8> {
+    (0..size).map(|i| ((i + seed) * 31 % 251) as u8 + 1).collect()
+}
+
+/// The SHA-256 of `data`, as lowercase hex
+pub fn hash(data: &[u8]) -> String {
+    let mut hasher = crate::sha256::Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// The `.vmcx` the fake imports a VM with the given name and adapters from
+fn vmcx(name: &str, adapters: &[(&str, &str)]) -> String {
+    let adapters: BTreeMap<&str, &str> = adapters.iter().cloned().collect();
+    serde_json::json!({ "name": name, "adapters": adapters }).to_string()
+}
+
+/// A lab with a folder per VM, each holding a `.vmcx` the fake imports the VM's name and adapters from
+fn make_lab(vms: &[(&str, &[(&str, &str)])]) -> TempDir {
+    let files: Vec<(String, String)> = vms.iter()
+        .map(|(name, adapters)| (format!("{}/Virtual Machines/exported.vmcx", name), vmcx(name, adapters)))
+        .collect();
+    lab_with_files(&files)
+}
+
+fn default_lab() -> TempDir {
+    make_lab(&[
+        ("dc01", &[("Network Adapter", "corp"), ("Mgmt Adapter", "mgmt")]),
+        ("web01", &[("Network Adapter", "corp")]),
+        ("client01", &[("Network Adapter", "corp"), ("Spare Adapter", "")]),
+    ])
+}
+
+fn options(args: &[&str]) -> DeployOptions {
+    let mut argv = vec!["deploy", "--non-interactive"];
+    argv.extend_from_slice(args);
+    DeployOptions::from_iter(argv)
+}
+
+fn graph(entries: &[(&str, &[&str])]) -> BTreeMap<String, BTreeSet<String>> {
+    entries.iter()
+        .map(|(switch, vms)| (switch.to_string(), vms.iter().map(|vm| vm.to_string()).collect()))
+        .collect()
+}
+
+fn default_graph() -> BTreeMap<String, BTreeSet<String>> {
+    graph(&[("corp", &["client01", "dc01", "web01"]), ("mgmt", &["dc01"])])
+}
+
+/// The names of the `.vmcx` files of each VM folder
+fn vmcx_files(lab_path: &Path) -> BTreeMap<String, Vec<String>> {
+    get_vm_paths(lab_path).unwrap().into_iter()
+        .map(|vm_path| {
+            let mut files: Vec<String> = get_vmcx_file_paths(&vm_path).unwrap().iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            (vm_path.file_name().unwrap().to_string_lossy().into_owned(), files)
+        })
+        .collect()
+}
+
+#[test]
+fn deploy_creates_switches_and_wires_up_vms() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert!(backend.vms().iter().all(|vm| vm.running));
+    assert_eq!(backend.calls().iter().filter(|c| matches!(c, Call::CreateSwitch(_))).count(), 2);
+
+    let state = LabState::load(lab.path()).unwrap().unwrap();
+    assert_eq!(state.vms.len(), 3);
+    assert_eq!(state.switches.len(), 2);
+    assert!(!Journal::exists(lab.path()));
+}
+
+#[test]
+fn deploy_with_jobs_wires_up_the_same_lab() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--jobs", "3"])).unwrap();
+
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert_eq!(backend.calls().iter().filter(|c| matches!(c, Call::CreateSwitch(_))).count(), 2);
+}
+
+#[test]
+fn deploy_follows_the_manifest() {
+    let lab = default_lab();
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        [[switches]]
+        name = "lan"
+        type = "internal"
+        host_ip = "192.168.50.1/24"
+
+        [[vms]]
+        name = "DC"
+        folder = "dc01"
+        boot_order = 2
+
+        [[vms.adapters]]
+        name = "Mgmt Adapter"
+        switch = "lan"
+
+        [[vms]]
+        name = "Web"
+        folder = "web01"
+        boot_order = 1
+    "#).unwrap();
+
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    // client01 isn't in the manifest, so it isn't deployed
+    assert_eq!(backend.switch_graph(), graph(&[("corp", &["DC", "Web"]), ("lan", &["DC"])]));
+    let imports: Vec<PathBuf> = backend.calls().into_iter()
+        .filter_map(|c| match c { Call::ImportVm(path) => Some(path), _ => None })
+        .collect();
+    assert_eq!(imports, vec![lab.path().join("web01"), lab.path().join("dc01")]);
+
+    let lan = backend.switches().into_iter().find(|s| s.name == "lan").unwrap();
+    assert_eq!(lan.switch_type, SwitchKind::Internal);
+    assert_eq!(lan.host_ip.as_deref(), Some("192.168.50.1/24"));
+    let dc = backend.vms().into_iter().find(|vm| vm.name == "DC").unwrap();
+    assert_eq!(dc.path, lab.path().join("dc01"));
+}
+
+#[test]
+fn deploy_runs_the_provisioners() {
+    let lab = default_lab();
+    fs::write(lab.path().join("all.ps1"), "").unwrap();
+    fs::write(lab.path().join("dc.ps1"), "").unwrap();
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        provisioners = ["all.ps1"]
+
+        [[vms]]
+        name = "dc01"
+        folder = "dc01"
+        provisioners = ["dc.ps1"]
+
+        [[vms]]
+        name = "web01"
+        folder = "web01"
+    "#).unwrap();
+
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    let vm_ids: HashMap<String, VmId> = backend.vms().into_iter().map(|vm| (vm.name, vm.id)).collect();
+    let runs: Vec<(PathBuf, Vec<VmId>)> = backend.calls().into_iter()
+        .filter_map(|c| match c { Call::RunProvisioner(path, vm_ids) => Some((path, vm_ids)), _ => None })
+        .collect();
+    assert_eq!(runs, vec![
+        (lab.path().join("all.ps1"), vec![vm_ids["dc01"], vm_ids["web01"]]),
+        (lab.path().join("dc.ps1"), vec![vm_ids["dc01"]]),
+    ]);
+    assert_eq!(backend.trusted_hosts().len(), 2);
+}
+
+#[test]
+fn deploy_reuses_external_switches_that_already_exist() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    backend.add_switch("corp", SwitchKind::External);
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert_eq!(backend.calls().iter().filter(|c| matches!(c, Call::CreateSwitch(_))).count(), 1);
+}
+
+#[test]
+fn deploy_does_not_share_other_switches_it_did_not_create() {
+    for switch_type in &[SwitchKind::Private, SwitchKind::Internal] {
+        let lab = default_lab();
+        let backend = FakeBackend::new();
+        backend.add_switch("corp", *switch_type);
+
+        let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        assert_eq!(ErrorReport::new(&e).cause_category, Some(ErrorCategory::Conflict), "{:?}", switch_type);
+        assert!(backend.vms().is_empty(), "{:?}", switch_type);
+        assert_eq!(backend.switch_graph(), graph(&[("corp", &[])]), "{:?}", switch_type);
+    }
+}
+
+#[test]
+fn deploy_does_not_share_an_external_switch_the_manifest_declares_private() {
+    let lab = default_lab();
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        [[switches]]
+        name = "corp"
+        type = "private"
+
+        [[vms]]
+        name = "web01"
+        folder = "web01"
+    "#).unwrap();
+    let backend = FakeBackend::new();
+    backend.add_switch("corp", SwitchKind::External);
+
+    let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+    assert_eq!(ErrorReport::new(&e).cause_category, Some(ErrorCategory::Conflict));
+    assert_eq!(backend.switch_graph(), graph(&[("corp", &[])]));
+}
+
+#[test]
+fn drop_deletes_what_deploy_created() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    delete_lab(&backend, lab.path()).unwrap();
+
+    assert!(backend.vms().is_empty());
+    assert!(backend.switch_graph().is_empty());
+    assert!(LabState::load(lab.path()).unwrap().is_none());
+}
+
+#[test]
+fn drop_leaves_switches_it_did_not_create() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    backend.add_switch("corp", SwitchKind::External);
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    delete_lab(&backend, lab.path()).unwrap();
+
+    assert_eq!(backend.switch_graph(), graph(&[("corp", &[])]));
+}
+
+#[test]
+fn drop_keeps_switches_used_by_vms_outside_the_lab() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    backend.add_vm("jumpbox", &["mgmt"]);
+
+    // The plan only keeps back what drop itself will refuse to delete
+    let mut plan = Plan::new("drop");
+    plan_drop(&backend, lab.path(), &mut plan).unwrap();
+    let lines = plan.lines();
+    let switch_lines: Vec<&String> = lines.iter().filter(|l| l.contains("switch")).collect();
+    assert_eq!(switch_lines.len(), 2);
+    assert!(switch_lines.iter().any(|l| l.starts_with("Delete switch 'corp'")));
+    assert!(switch_lines.iter().any(|l| l.starts_with("Keep switch 'mgmt'") && l.ends_with("still used by VMs jumpbox")));
+
+    let e = delete_lab(&backend, lab.path()).unwrap_err();
+    assert_eq!(ErrorCategory::of(&e), ErrorCategory::Conflict);
+    assert_eq!(backend.switch_graph(), graph(&[("mgmt", &["jumpbox"])]));
+    assert_eq!(LabState::load(lab.path()).unwrap().unwrap().switches.len(), 1);
+}
+
+#[test]
+fn drop_of_a_lab_that_was_never_deployed_does_nothing() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    delete_lab(&backend, lab.path()).unwrap();
+
+    assert!(backend.calls().iter().all(|c| *c == Call::GetSwitches));
+}
+
+/// Counts the calls a clean deploy of the default lab makes, so that each of them can be made to fail in turn
+fn deploy_call_count(args: &[&str]) -> usize {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(args)).unwrap();
+    backend.calls().len()
+}
+
+#[test]
+fn failed_deploy_is_rolled_back_whichever_call_fails() {
+    for n in 1..=deploy_call_count(&[]) {
+        let lab = default_lab();
+        let vmcx_files_before = vmcx_files(lab.path());
+        let backend = FakeBackend::new();
+        backend.fail_on_call(n);
+
+        let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::RolledBack, "call {}", n);
+        assert!(backend.vms().is_empty(), "call {}", n);
+        assert!(backend.switch_graph().is_empty(), "call {}", n);
+        assert!(LabState::load(lab.path()).unwrap().is_none(), "call {}", n);
+        assert!(!Journal::exists(lab.path()), "call {}", n);
+        assert_eq!(vmcx_files(lab.path()), vmcx_files_before, "call {}", n);
+    }
+}
+
+#[test]
+fn failed_rollback_keeps_what_it_could_not_undo() {
+    // Fail the deploy on its last call, and then each call its rollback makes in turn
+    let deploy_calls = deploy_call_count(&[]);
+    let rollback_calls = {
+        let lab = default_lab();
+        let backend = FakeBackend::new();
+        backend.fail_on_call(deploy_calls);
+        deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        backend.calls().len() - deploy_calls
+    };
+
+    for m in deploy_calls + 1..=deploy_calls + rollback_calls {
+        let lab = default_lab();
+        let vmcx_files_before = vmcx_files(lab.path());
+        let backend = FakeBackend::new();
+        backend.fail_on_call(deploy_calls);
+        backend.fail_on_call(m);
+
+        let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::CleanupIncomplete, "call {}", m);
+        assert!(Journal::exists(lab.path()), "call {}", m);
+
+        // What's left on the host is exactly what the state still records
+        let state = LabState::load(lab.path()).unwrap().unwrap_or_else(LabState::new);
+        let state_vm_ids: BTreeSet<VmId> = state.vms.iter().map(|vm| vm.id).collect();
+        let state_switch_ids: BTreeSet<Uuid> = state.switches.iter().map(|s| s.id).collect();
+        assert_eq!(state_vm_ids, backend.vms().iter().map(|vm| vm.id).collect(), "call {}", m);
+        assert_eq!(state_switch_ids, backend.switches().iter().map(|s| s.id).collect(), "call {}", m);
+
+        delete_lab(&backend, lab.path()).unwrap();
+        assert!(backend.vms().is_empty(), "call {}", m);
+        assert!(backend.switch_graph().is_empty(), "call {}", m);
+        assert!(LabState::load(lab.path()).unwrap().is_none(), "call {}", m);
+        assert!(!Journal::exists(lab.path()), "call {}", m);
+        assert_eq!(vmcx_files(lab.path()), vmcx_files_before, "call {}", m);
+    }
+}
+
+#[test]
+fn failed_deploy_can_be_resumed_whichever_call_fails() {
+    for n in 1..=deploy_call_count(&[]) {
+        let lab = default_lab();
+        let backend = FakeBackend::new();
+        backend.fail_on_call(n);
+
+        deploy_lab(&backend, lab.path().to_owned(), &options(&["--no-rollback"])).unwrap_err();
+        assert!(Journal::exists(lab.path()), "call {}", n);
+        let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::Conflict, "call {}", n);
+
+        deploy_lab(&backend, lab.path().to_owned(), &options(&["--resume"])).unwrap();
+        assert_eq!(backend.switch_graph(), default_graph(), "call {}", n);
+        assert_eq!(backend.vms().len(), 3, "call {}", n);
+        assert!(backend.vms().iter().all(|vm| vm.running), "call {}", n);
+        assert!(!Journal::exists(lab.path()), "call {}", n);
+    }
+}
+
+#[test]
+fn drop_rolls_back_an_unfinished_deploy() {
+    for n in 1..=deploy_call_count(&[]) {
+        let lab = default_lab();
+        let vmcx_files_before = vmcx_files(lab.path());
+        let backend = FakeBackend::new();
+        backend.fail_on_call(n);
+
+        deploy_lab(&backend, lab.path().to_owned(), &options(&["--no-rollback"])).unwrap_err();
+        delete_lab(&backend, lab.path()).unwrap();
+        assert!(backend.vms().is_empty(), "call {}", n);
+        assert!(backend.switch_graph().is_empty(), "call {}", n);
+        assert!(!Journal::exists(lab.path()), "call {}", n);
+        assert_eq!(vmcx_files(lab.path()), vmcx_files_before, "call {}", n);
+    }
+}
+
+/// Leaves the lab as a deploy that died right after importing the VM in `folder`, before it could record the import.
+/// The VM's `.vmcx` file is named after an ID first, as Hyper-V exports it.
+fn die_after_import(backend: &FakeBackend, lab_path: &Path, folder: &str) -> VmId {
+    let vm_path = lab_path.join(folder);
+    let vmcx_path = get_single_vmcx_file_path(&vm_path).unwrap().unwrap();
+    fs::rename(&vmcx_path, vmcx_path.with_file_name("7A4B5C6D-0000-4000-8000-000000000001.vmcx")).unwrap();
+
+    let mut journal = Journal::new(lab_path);
+    back_up_vm_config(&vm_path).unwrap();
+    journal.record(JournalEntry::BackedUpVmConfig { folder: PathBuf::from(folder) }).unwrap();
+    journal.record(JournalEntry::ImportingVm { folder: PathBuf::from(folder), exported_id: get_vm_id(&vm_path).unwrap() }).unwrap();
+    backend.import_vm_inplace_new_id(&vm_path, None).unwrap().id
+}
+
+#[test]
+fn resume_after_dying_mid_import_does_not_import_twice() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    let orphan_id = die_after_import(&backend, lab.path(), "dc01");
+
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--resume"])).unwrap();
+    assert_eq!(backend.vms().len(), 3);
+    assert!(backend.vms().iter().all(|vm| vm.id != orphan_id));
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert!(!Journal::exists(lab.path()));
+}
+
+#[test]
+fn drop_after_dying_mid_import_deletes_the_vm() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    die_after_import(&backend, lab.path(), "dc01");
+    let vmcx_files_before = {
+        let mut files = vmcx_files(lab.path());
+        files.insert("dc01".to_owned(), vec!["7A4B5C6D-0000-4000-8000-000000000001.vmcx".to_owned()]);
+        files
+    };
+
+    delete_lab(&backend, lab.path()).unwrap();
+    assert!(backend.vms().is_empty());
+    assert!(!Journal::exists(lab.path()));
+    assert_eq!(vmcx_files(lab.path()), vmcx_files_before);
+}
+
+#[test]
+fn failed_drop_can_be_run_again() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    let calls_before = backend.calls().len();
+    backend.fail_on_call(calls_before + 3);
+
+    delete_lab(&backend, lab.path()).unwrap_err();
+    delete_lab(&backend, lab.path()).unwrap();
+    assert!(backend.vms().is_empty());
+    assert!(backend.switch_graph().is_empty());
+}
+
+#[test]
+fn deploy_of_a_deployed_lab_needs_to_be_told_what_to_do() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+    assert_eq!(ErrorCategory::of(&e), ErrorCategory::Conflict);
+
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--redeploy"])).unwrap();
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert_eq!(backend.vms().len(), 3);
+}
+
+#[test]
+fn missing_only_deploys_the_vms_that_are_gone() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    let web01 = backend.vms().into_iter().find(|vm| vm.name == "web01").unwrap();
+    backend.delete_vm(&web01.id).unwrap();
+    // Deleting a VM takes its config files with it, so put back the ones it was exported with
+    fs::remove_dir_all(lab.path().join("web01").join("Virtual Machines")).unwrap();
+    write_files(lab.path(), &[("web01/Virtual Machines/exported.vmcx", vmcx("web01", &[("Network Adapter", "corp")]))]);
+
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--missing-only"])).unwrap();
+    assert_eq!(backend.switch_graph(), default_graph());
+    assert_eq!(backend.calls().iter().filter(|c| matches!(c, Call::ImportVm(_))).count(), 4);
+}
+
+/// The drift `status` reports for the lab, as it's printed
+/// A lab whose manifest keeps its VMs in folders below `vms`
+fn nested_lab() -> TempDir {
+    let lab = lab_with_files(&[
+        ("vms/dc01/Virtual Machines/exported.vmcx", vmcx("dc01", &[("Network Adapter", "corp")])),
+        ("vms/web01/Virtual Machines/exported.vmcx", vmcx("web01", &[("Network Adapter", "corp")])),
+    ]);
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        [[vms]]
+        name = "dc01"
+        folder = "vms/dc01"
+
+        [[vms]]
+        name = "web01"
+        folder = "vms/web01"
+    "#).unwrap();
+    lab
+}
+
+#[test]
+fn nested_vm_folders_are_recorded_relative_to_the_lab() {
+    let lab = nested_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    let state = LabState::load(lab.path()).unwrap().unwrap();
+    let folders: BTreeSet<PathBuf> = state.vms.iter().map(|vm| vm.folder.clone()).collect();
+    assert_eq!(folders, vec![PathBuf::from("vms/dc01"), PathBuf::from("vms/web01")].into_iter().collect());
+
+    backend.delete_vm(&vm_id(&backend, "web01")).unwrap();
+    fs::remove_dir_all(lab.path().join("vms/web01/Virtual Machines")).unwrap();
+    write_files(lab.path(), &[("vms/web01/Virtual Machines/exported.vmcx", vmcx("web01", &[("Network Adapter", "corp")]))]);
+
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--missing-only"])).unwrap();
+    assert_eq!(backend.calls().iter().filter(|c| matches!(c, Call::ImportVm(_))).count(), 3);
+    assert_eq!(backend.switch_graph(), graph(&[("corp", &["dc01", "web01"])]));
+}
+
+#[test]
+fn nested_vm_folders_are_rolled_back_and_resumed() {
+    let count = {
+        let lab = nested_lab();
+        let backend = FakeBackend::new();
+        deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+        backend.calls().len()
+    };
+
+    for n in 1..=count {
+        let lab = nested_lab();
+        let vmcx_files_before = vmcx_files(&lab.path().join("vms"));
+        let backend = FakeBackend::new();
+        backend.fail_on_call(n);
+        let e = deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap_err();
+        assert_eq!(ErrorCategory::of(&e), ErrorCategory::RolledBack, "call {}", n);
+        assert_eq!(vmcx_files(&lab.path().join("vms")), vmcx_files_before, "call {}", n);
+
+        let backend = FakeBackend::new();
+        backend.fail_on_call(n);
+        deploy_lab(&backend, lab.path().to_owned(), &options(&["--no-rollback"])).unwrap_err();
+        deploy_lab(&backend, lab.path().to_owned(), &options(&["--resume"])).unwrap();
+        assert_eq!(backend.switch_graph(), graph(&[("corp", &["dc01", "web01"])]), "call {}", n);
+        assert!(backend.vms().iter().all(|vm| vm.running), "call {}", n);
+    }
+}
+
+/// Unlike anything `contents` makes
+fn web01_disk() -> Vec<u8> {
+    (0..100_000).map(|i| (i % 7) as u8 + 1).collect()
+}
+
+/// A lab of two VMs with a disk image each, booted one after the other, packed into a bundle in the returned folder
+fn packed_lab() -> (TempDir, PathBuf) {
+    let lab = lab_with_files(&[
+        ("dc01/Virtual Machines/exported.vmcx", vmcx("dc01", &[("Network Adapter", "corp")]).into_bytes()),
+        ("dc01/Virtual Hard Disks/dc01.vhdx", contents(100_000, 1)),
+        ("web01/Virtual Machines/exported.vmcx", vmcx("web01", &[("Network Adapter", "corp")]).into_bytes()),
+        ("web01/Virtual Hard Disks/web01.vhdx", web01_disk()),
+    ]);
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        [[vms]]
+        name = "dc01"
+        folder = "dc01"
+        boot_order = 1
+
+        [[vms]]
+        name = "web01"
+        folder = "web01"
+        boot_order = 2
+    "#).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let bundle_path = dir.path().join("lab.lama");
+    bundle::pack_lab(lab.path(), &bundle_path, false).unwrap();
+    (dir, bundle_path)
+}
+
+/// The labs extracted into `dest_dir`
+fn extracted_labs(dest_dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dest_dir).map(|entries| entries.map(|e| e.unwrap().path()).collect()).unwrap_or_default()
+}
+
+#[test]
+fn deploy_of_a_bundle_extracts_the_lab_and_deploys_it() {
+    let (dir, bundle_path) = packed_lab();
+    let dest_dir = dir.path().join("dest");
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, bundle_path, &options(&["--copy-to", dest_dir.to_str().unwrap()])).unwrap();
+
+    assert_eq!(backend.switch_graph(), graph(&[("corp", &["dc01", "web01"])]));
+    let labs = extracted_labs(&dest_dir);
+    assert_eq!(labs.len(), 1);
+    assert!(!bundle::is_partly_extracted(&labs[0]));
+    assert_eq!(fs::read(labs[0].join("web01/Virtual Hard Disks/web01.vhdx")).unwrap(), web01_disk());
+    assert_eq!(LabState::load(&labs[0]).unwrap().unwrap().vms.len(), 2);
+}
+
+#[test]
+fn deploy_of_a_corrupted_bundle_is_rolled_back_and_what_was_extracted_removed() {
+    let (dir, bundle_path) = packed_lab();
+    let mut bundle = fs::read(&bundle_path).unwrap();
+    let disk = web01_disk();
+    let at = bundle.windows(64).position(|w| w == &disk[..64]).unwrap();
+    bundle[at + 1000] ^= 0xff;
+    fs::write(&bundle_path, bundle).unwrap();
+
+    let dest_dir = dir.path().join("dest");
+    let backend = FakeBackend::new();
+    let e = deploy_lab(&backend, bundle_path, &options(&["--copy-to", dest_dir.to_str().unwrap()])).unwrap_err();
+    match e.downcast_ref::<LamaError>() {
+        Some(LamaError::RolledBack { cause }) => assert_eq!(ErrorCategory::of(cause), ErrorCategory::Corrupted, "{}", cause),
+        _ => panic!("unexpected error: {}", e),
+    }
+
+    // dc01 was all there, so it was deployed before the bundle was read to the end
+    let imports: Vec<PathBuf> = backend.calls().into_iter()
+        .filter_map(|c| match c { Call::ImportVm(path) => Some(path), _ => None })
+        .collect();
+    assert_eq!(imports.len(), 1);
+    assert!(imports[0].ends_with("dc01"));
+    assert!(backend.vms().is_empty());
+    assert!(extracted_labs(&dest_dir).is_empty());
+}
+
+#[test]
+fn deploy_of_a_partly_extracted_lab_is_refused() {
+    let lab = default_lab();
+    write_files(lab.path(), &[(".lama/extracting", "")]);
+    let e = deploy_lab(&FakeBackend::new(), lab.path().to_owned(), &options(&[])).unwrap_err();
+    assert_eq!(ErrorCategory::of(&e), ErrorCategory::InvalidInput);
+}
+
+fn drift(backend: &FakeBackend, lab_path: &Path) -> Vec<String> {
+    let mut drift: Vec<String> = LabStatus::query(backend, lab_path).unwrap().drift.iter().map(|d| d.to_string()).collect();
+    drift.sort();
+    drift
+}
+
+fn vm_id(backend: &FakeBackend, name: &str) -> VmId {
+    backend.vms().into_iter().find(|vm| vm.name == name).unwrap().id
+}
+
+fn switch_id(backend: &FakeBackend, name: &str) -> String {
+    backend.switches().into_iter().find(|s| s.name == name).unwrap().id.to_string()
+}
+
+#[test]
+fn status_of_a_fresh_deploy_has_no_drift() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    let status = LabStatus::query(&backend, lab.path()).unwrap();
+    assert!(status.drift.is_empty());
+    assert_eq!(status.vms.len(), 3);
+    assert!(status.vms.iter().all(|vm| vm.state.as_deref() == Some("Running")));
+    assert!(status.switches.iter().all(|s| s.exists));
+}
+
+#[test]
+fn status_reports_deleted_vms_and_switches() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    backend.delete_vm(&vm_id(&backend, "web01")).unwrap();
+    backend.delete_switch(&switch_id(&backend, "mgmt")).unwrap();
+
+    assert_eq!(drift(&backend, lab.path()), vec![
+        "Adapter 'Mgmt Adapter' of VM 'dc01' is not connected to 'mgmt'",
+        "Switch 'mgmt' no longer exists",
+        "VM 'web01' no longer exists",
+    ]);
+    let status = LabStatus::query(&backend, lab.path()).unwrap();
+    assert_eq!(status.vms.iter().find(|vm| vm.name == "web01").unwrap().state, None);
+    assert!(!status.switches.iter().find(|s| s.name == "mgmt").unwrap().exists);
+}
+
+#[test]
+fn status_reports_rewired_adapters() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    let web01 = vm_id(&backend, "web01");
+    let adapter_id = backend.get_vm_adapters(&web01).unwrap().into_iter().find(|a| a.name == "Network Adapter").unwrap().id;
+    backend.connect_adapter(&web01, &adapter_id, &switch_id(&backend, "mgmt")).unwrap();
+
+    assert_eq!(drift(&backend, lab.path()), vec!["Adapter 'Network Adapter' of VM 'web01' is connected to 'mgmt' instead of 'corp'"]);
+}
+
+#[test]
+fn status_reports_adapters_that_were_not_deployed() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+
+    // The spare adapter wasn't connected at deploy time, so connecting it now is drift
+    let client01 = vm_id(&backend, "client01");
+    let adapter_id = backend.get_vm_adapters(&client01).unwrap().into_iter().find(|a| a.name == "Spare Adapter").unwrap().id;
+    backend.connect_adapter(&client01, &adapter_id, &switch_id(&backend, "corp")).unwrap();
+
+    // And an adapter recorded at deploy time that the VM no longer has
+    let mut state = LabState::load(lab.path()).unwrap().unwrap();
+    let dc01 = state.vm_mut(&vm_id(&backend, "dc01")).unwrap();
+    let mut removed = dc01.adapters[0].clone();
+    removed.id = "removed".to_owned();
+    removed.name = "Removed Adapter".to_owned();
+    dc01.adapters.push(removed);
+    state.save(lab.path()).unwrap();
+
+    assert_eq!(drift(&backend, lab.path()), vec![
+        "VM 'client01' has adapter 'Spare Adapter' which was not deployed",
+        "VM 'dc01' no longer has adapter 'Removed Adapter'",
+    ]);
+}
+
+#[test]
+fn status_of_a_lab_that_was_never_deployed_fails() {
+    let lab = default_lab();
+    let error = LabStatus::query(&FakeBackend::new(), lab.path()).unwrap_err();
+    assert_eq!(ErrorCategory::of(&error), ErrorCategory::NotFound);
+}
+
+#[test]
+fn deploy_plan_lists_the_adapters_wired_up_on_export() {
+    let lab = default_lab();
+    fs::write(lab.path().join(MANIFEST_FILE_NAME), r#"
+        [[switches]]
+        name = "lan"
+
+        [[vms]]
+        name = "DC"
+        folder = "dc01"
+
+        [[vms.adapters]]
+        name = "Mgmt Adapter"
+        switch = "lan"
+
+        [[vms]]
+        name = "client01"
+        folder = "client01"
+    "#).unwrap();
+
+    let backend = FakeBackend::new();
+    let mut plan = Plan::new("deploy");
+    plan_deploy(&backend, lab.path(), None, None, &options(&["--dry-run"]), &mut plan).unwrap();
+    assert_eq!(plan.lines(), vec![
+        "Import VM dc01 as 'DC'",
+        "Create private switch 'lan'",
+        "Connect adapter 'Mgmt Adapter' of VM DC to switch 'lan'",
+        "Create private switch 'corp'",
+        "Connect adapter 'Network Adapter' of VM DC to switch 'corp'",
+        "Start VM DC",
+        "Import VM client01 as 'client01'",
+        "Connect adapter 'Network Adapter' of VM client01 to switch 'corp'",
+        "Start VM client01",
+    ]);
+    assert!(backend.calls().iter().all(|c| matches!(c, Call::GetExportedVmAdapters(_))));
+}
+
+/// Turns the state of a deployed lab into what older versions of lama kept, a switch name -> ID map
+fn make_state_legacy(lab_path: &Path) {
+    let state = LabState::load(lab_path).unwrap().unwrap();
+    let switches: HashMap<&str, Uuid> = state.switches.iter().map(|s| (s.name.as_str(), s.id)).collect();
+    fs::write(lab_path.join(".lama").join("switches.json"), serde_json::to_string(&switches).unwrap()).unwrap();
+    fs::remove_file(lab_path.join(".lama").join("state.json")).unwrap();
+}
+
+fn lama_dir_files(lab_path: &Path) -> BTreeSet<String> {
+    fs::read_dir(lab_path.join(".lama")).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect()
+}
+
+#[test]
+fn status_and_dry_runs_leave_legacy_state_alone() {
+    let lab = default_lab();
+    let backend = FakeBackend::new();
+    deploy_lab(&backend, lab.path().to_owned(), &options(&[])).unwrap();
+    make_state_legacy(lab.path());
+    let files_before = lama_dir_files(lab.path());
+
+    LabStatus::query(&backend, lab.path()).unwrap();
+    let mut plan = Plan::new("drop");
+    plan_drop(&backend, lab.path(), &mut plan).unwrap();
+    assert_eq!(plan.lines().len(), 3 * 2 + 2);
+    deploy_lab(&backend, lab.path().to_owned(), &options(&["--dry-run", "--redeploy"])).unwrap();
+    assert_eq!(lama_dir_files(lab.path()), files_before);
+
+    delete_lab(&backend, lab.path()).unwrap();
+    assert!(backend.vms().is_empty());
+    assert!(backend.switch_graph().is_empty());
+    let files_after = lama_dir_files(lab.path());
+    assert!(!files_after.contains("state.json") && !files_after.contains("switches.json"));
+}